@@ -0,0 +1,96 @@
+//! Packs everything under rootfs/ into the cpio "newc" archive src/initramfs.rs links into the
+//! kernel, the same archive `find . | cpio -o -H newc` would make there.
+
+use std::env;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const ROOTFS: &str = "rootfs";
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", ROOTFS);
+    let mut archive = Vec::new();
+    let mut ino = 0;
+    pack(&mut archive, Path::new(ROOTFS), ".", &mut ino);
+    append(&mut archive, "TRAILER!!!", 0, 0, &[]);
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("initramfs.cpio");
+    if let Err(e) = fs::write(&out, archive) {
+        panic!("{}: {}", out.display(), e);
+    }
+}
+
+/// Appends `path` as `name`, a directory with everything below it in name order.
+fn pack(archive: &mut Vec<u8>, path: &Path, name: &str, ino: &mut u32) {
+    let fail = |e: std::io::Error| -> ! { panic!("{}: {}", path.display(), e) };
+    let meta = fs::symlink_metadata(path).unwrap_or_else(|e| fail(e));
+    *ino += 1;
+    let data = if meta.file_type().is_symlink() {
+        fs::read_link(path)
+            .unwrap_or_else(|e| fail(e))
+            .into_os_string()
+            .into_vec()
+    } else if meta.is_file() {
+        fs::read(path).unwrap_or_else(|e| fail(e))
+    } else {
+        Vec::new()
+    };
+    append(archive, name, *ino, meta.mode(), &data);
+    if !meta.is_dir() {
+        return;
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).unwrap_or_else(|e| fail(e)) {
+        entries.push(entry.unwrap_or_else(|e| fail(e)).file_name());
+    }
+    entries.sort();
+    for entry in entries {
+        let entry = match entry.to_str() {
+            Some(entry) => entry.to_owned(),
+            None => panic!("{}: file names must be UTF-8", path.display()),
+        };
+        pack(
+            archive,
+            &path.join(&entry),
+            &format!("{}/{}", name, entry),
+            ino,
+        );
+    }
+}
+
+fn append(archive: &mut Vec<u8>, name: &str, ino: u32, mode: u32, data: &[u8]) {
+    let nlink = if mode & S_IFMT == S_IFDIR { 2 } else { 1 };
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize and check. Owned by root and without times, so that builds are reproducible.
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        nlink,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad(archive);
+    archive.extend_from_slice(data);
+    pad(archive);
+}
+
+fn pad(archive: &mut Vec<u8>) {
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
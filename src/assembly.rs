@@ -3,3 +3,4 @@ use core::arch::global_asm;
 global_asm!(include_str!("asm/boot.S"));
 global_asm!(include_str!("asm/trap.S"));
 global_asm!(include_str!("asm/mem.S"));
+global_asm!(include_str!("asm/init.S"));
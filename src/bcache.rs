@@ -0,0 +1,413 @@
+extern crate alloc;
+use crate::block::{self, BlockError, Operation, Request, SECTOR_SIZE};
+use crate::lock::{SpinOnce, Spinlock};
+use crate::timer;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of a cached block, filesystems with larger blocks span several.
+pub const BLOCK_SIZE: usize = 1024;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+/// Number of cached blocks, see the statistics before changing it. They come out of the
+/// kernel heap, which is only 256 KiB.
+const BUFFERS: usize = 32;
+/// Dirty blocks older than this are written back by the timer interrupt.
+const WRITEBACK_NS: u64 = 5_000_000_000;
+/// Lookups a restartable system call makes before its misses are polled. Half the cache, so
+/// that what earlier attempts read is still cached when the call is restarted.
+const RESTARTABLE_LOOKUPS: usize = BUFFERS / 2;
+
+static CACHE: SpinOnce<Spinlock<Cache>> = SpinOnce::new();
+// lookups left to the running system call before misses are polled, 0 outside of
+// restartable. One for all harts, system calls run under the kernel lock.
+static LOOKUPS_LEFT: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters of the cache since boot.
+#[derive(Copy, Clone, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub read_aheads: u64,
+    pub write_backs: u64,
+    pub evictions: u64,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let lookups = self.hits + self.misses;
+        writeln!(f, "buffers:     {} x {} bytes", BUFFERS, BLOCK_SIZE)?;
+        writeln!(
+            f,
+            "hits:        {} ({}%)",
+            self.hits,
+            self.hits * 100 / core::cmp::max(lookups, 1)
+        )?;
+        writeln!(f, "misses:      {}", self.misses)?;
+        writeln!(f, "read-aheads: {}", self.read_aheads)?;
+        writeln!(f, "write-backs: {}", self.write_backs)?;
+        writeln!(f, "evictions:   {}", self.evictions)
+    }
+}
+
+struct Buffer {
+    disk: usize,
+    block: u64,
+    valid: bool,
+    dirty: bool,
+    // value of the cache's clock when the block was last used, the smallest is evicted first
+    used: u64,
+    // a read-ahead or write-back the device hasn't finished, the data must be left alone
+    io: Option<(Operation, Arc<Request>)>,
+    data: Box<[u8; BLOCK_SIZE]>,
+}
+
+struct Cache {
+    buffers: Vec<Buffer>,
+    clock: u64,
+    last_writeback: u64,
+    // bit n: disk n has been written since it was last flushed
+    unflushed: usize,
+    // flushes sync started that the disks may not have completed
+    flushes: Vec<Arc<Request>>,
+    stats: Stats,
+}
+
+impl Buffer {
+    fn holds(&self, disk: usize, block: u64) -> bool {
+        self.disk == disk && self.block == block && (self.valid || self.io.is_some())
+    }
+    /// Finishes the I/O in flight, by polling if `wait` is set.
+    fn settle(&mut self, wait: bool) {
+        let (operation, request) = match self.io.take() {
+            Some(io) => io,
+            None => return,
+        };
+        if !wait && !request.done() {
+            self.io = Some((operation, request));
+            return;
+        }
+        match (operation, block::wait(self.disk, &request)) {
+            (Operation::Read, result) => self.valid = result.is_ok(),
+            (Operation::Write, Err(e)) => {
+                println!("bcache: write-back of block {}: {}", self.block, e);
+                self.dirty = true;
+            }
+            _ => {}
+        }
+    }
+    /// Finishes the I/O in flight, by polling if `poll` is set. Otherwise I/O the device
+    /// hasn't completed is left in flight and its channel returned in BlockError::WouldBlock.
+    fn finish(&mut self, poll: bool) -> Result<(), BlockError> {
+        match self.io {
+            Some((_, ref request)) if !poll && !request.done() => {
+                Err(BlockError::WouldBlock(request.channel()))
+            }
+            _ => {
+                self.settle(true);
+                Ok(())
+            }
+        }
+    }
+    /// Starts writing the block back, it stays dirty until the write is submitted.
+    fn write_back(&mut self) -> Result<(), BlockError> {
+        let request = unsafe {
+            block::submit(
+                self.disk,
+                Operation::Write,
+                self.block * SECTORS_PER_BLOCK,
+                self.data.as_mut_ptr(),
+                BLOCK_SIZE,
+            )?
+        };
+        self.dirty = false;
+        self.io = Some((Operation::Write, request));
+        Ok(())
+    }
+}
+
+impl Cache {
+    fn lookup(&self, disk: usize, block: u64) -> Option<usize> {
+        self.buffers.iter().position(|b| b.holds(disk, block))
+    }
+    /// The least recently used buffer, preferring clean ones without I/O. With `idle_only` it
+    /// is one that can be reused right away, or none.
+    fn victim(&mut self, idle_only: bool) -> Option<usize> {
+        let idle = |b: &Buffer| b.io.is_none() && !b.dirty;
+        let lru = |(_, b): &(usize, &Buffer)| b.used;
+        let found = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| idle(b))
+            .min_by_key(lru);
+        match found {
+            Some((i, _)) => Some(i),
+            None if idle_only => None,
+            None => self
+                .buffers
+                .iter()
+                .enumerate()
+                .min_by_key(lru)
+                .map(|(i, _)| i),
+        }
+    }
+    /// Makes buffer `i` free for another block, writing its contents back if needed. I/O is
+    /// waited for like by Buffer::finish.
+    fn evict(&mut self, i: usize, poll: bool) -> Result<(), BlockError> {
+        let buffer = &mut self.buffers[i];
+        buffer.finish(poll)?;
+        if buffer.dirty {
+            buffer.write_back()?;
+            self.stats.write_backs += 1;
+            self.unflushed |= 1 << buffer.disk;
+            buffer.finish(poll)?;
+            if buffer.dirty {
+                return Err(BlockError::IoError);
+            }
+        }
+        if buffer.valid {
+            self.stats.evictions += 1;
+        }
+        buffer.valid = false;
+        Ok(())
+    }
+    /// The buffer holding `block`, read from the disk if it isn't cached. Within
+    /// restartable a miss only starts the read and fails with BlockError::WouldBlock.
+    fn get(&mut self, disk: usize, block: u64) -> Result<usize, BlockError> {
+        let poll = LOOKUPS_LEFT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_err();
+        self.clock += 1;
+        if let Some(i) = self.lookup(disk, block) {
+            let buffer = &mut self.buffers[i];
+            buffer.used = self.clock;
+            buffer.finish(poll)?;
+            if buffer.valid {
+                self.stats.hits += 1;
+                return Ok(i);
+            }
+        }
+        self.stats.misses += 1;
+        let i = self.victim(false).expect("buffer cache without buffers");
+        self.evict(i, poll)?;
+        let buffer = &mut self.buffers[i];
+        let sector = block * SECTORS_PER_BLOCK;
+        // tagged once the read has been started or succeeded, a failed one leaves the buffer
+        // invalid
+        if poll {
+            block::read(disk, sector, &mut buffer.data[..])?;
+            buffer.valid = true;
+        } else {
+            let request = unsafe {
+                block::submit(
+                    disk,
+                    Operation::Read,
+                    sector,
+                    buffer.data.as_mut_ptr(),
+                    BLOCK_SIZE,
+                )?
+            };
+            buffer.io = Some((Operation::Read, request));
+        }
+        buffer.disk = disk;
+        buffer.block = block;
+        buffer.used = self.clock;
+        buffer.finish(poll)?;
+        Ok(i)
+    }
+    fn read_ahead(&mut self, disk: usize, block: u64) {
+        if self.lookup(disk, block).is_some() {
+            return;
+        }
+        // never worth writing something back for
+        let i = match self.victim(true) {
+            Some(i) => i,
+            None => return,
+        };
+        let buffer = &mut self.buffers[i];
+        let request = unsafe {
+            block::submit(
+                disk,
+                Operation::Read,
+                block * SECTORS_PER_BLOCK,
+                buffer.data.as_mut_ptr(),
+                BLOCK_SIZE,
+            )
+        };
+        // past the end of the disk, or the queue is full
+        if let Ok(request) = request {
+            if buffer.valid {
+                self.stats.evictions += 1;
+            }
+            buffer.disk = disk;
+            buffer.block = block;
+            buffer.valid = false;
+            // just behind the blocks in use, so unused read-aheads go first
+            buffer.used = self.clock.saturating_sub(1);
+            buffer.io = Some((Operation::Read, request));
+            self.stats.read_aheads += 1;
+        }
+    }
+    /// Starts writing back every dirty block, returns a write that is still in flight.
+    fn write_back_all(&mut self) -> Option<Arc<Request>> {
+        let mut pending = None;
+        for buffer in self.buffers.iter_mut() {
+            buffer.settle(false);
+            if buffer.dirty && buffer.io.is_none() {
+                match buffer.write_back() {
+                    Ok(()) => {
+                        self.stats.write_backs += 1;
+                        self.unflushed |= 1 << buffer.disk;
+                    }
+                    Err(e) => println!("bcache: write-back of block {}: {}", buffer.block, e),
+                }
+            }
+            if let Some((Operation::Write, ref request)) = buffer.io {
+                pending = Some(request.clone());
+            }
+        }
+        pending
+    }
+}
+
+/// Allocates the buffers, once the kernel heap is up.
+pub fn init() {
+    CACHE.call_once(|| {
+        let buffers = (0..BUFFERS)
+            .map(|_| Buffer {
+                disk: 0,
+                block: 0,
+                valid: false,
+                dirty: false,
+                used: 0,
+                io: None,
+                data: Box::new([0; BLOCK_SIZE]),
+            })
+            .collect();
+        Spinlock::new(Cache {
+            buffers,
+            clock: 0,
+            last_writeback: timer::mtime(),
+            unflushed: 0,
+            flushes: Vec::new(),
+            stats: Stats::default(),
+        })
+    });
+}
+
+fn cache() -> crate::lock::SpinlockGuard<'static, Cache> {
+    CACHE.get().expect("buffer cache not initialized").lock()
+}
+
+/// Runs the system call `f` so that the blocks it misses are read without polling. A miss
+/// fails with BlockError::WouldBlock on the channel of the read, the process is to block on
+/// it and f to be restarted from the beginning. Once f has written to the cache, which doing
+/// it again would repeat, or made RESTARTABLE_LOOKUPS lookups, misses are polled like those
+/// of the kernel itself.
+pub fn restartable<R>(f: impl FnOnce() -> R) -> R {
+    LOOKUPS_LEFT.store(RESTARTABLE_LOOKUPS, Ordering::Relaxed);
+    let result = f();
+    LOOKUPS_LEFT.store(0, Ordering::Relaxed);
+    result
+}
+
+/// Reads `buf.len()` bytes from byte `offset` of `disk` on through the cache. The block after
+/// the last one read is read ahead.
+pub fn read(disk: usize, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let mut cache = cache();
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let block = position / BLOCK_SIZE as u64;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let len = core::cmp::min(BLOCK_SIZE - start, buf.len() - done);
+        let i = cache.get(disk, block)?;
+        buf[done..done + len].copy_from_slice(&cache.buffers[i].data[start..start + len]);
+        done += len;
+    }
+    let next = (offset + buf.len() as u64).div_ceil(BLOCK_SIZE as u64);
+    cache.read_ahead(disk, next);
+    Ok(())
+}
+
+/// Writes `buf` at byte `offset` of `disk`. The blocks are only marked dirty, they reach the
+/// disk on eviction, by the periodic write-back or by sync.
+pub fn write(disk: usize, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+    let mut cache = cache();
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let block = position / BLOCK_SIZE as u64;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let len = core::cmp::min(BLOCK_SIZE - start, buf.len() - done);
+        // whole blocks would not need to be read first, but are rare enough
+        let i = cache.get(disk, block)?;
+        LOOKUPS_LEFT.store(0, Ordering::Relaxed);
+        let buffer = &mut cache.buffers[i];
+        buffer.data[start..start + len].copy_from_slice(&buf[done..done + len]);
+        buffer.dirty = true;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Hints that `block` of `disk` will be read soon, it is read in the background if a buffer is
+/// free for it.
+pub fn read_ahead(disk: usize, block: u64) {
+    cache().read_ahead(disk, block);
+}
+
+/// Called from the timer interrupt, starts the write-back of the dirty blocks every few
+/// seconds without waiting for it.
+pub fn periodic() {
+    let cache = match CACHE.get() {
+        Some(cache) => cache,
+        None => return,
+    };
+    let mut cache = cache.lock();
+    let now = timer::mtime();
+    if now - cache.last_writeback >= timer::ns_to_ticks(WRITEBACK_NS) {
+        cache.last_writeback = now;
+        cache.write_back_all();
+    }
+}
+
+/// Writes back every dirty block, then flushes the disks' own caches. Returns a write or
+/// flush still in flight to wait for, sync is done once it returns None.
+pub fn sync() -> Option<Arc<Request>> {
+    let mut cache = cache();
+    if let Some(request) = cache.write_back_all() {
+        return Some(request);
+    }
+    for disk in 0..block::disks() {
+        if cache.unflushed & 1 << disk != 0 {
+            let request =
+                unsafe { block::submit(disk, Operation::Flush, 0, core::ptr::null_mut(), 0) };
+            match request {
+                Ok(request) => cache.flushes.push(request),
+                // a disk without a write cache has nothing to flush
+                Err(BlockError::Unsupported) => {}
+                Err(e) => println!("bcache: flushing disk {}: {}", disk, e),
+            }
+        }
+    }
+    cache.unflushed = 0;
+    cache.flushes.retain(|request| match request.result() {
+        None => true,
+        Some(Ok(())) => false,
+        Some(Err(e)) => {
+            println!("bcache: flushing: {}", e);
+            false
+        }
+    });
+    cache.flushes.first().cloned()
+}
+
+pub fn stats() -> Stats {
+    cache().stats
+}
@@ -0,0 +1,329 @@
+extern crate alloc;
+use crate::fdt::MAX_VIRTIO;
+use crate::lock::Spinlock;
+use crate::sched;
+use crate::virtio::{self, Buffer, Driver, Mmio, VirtioError, Virtqueue, QUEUE_SIZE};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::{Display, Formatter};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// configuration space
+const CONFIG_CAPACITY: usize = 0;
+
+// request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// request status, written by the device
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// Driver of virtio-blk devices. Disks are numbered in the order they were attached, disk 0 is
+/// the hdd.dsk of the QEMU runner.
+pub struct Block;
+
+pub static DRIVER: Block = Block;
+
+const NO_DISK: Option<Disk> = None;
+// by virtio-mmio slot
+static DISKS: Spinlock<[Option<Disk>; MAX_VIRTIO]> = Spinlock::new([NO_DISK; MAX_VIRTIO]);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockError {
+    NoDevice,
+    OutOfRange,
+    Misaligned,
+    ReadOnly,
+    Unsupported,
+    IoError,
+    Busy,
+    /// The request on this channel has to complete first, see bcache::restartable.
+    WouldBlock(usize),
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            BlockError::NoDevice => "no such disk",
+            BlockError::OutOfRange => "sectors beyond the end of the disk",
+            BlockError::Misaligned => "buffer is not a whole number of sectors",
+            BlockError::ReadOnly => "disk is read-only",
+            BlockError::Unsupported => "operation not supported by the disk",
+            BlockError::IoError => "I/O error",
+            BlockError::Busy => "too many requests in flight",
+            BlockError::WouldBlock(_) => "request not completed yet",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+}
+
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A request handed to a disk. The driver keeps it alive until the device completed it, the
+/// submitter may wait for that by polling or by blocking on its channel.
+pub struct Request {
+    header: Header,
+    // written by the device
+    status: UnsafeCell<u8>,
+    done: AtomicBool,
+}
+
+// the device writes status before the driver sets done, nobody else writes either
+unsafe impl Sync for Request {}
+unsafe impl Send for Request {}
+
+impl Request {
+    pub fn done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+    /// The outcome once the device completed the request.
+    pub fn result(&self) -> Option<Result<(), BlockError>> {
+        if !self.done() {
+            return None;
+        }
+        Some(match unsafe { self.status.get().read_volatile() } {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(BlockError::Unsupported),
+            _ => Err(BlockError::IoError),
+        })
+    }
+    /// Processes waiting for the request block on this channel, sched::wake is called on it
+    /// when the request completes.
+    pub fn channel(&self) -> usize {
+        self as *const Request as usize
+    }
+}
+
+struct Disk {
+    mmio: Mmio,
+    queue: Virtqueue,
+    // in 512 byte sectors
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+    // requests the device has not completed, by the head of their descriptor chain
+    in_flight: Vec<Option<Arc<Request>>>,
+}
+
+impl Disk {
+    /// Moves the requests the device completed out of the used ring.
+    fn complete(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            if let Some(request) = self.in_flight[head as usize].take() {
+                request.done.store(true, Ordering::Release);
+                sched::wake(request.channel());
+            }
+        }
+    }
+}
+
+impl Driver for Block {
+    fn name(&self) -> &'static str {
+        "block"
+    }
+    fn device_id(&self) -> u32 {
+        virtio::ID_BLOCK
+    }
+    fn attach(&self, slot: usize, mmio: Mmio) -> Result<(), VirtioError> {
+        let features = mmio.negotiate(F_RO | F_FLUSH)?;
+        let queue = mmio.setup_queue(0)?;
+        let capacity = mmio.config_u64(CONFIG_CAPACITY);
+        mmio.driver_ok();
+        let mut in_flight = Vec::with_capacity(QUEUE_SIZE);
+        in_flight.resize(QUEUE_SIZE, None);
+        println!(
+            "block: disk {}, {} sectors ({} KiB){}",
+            disks(),
+            capacity,
+            capacity * SECTOR_SIZE as u64 / 1024,
+            if features & F_RO != 0 {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+        DISKS.lock()[slot] = Some(Disk {
+            mmio,
+            queue,
+            capacity,
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+            in_flight,
+        });
+        Ok(())
+    }
+    fn interrupt(&self, slot: usize, status: u32) {
+        if status & virtio::INTERRUPT_USED_BUFFER == 0 {
+            return;
+        }
+        if let Some(disk) = DISKS.lock()[slot].as_mut() {
+            disk.complete();
+        }
+    }
+}
+
+/// Number of attached disks.
+pub fn disks() -> usize {
+    DISKS.lock().iter().flatten().count()
+}
+
+/// Runs `f` on disk number `disk`.
+fn with_disk<R>(disk: usize, f: impl FnOnce(&mut Disk) -> R) -> Result<R, BlockError> {
+    let mut disks = DISKS.lock();
+    let found = disks.iter_mut().flatten().nth(disk);
+    found.map(f).ok_or(BlockError::NoDevice)
+}
+
+/// Size of disk number `disk` in sectors.
+pub fn capacity(disk: usize) -> Result<u64, BlockError> {
+    with_disk(disk, |d| d.capacity)
+}
+
+/// Starts an operation on `len` bytes at `buffer`, from sector `sector` on. A read fills the
+/// buffer, a write sends it, a flush ignores both. Completion is signalled through the
+/// returned request, whether the caller polls or not.
+///
+/// # Safety
+/// The buffer must stay valid and, for reads, unused until the request is done. Its
+/// physical and virtual addresses must be the same, like those of the kernel heap.
+pub unsafe fn submit(
+    disk: usize,
+    operation: Operation,
+    sector: u64,
+    buffer: *mut u8,
+    len: usize,
+) -> Result<Arc<Request>, BlockError> {
+    if len % SECTOR_SIZE != 0 {
+        return Err(BlockError::Misaligned);
+    }
+    let kind = match operation {
+        Operation::Read => T_IN,
+        Operation::Write => T_OUT,
+        Operation::Flush => T_FLUSH,
+    };
+    let request = Arc::new(Request {
+        header: Header {
+            kind,
+            reserved: 0,
+            sector: if operation == Operation::Flush {
+                0
+            } else {
+                sector
+            },
+        },
+        status: UnsafeCell::new(0xff),
+        done: AtomicBool::new(false),
+    });
+    with_disk(disk, |d| {
+        let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+        match operation {
+            Operation::Flush if !d.can_flush => return Err(BlockError::Unsupported),
+            Operation::Flush => {}
+            _ if end.map_or(true, |end| end > d.capacity) => return Err(BlockError::OutOfRange),
+            Operation::Write if d.read_only => return Err(BlockError::ReadOnly),
+            _ => {}
+        }
+        let header = Buffer {
+            addr: &request.header as *const Header as usize,
+            len: core::mem::size_of::<Header>() as u32,
+            writable: false,
+        };
+        let data = Buffer {
+            addr: buffer as usize,
+            len: len as u32,
+            writable: operation == Operation::Read,
+        };
+        let status = Buffer {
+            addr: request.status.get() as usize,
+            len: 1,
+            writable: true,
+        };
+        let with_data = [header, data, status];
+        let without_data = [header, status];
+        let chain: &[Buffer] = if operation == Operation::Flush {
+            &without_data
+        } else {
+            &with_data
+        };
+        // the ring may be full of requests completed while interrupts were off
+        let head = match d.queue.add(chain) {
+            Some(head) => head,
+            None => {
+                d.complete();
+                d.queue.add(chain).ok_or(BlockError::Busy)?
+            }
+        };
+        d.in_flight[head as usize] = Some(request.clone());
+        d.mmio.notify(d.queue.index());
+        Ok(())
+    })??;
+    Ok(request)
+}
+
+/// Waits for `request` by polling the disk, for callers that can't block, like the kernel
+/// itself.
+pub fn wait(disk: usize, request: &Request) -> Result<(), BlockError> {
+    loop {
+        if let Some(result) = request.result() {
+            return result;
+        }
+        with_disk(disk, |d| d.complete())?;
+        spin_loop();
+    }
+}
+
+/// Blocks the current process until `request` is done, it then resumes at `pc`. System calls
+/// pass their own address and find the request done when they are restarted. The request is
+/// taken by value as nothing on the kernel stack is dropped once the process is switched out.
+pub fn block_on(request: Arc<Request>, pc: usize) -> ! {
+    let (done, channel) = (request.done(), request.channel());
+    drop(request);
+    // the completion can't slip in between, interrupts are off and the trap holds the kernel
+    // lock
+    if done {
+        sched::yield_current(pc);
+    }
+    sched::block_on(channel, pc)
+}
+
+/// Reads whole sectors from `sector` on into `buf`, polling for the completion.
+pub fn read(disk: usize, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let request = unsafe { submit(disk, Operation::Read, sector, buf.as_mut_ptr(), buf.len())? };
+    wait(disk, &request)
+}
+
+/// Writes whole sectors from `sector` on, polling for the completion.
+pub fn write(disk: usize, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+    let request = unsafe {
+        submit(
+            disk,
+            Operation::Write,
+            sector,
+            buf.as_ptr() as *mut u8,
+            buf.len(),
+        )?
+    };
+    wait(disk, &request)
+}
@@ -1,5 +1,14 @@
 #![allow(unused)]
 use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Harts the kernel keeps per-hart state for.
+pub const MAX_HARTS: usize = 8;
+
+extern "C" {
+    static KERNEL_LOCK: AtomicU32;
+}
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct TrapFrame {
@@ -29,12 +38,31 @@ pub enum SatpMode {
     Sv48 = 9,
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+
+/// Takes the big kernel lock. The trap vector takes it on every trap, it is released when the
+/// hart leaves the kernel through the trap vector or switch_to_user.
+pub fn kernel_lock() {
+    unsafe {
+        while KERNEL_LOCK.swap(1, Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+    }
+}
 
 pub const fn build_satp(mode: SatpMode, asid: u16, addr: usize) -> usize {
     (mode as usize) << 60 | (asid as usize) << 44 | (addr >> 12) & 0xff_ffff_ffff
 }
 
+/// Id of the hart running the kernel, the trap vector keeps it in tp while in supervisor mode.
+pub fn hartid() -> usize {
+    unsafe {
+        let rval;
+        asm!("mv {}, tp", out(reg) rval);
+        rval
+    }
+}
+
 pub fn mhartid_read() -> usize {
     unsafe {
         let rval;
@@ -57,6 +85,83 @@ pub fn mstatus_read() -> usize {
     }
 }
 
+pub fn mcause_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr	{}, mcause", out(reg) rval);
+        rval
+    }
+}
+
+pub fn mepc_write(val: usize) {
+    unsafe {
+        asm!("csrw	mepc, {}", in(reg) val);
+    }
+}
+
+pub fn mepc_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr	{}, mepc", out(reg) rval);
+        rval
+    }
+}
+
+pub fn mie_set(bits: usize) {
+    unsafe {
+        asm!("csrs	mie, {}", in(reg) bits);
+    }
+}
+
+pub fn mie_clear(bits: usize) {
+    unsafe {
+        asm!("csrc	mie, {}", in(reg) bits);
+    }
+}
+
+pub fn mip_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr	{}, mip", out(reg) rval);
+        rval
+    }
+}
+
+pub fn mip_set(bits: usize) {
+    unsafe {
+        asm!("csrs	mip, {}", in(reg) bits);
+    }
+}
+
+pub fn mip_clear(bits: usize) {
+    unsafe {
+        asm!("csrc	mip, {}", in(reg) bits);
+    }
+}
+
+pub fn sip_clear(bits: usize) {
+    unsafe {
+        asm!("csrc	sip, {}", in(reg) bits);
+    }
+}
+
+/// Clears sstatus.SIE, returns whether supervisor interrupts were enabled before.
+pub fn interrupts_disable() -> bool {
+    unsafe {
+        let rval: usize;
+        asm!("csrrci	{}, sstatus, 1 << 1", out(reg) rval);
+        rval & 1 << 1 != 0
+    }
+}
+
+pub fn interrupts_restore(enabled: bool) {
+    if enabled {
+        unsafe {
+            asm!("csrsi	sstatus, 1 << 1");
+        }
+    }
+}
+
 pub fn stvec_write(val: usize) {
     unsafe {
         asm!("csrw	stvec, {}", in(reg) val);
@@ -154,3 +259,10 @@ pub fn satp_fence_asid(asid: usize) {
         asm!("sfence.vma zero, {}", in(reg)asid);
     }
 }
+
+/// Orders memory and device accesses, for handing buffers to devices that read memory directly.
+pub fn io_fence() {
+    unsafe {
+        asm!("fence iorw, iorw");
+    }
+}
@@ -0,0 +1,207 @@
+extern crate alloc;
+use crate::bcache;
+use crate::block::{self, SECTOR_SIZE};
+use crate::lock::Spinlock;
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, S_IFBLK, S_IFCHR, S_IFDIR, S_IFMT};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// A device read and written as a stream of bytes, offsets mean nothing to it.
+pub trait CharDevice: Sync {
+    /// Reads what the device has, FsError::WouldBlock if that is nothing yet.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError>;
+}
+
+// in the order they were registered
+static CHAR_DEVICES: Spinlock<Vec<(&'static str, &'static dyn CharDevice)>> =
+    Spinlock::new(Vec::new());
+
+/// Makes `device` show up as /dev/`name`.
+pub fn register(name: &'static str, device: &'static dyn CharDevice) {
+    let mut devices = CHAR_DEVICES.lock();
+    if devices.iter().any(|&(n, _)| n == name) {
+        println!("devfs: {} registered twice", name);
+        return;
+    }
+    devices.push((name, device));
+}
+
+/// The registered character devices and the disks, vda being disk 0.
+pub struct Devfs;
+
+#[derive(Copy, Clone)]
+enum Entry {
+    Root,
+    // by index of registration
+    Char(usize),
+    Disk(usize),
+}
+
+impl Entry {
+    fn ino(self) -> u64 {
+        match self {
+            Entry::Root => 1,
+            Entry::Char(index) => 2 + index as u64,
+            Entry::Disk(disk) => 0x100 + disk as u64,
+        }
+    }
+    fn mode(self) -> u32 {
+        match self {
+            Entry::Root => S_IFDIR | 0o755,
+            Entry::Char(_) => S_IFCHR | 0o666,
+            Entry::Disk(_) => S_IFBLK | 0o600,
+        }
+    }
+}
+
+fn char_device(index: usize) -> Result<&'static dyn CharDevice, FsError> {
+    match CHAR_DEVICES.lock().get(index) {
+        Some(&(_, device)) => Ok(device),
+        None => Err(FsError::NotFound),
+    }
+}
+
+/// vda, vdb and so on, like Linux names virtio disks.
+fn disk_name(disk: usize) -> String {
+    format!("vd{}", (b'a' + disk as u8) as char)
+}
+
+/// Size of `disk` in bytes.
+fn disk_size(disk: usize) -> Result<u64, FsError> {
+    Ok(block::capacity(disk)? * SECTOR_SIZE as u64)
+}
+
+struct Node {
+    entry: Entry,
+}
+
+impl Node {
+    fn node(entry: Entry) -> Arc<dyn Inode> {
+        Arc::new(Node { entry })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let size = match self.entry {
+            Entry::Disk(disk) => disk_size(disk)?,
+            _ => 0,
+        };
+        Ok(Stat {
+            ino: self.entry.ino(),
+            mode: self.entry.mode(),
+            nlink: 1,
+            size,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.entry {
+            Entry::Root => Err(FsError::IsDir),
+            Entry::Char(index) => char_device(index)?.read(buf),
+            Entry::Disk(disk) => {
+                // through the cache, so that reads see what mounted filesystems wrote
+                let size = disk_size(disk)?;
+                if offset >= size {
+                    return Ok(0);
+                }
+                let len = core::cmp::min(buf.len() as u64, size - offset) as usize;
+                bcache::read(disk, offset, &mut buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        match self.entry {
+            Entry::Root => Err(FsError::IsDir),
+            Entry::Char(index) => char_device(index)?.write(buf),
+            Entry::Disk(disk) => {
+                let size = disk_size(disk)?;
+                if offset >= size && !buf.is_empty() {
+                    return Err(FsError::NoSpace);
+                }
+                let len = core::cmp::min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+                bcache::write(disk, offset, &buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if !matches!(self.entry, Entry::Root) {
+            return Err(FsError::NotDir);
+        }
+        let devices = CHAR_DEVICES.lock();
+        if let Some(index) = devices.iter().position(|&(n, _)| n == name) {
+            return Ok(Node::node(Entry::Char(index)));
+        }
+        match (0..block::disks()).find(|&disk| disk_name(disk) == name) {
+            Some(disk) => Ok(Node::node(Entry::Disk(disk))),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, _name: &str, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        if !matches!(self.entry, Entry::Root) {
+            return Err(FsError::NotDir);
+        }
+        let devices = CHAR_DEVICES.lock();
+        let (name, entry) = match devices.get(index) {
+            Some(&(name, _)) => (String::from(name), Entry::Char(index)),
+            None if index - devices.len() < block::disks() => {
+                let disk = index - devices.len();
+                (disk_name(disk), Entry::Disk(disk))
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(DirEntry {
+            ino: entry.ino(),
+            mode: entry.mode() & S_IFMT,
+            name,
+        }))
+    }
+}
+
+impl Filesystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Node::node(Entry::Root)
+    }
+}
+
+/// /dev/null, reads find nothing and writes go nowhere.
+pub struct Null;
+
+pub static NULL: Null = Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// /dev/zero, reads find zeros and writes go nowhere.
+pub struct Zero;
+
+pub static ZERO: Zero = Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,272 @@
+use crate::page::{entry_bits, Pmem, Table, PAGE_SIZE};
+use core::fmt::{Display, Formatter};
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const VERSION_CURRENT: u32 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_RISCV: u16 = 243;
+
+const HEADER_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// End of the lower half of the Sv39 address space, user images must stay below it.
+pub const USER_END: usize = 1 << 38;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    SegmentOutOfBounds,
+    SegmentMisaligned,
+    SegmentNoAccess,
+    ReservedAddress,
+    NoLoadableSegments,
+    BadEntry,
+    OutOfMemory,
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            ElfError::TooShort => "image is shorter than its headers",
+            ElfError::BadMagic => "not an ELF image",
+            ElfError::NotElf64 => "not a 64 bit ELF image",
+            ElfError::NotLittleEndian => "not a little endian ELF image",
+            ElfError::BadVersion => "unsupported ELF version",
+            ElfError::NotExecutable => "not an executable (ET_EXEC) image",
+            ElfError::WrongMachine => "not a RISC-V image",
+            ElfError::BadProgramHeaders => "malformed program header table",
+            ElfError::SegmentOutOfBounds => "segment exceeds the image or user address space",
+            ElfError::SegmentMisaligned => "segment offset and address are not congruent",
+            ElfError::SegmentNoAccess => "segment has no access permissions",
+            ElfError::ReservedAddress => "segment overlaps a reserved address range",
+            ElfError::NoLoadableSegments => "image has no PT_LOAD segments",
+            ElfError::BadEntry => "entry point is not in an executable segment",
+            ElfError::OutOfMemory => "out of memory while loading segments",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+#[derive(Copy, Clone)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub vaddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub align: usize,
+}
+
+impl Segment {
+    fn parse(data: &[u8]) -> Self {
+        Segment {
+            kind: read_u32(data, 0),
+            flags: read_u32(data, 4),
+            offset: read_u64(data, 8),
+            vaddr: read_u64(data, 16),
+            filesz: read_u64(data, 32),
+            memsz: read_u64(data, 40),
+            align: read_u64(data, 48),
+        }
+    }
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+    pub fn end(&self) -> usize {
+        self.vaddr + self.memsz
+    }
+    pub fn bits(&self) -> u64 {
+        let mut bits = entry_bits::USER;
+        if self.flags & PF_R != 0 {
+            bits |= entry_bits::READ;
+        }
+        // write-only mappings are reserved encodings in Sv39
+        if self.flags & PF_W != 0 {
+            bits |= entry_bits::READ_WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            bits |= entry_bits::EXECUTE;
+        }
+        bits
+    }
+    fn validate(&self, image_len: usize) -> Result<(), ElfError> {
+        let file_end = self
+            .offset
+            .checked_add(self.filesz)
+            .ok_or(ElfError::SegmentOutOfBounds)?;
+        let mem_end = self
+            .vaddr
+            .checked_add(self.memsz)
+            .ok_or(ElfError::SegmentOutOfBounds)?;
+        if file_end > image_len || self.filesz > self.memsz || mem_end > USER_END {
+            return Err(ElfError::SegmentOutOfBounds);
+        }
+        if self.align > 1 && self.vaddr % PAGE_SIZE != self.offset % PAGE_SIZE {
+            return Err(ElfError::SegmentMisaligned);
+        }
+        if self.flags & (PF_R | PF_W | PF_X) == 0 {
+            return Err(ElfError::SegmentNoAccess);
+        }
+        Ok(())
+    }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != DATA_LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] as u32 != VERSION_CURRENT || read_u32(data, 20) != VERSION_CURRENT {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(data, 16) != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != MACHINE_RISCV {
+            return Err(ElfError::WrongMachine);
+        }
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            phoff: read_u64(data, 32),
+            phnum: read_u16(data, 56) as usize,
+        };
+        let phentsize = read_u16(data, 54) as usize;
+        let table_end = elf
+            .phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff));
+        match table_end {
+            Some(end) if phentsize == PHDR_SIZE && end <= data.len() => {}
+            _ => return Err(ElfError::BadProgramHeaders),
+        }
+
+        let mut loadable = false;
+        let mut entry_valid = false;
+        for segment in elf.segments().filter(Segment::is_load) {
+            segment.validate(data.len())?;
+            loadable = true;
+            if segment.flags & PF_X != 0 && (segment.vaddr..segment.end()).contains(&elf.entry) {
+                entry_valid = true;
+            }
+        }
+        if !loadable {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !entry_valid {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+    pub fn phent_size(&self) -> usize {
+        PHDR_SIZE
+    }
+    /// User address of the program header table, if a PT_LOAD segment contains it.
+    pub fn phdr_address(&self) -> Option<usize> {
+        let table_end = self.phoff + self.phnum * PHDR_SIZE;
+        self.segments()
+            .filter(Segment::is_load)
+            .find(|s| s.offset <= self.phoff && table_end <= s.offset + s.filesz)
+            .map(|s| s.vaddr + (self.phoff - s.offset))
+    }
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.phnum).map(move |i| {
+            let offset = self.phoff + i * PHDR_SIZE;
+            Segment::parse(&self.data[offset..offset + PHDR_SIZE])
+        })
+    }
+    /// Maps every PT_LOAD segment into `root`, pages shared by two segments get the union of
+    /// their permissions. On error, pages mapped so far stay in `root` and are freed with it.
+    pub fn load(&self, root: &mut Table, pmem: &mut Pmem) -> Result<(), ElfError> {
+        for segment in self.segments().filter(Segment::is_load) {
+            let bits = segment.bits();
+            let file_end = segment.vaddr + segment.filesz;
+            let mut page = segment.vaddr & !(PAGE_SIZE - 1);
+            while page < segment.end() {
+                let phys = match Table::lookup(root, page) {
+                    Some(entry) => {
+                        entry.set_entry(entry.get_entry() | bits);
+                        entry.get_phys() as *mut u8
+                    }
+                    None => {
+                        let frame = pmem.zalloc(1);
+                        if !frame.available() {
+                            return Err(ElfError::OutOfMemory);
+                        }
+                        let frame = frame.leak();
+                        if !Table::map(root, pmem, page, frame as usize, bits, 0) {
+                            unsafe { pmem.dealloc_phys(frame) };
+                            return Err(ElfError::OutOfMemory);
+                        }
+                        frame
+                    }
+                };
+                let low = core::cmp::max(page, segment.vaddr);
+                let high = core::cmp::min(page + PAGE_SIZE, segment.end());
+                let copy_end = core::cmp::min(high, core::cmp::max(low, file_end));
+                unsafe {
+                    let dst = phys.add(low - page);
+                    if copy_end > low {
+                        let src =
+                            &self.data[segment.offset + (low - segment.vaddr)..][..copy_end - low];
+                        dst.copy_from_nonoverlapping(src.as_ptr(), src.len());
+                    }
+                    // bss
+                    phys.add(copy_end - page).write_bytes(0, high - copy_end);
+                }
+                page += PAGE_SIZE;
+            }
+        }
+        Ok(())
+    }
+}
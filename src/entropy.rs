@@ -0,0 +1,130 @@
+use crate::devfs::CharDevice;
+use crate::fdt::MAX_VIRTIO;
+use crate::lock::Spinlock;
+use crate::sched;
+use crate::vfs::FsError;
+use crate::virtio::{self, Buffer, Driver, Mmio, VirtioError, Virtqueue};
+
+const POOL_SIZE: usize = 64;
+
+/// Driver of virtio-rng devices, each keeps a small pool of random bytes it refills as it is
+/// drained.
+pub struct Entropy;
+
+pub static DRIVER: Entropy = Entropy;
+
+const NO_DEVICE: Option<Device> = None;
+// by virtio-mmio slot, the pools are written by the devices
+static DEVICES: Spinlock<[Option<Device>; MAX_VIRTIO]> = Spinlock::new([NO_DEVICE; MAX_VIRTIO]);
+
+struct Device {
+    mmio: Mmio,
+    queue: Virtqueue,
+    pool: [u8; POOL_SIZE],
+    // bytes of the pool the device filled that haven't been handed out
+    filled: usize,
+    requested: bool,
+}
+
+impl Device {
+    fn refill(&mut self) {
+        if self.requested {
+            return;
+        }
+        let buffer = Buffer {
+            addr: self.pool.as_ptr() as usize,
+            len: POOL_SIZE as u32,
+            writable: true,
+        };
+        if self.queue.add(&[buffer]).is_some() {
+            self.requested = true;
+            self.mmio.notify(self.queue.index());
+        }
+    }
+}
+
+impl Driver for Entropy {
+    fn name(&self) -> &'static str {
+        "entropy"
+    }
+    fn device_id(&self) -> u32 {
+        virtio::ID_ENTROPY
+    }
+    fn attach(&self, slot: usize, mmio: Mmio) -> Result<(), VirtioError> {
+        mmio.negotiate(0)?;
+        let queue = mmio.setup_queue(0)?;
+        mmio.driver_ok();
+        let mut devices = DEVICES.lock();
+        // the pool must not move once the device writes to it
+        let device = devices[slot].insert(Device {
+            mmio,
+            queue,
+            pool: [0; POOL_SIZE],
+            filled: 0,
+            requested: false,
+        });
+        device.refill();
+        Ok(())
+    }
+    fn interrupt(&self, slot: usize, status: u32) {
+        if status & virtio::INTERRUPT_USED_BUFFER == 0 {
+            return;
+        }
+        let mut devices = DEVICES.lock();
+        if let Some(device) = devices[slot].as_mut() {
+            while let Some((_, len)) = device.queue.pop_used() {
+                device.filled = len as usize;
+                device.requested = false;
+            }
+        }
+        drop(devices);
+        sched::wake(pool_channel());
+    }
+}
+
+/// The pools as /dev/random. A read returns what the pools hold and blocks while they are
+/// empty, what is written is thrown away.
+pub struct Random;
+
+pub static RANDOM: Random = Random;
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        match mix_into(buf) {
+            0 if !buf.is_empty() => Err(FsError::WouldBlock(pool_channel())),
+            mixed => Ok(mixed),
+        }
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Whether a virtio-rng device is attached.
+pub fn available() -> bool {
+    DEVICES.lock().iter().any(Option::is_some)
+}
+
+/// Readers of /dev/random block on this channel until a pool has been refilled.
+fn pool_channel() -> usize {
+    &DEVICES as *const _ as usize
+}
+
+/// XORs as many bytes as the pools hold into `buf`, returns their number. The drained pools
+/// are refilled in the background.
+pub fn mix_into(buf: &mut [u8]) -> usize {
+    let mut mixed = 0;
+    let mut devices = DEVICES.lock();
+    for device in devices.iter_mut().flatten() {
+        while mixed < buf.len() && device.filled > 0 {
+            device.filled -= 1;
+            buf[mixed] ^= device.pool[device.filled];
+            mixed += 1;
+        }
+        if device.filled == 0 {
+            device.refill();
+        }
+    }
+    mixed
+}
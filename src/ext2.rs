@@ -0,0 +1,902 @@
+extern crate alloc;
+use crate::bcache;
+use crate::lock::Spinlock;
+use crate::vfs::{
+    DirEntry, Filesystem, FsError, Inode, Stat, NAME_MAX, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const MAGIC: u16 = 0xef53;
+/// The superblock is the second kilobyte of the disk, whatever the block size.
+const SUPERBLOCK: u64 = 1024;
+const ROOT_INO: u32 = 2;
+const DESC_SIZE: u64 = 32;
+// what revision 0 filesystems have, later ones say in the superblock
+const OLD_INODE_SIZE: u64 = 128;
+const OLD_FIRST_INO: u32 = 11;
+
+// block slots of an inode, followed by the single, double and triple indirect ones
+const DIRECT: usize = 12;
+const BLOCK_SLOTS: usize = 15;
+// a symbolic link this short keeps its target in the block slots
+const FAST_SYMLINK_MAX: usize = 60;
+
+// features a driver must know to read the filesystem, or to write it
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+
+// a directory with a hash index, the index must go once entries change
+const INDEX_FL: u32 = 0x1000;
+
+/// An ext2 filesystem, as `mke2fs -t ext2` creates it.
+pub struct Ext2(Arc<Volume>);
+
+struct Volume {
+    disk: usize,
+    block_size: u64,
+    inodes: u32,
+    blocks: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    first_ino: u32,
+    groups: u32,
+    // directory entries carry the file type
+    filetype: bool,
+    // features this driver can't keep up to date
+    read_only: bool,
+    // operations touch several blocks that must change together: bitmaps, group
+    // descriptors, inodes and directories
+    lock: Spinlock<()>,
+}
+
+/// The fields of an on-disk inode this driver uses, the others are kept as they are.
+#[derive(Copy, Clone)]
+struct DiskInode {
+    ino: u32,
+    mode: u16,
+    links: u16,
+    size: u64,
+    // in 512 byte sectors, indirect blocks included
+    sectors: u32,
+    flags: u32,
+    block: [u32; BLOCK_SLOTS],
+    // block of extended attributes
+    file_acl: u32,
+    raw: [u8; OLD_INODE_SIZE as usize],
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+/// Type of a directory entry, from the mode of its inode.
+fn file_type(mode: u32) -> u8 {
+    // regular, directory, character and block device, FIFO, socket, symbolic link
+    match mode & S_IFMT {
+        S_IFREG => 1,
+        S_IFDIR => 2,
+        0o020000 => 3,
+        0o060000 => 4,
+        0o010000 => 5,
+        0o140000 => 6,
+        S_IFLNK => 7,
+        _ => 0,
+    }
+}
+
+/// Space a directory entry with a name of `len` bytes needs.
+fn entry_len(len: usize) -> usize {
+    (8 + len + 3) & !3
+}
+
+impl DiskInode {
+    fn parse(ino: u32, bytes: &[u8; OLD_INODE_SIZE as usize]) -> Self {
+        let mode = u16_at(bytes, 0);
+        let mut block = [0; BLOCK_SLOTS];
+        for (i, slot) in block.iter_mut().enumerate() {
+            *slot = u32_at(bytes, 40 + i * 4);
+        }
+        // the upper half of the size is the directory ACL for anything but files
+        let high = if mode as u32 & S_IFMT == S_IFREG {
+            u32_at(bytes, 108) as u64
+        } else {
+            0
+        };
+        DiskInode {
+            ino,
+            mode,
+            links: u16_at(bytes, 26),
+            size: high << 32 | u32_at(bytes, 4) as u64,
+            sectors: u32_at(bytes, 28),
+            flags: u32_at(bytes, 32),
+            block,
+            file_acl: u32_at(bytes, 104),
+            raw: *bytes,
+        }
+    }
+    fn to_bytes(self) -> [u8; OLD_INODE_SIZE as usize] {
+        let mut bytes = self.raw;
+        bytes[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.links.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.sectors.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        for (i, slot) in self.block.iter().enumerate() {
+            bytes[40 + i * 4..][..4].copy_from_slice(&slot.to_le_bytes());
+        }
+        bytes[104..108].copy_from_slice(&self.file_acl.to_le_bytes());
+        if self.mode as u32 & S_IFMT == S_IFREG {
+            bytes[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+        bytes
+    }
+    fn is_dir(&self) -> bool {
+        self.mode as u32 & S_IFMT == S_IFDIR
+    }
+    fn is_symlink(&self) -> bool {
+        self.mode as u32 & S_IFMT == S_IFLNK
+    }
+    /// A symbolic link with its target in the block slots, it has no data blocks.
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = if self.file_acl != 0 {
+            (block_size / 512) as u32
+        } else {
+            0
+        };
+        self.is_symlink() && self.sectors == acl_sectors
+    }
+}
+
+impl Ext2 {
+    /// Reads the superblock of `disk`, fails with Corrupt if it isn't an ext2 filesystem this
+    /// driver can read. One with features it can't write is mounted read-only.
+    pub fn mount(disk: usize) -> Result<Self, FsError> {
+        let mut sb = [0; 128];
+        bcache::read(disk, SUPERBLOCK, &mut sb)?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(FsError::Corrupt);
+        }
+        let log_block_size = u32_at(&sb, 24);
+        let revision = u32_at(&sb, 76);
+        let (inode_size, first_ino, incompat, ro_compat) = if revision == 0 {
+            (OLD_INODE_SIZE, OLD_FIRST_INO, 0, 0)
+        } else {
+            (
+                u16_at(&sb, 88) as u64,
+                u32_at(&sb, 84),
+                u32_at(&sb, 96),
+                u32_at(&sb, 100),
+            )
+        };
+        // a journal to replay, ext4's extents and 64 bit block numbers change the layout
+        if log_block_size > 2
+            || incompat & !INCOMPAT_FILETYPE != 0
+            || inode_size < OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+        {
+            return Err(FsError::Corrupt);
+        }
+        let known = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+        let blocks = u32_at(&sb, 4);
+        let first_data_block = u32_at(&sb, 20);
+        let blocks_per_group = u32_at(&sb, 32);
+        let inodes_per_group = u32_at(&sb, 40);
+        if blocks_per_group == 0 || inodes_per_group == 0 || first_data_block >= blocks {
+            return Err(FsError::Corrupt);
+        }
+        let volume = Volume {
+            disk,
+            block_size: 1024 << log_block_size,
+            inodes: u32_at(&sb, 0),
+            blocks,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_ino,
+            groups: (blocks - first_data_block).div_ceil(blocks_per_group),
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            read_only: ro_compat & !known != 0,
+            lock: Spinlock::new(()),
+        };
+        if !volume.inode(ROOT_INO)?.is_dir() {
+            return Err(FsError::Corrupt);
+        }
+        println!(
+            "ext2: disk {}, {} inodes, {} blocks of {} bytes in {} groups{}",
+            disk,
+            volume.inodes,
+            volume.blocks,
+            volume.block_size,
+            volume.groups,
+            if volume.read_only { ", read-only" } else { "" }
+        );
+        Ok(Ext2(Arc::new(volume)))
+    }
+}
+
+impl Filesystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.0.clone(),
+            ino: ROOT_INO,
+        })
+    }
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(bcache::read(self.disk, offset, buf)?)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(bcache::write(self.disk, offset, buf)?)
+    }
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut word = [0; 4];
+        self.read(offset, &mut word)?;
+        Ok(u32::from_le_bytes(word))
+    }
+    /// Adds `delta` to the 16 bit counter at `offset` of group descriptor `group`, and to the
+    /// 32 bit one of the superblock at `total` if there is one.
+    fn count(
+        &self,
+        group: u32,
+        offset: u64,
+        total: Option<u64>,
+        delta: i32,
+    ) -> Result<(), FsError> {
+        let at = (self.first_data_block as u64 + 1) * self.block_size + group as u64 * DESC_SIZE;
+        let mut counter = [0; 2];
+        self.read(at + offset, &mut counter)?;
+        let value = (u16::from_le_bytes(counter) as i32 + delta) as u16;
+        self.write(at + offset, &value.to_le_bytes())?;
+        if let Some(total) = total {
+            let value = (self.read_u32(SUPERBLOCK + total)? as i64 + delta as i64) as u32;
+            self.write(SUPERBLOCK + total, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+    /// Field `offset` of group descriptor `group`, a block number.
+    fn desc(&self, group: u32, offset: u64) -> Result<u32, FsError> {
+        let at = (self.first_data_block as u64 + 1) * self.block_size + group as u64 * DESC_SIZE;
+        self.read_u32(at + offset)
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.inodes {
+            return Err(FsError::Corrupt);
+        }
+        let (group, index) = (
+            (ino - 1) / self.inodes_per_group,
+            (ino - 1) % self.inodes_per_group,
+        );
+        let table = self.desc(group, 8)? as u64;
+        Ok(table * self.block_size + index as u64 * self.inode_size)
+    }
+    fn inode(&self, ino: u32) -> Result<DiskInode, FsError> {
+        let mut bytes = [0; OLD_INODE_SIZE as usize];
+        self.read(self.inode_offset(ino)?, &mut bytes)?;
+        Ok(DiskInode::parse(ino, &bytes))
+    }
+    fn write_inode(&self, inode: &DiskInode) -> Result<(), FsError> {
+        self.write(self.inode_offset(inode.ino)?, &inode.to_bytes())
+    }
+
+    /// Sets the first clear bit below `limit` of the bitmap block `block`, returns its number.
+    fn alloc_bit(&self, block: u32, limit: u32) -> Result<Option<u32>, FsError> {
+        let mut buf = Vec::new();
+        buf.resize(self.block_size as usize, 0);
+        let offset = block as u64 * self.block_size;
+        self.read(offset, &mut buf)?;
+        let i = match buf.iter().position(|&byte| byte != 0xff) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let bit = buf[i].trailing_ones();
+        let n = i as u32 * 8 + bit;
+        if n >= limit {
+            return Ok(None);
+        }
+        self.write(offset + i as u64, &[buf[i] | 1 << bit])?;
+        Ok(Some(n))
+    }
+    fn free_bit(&self, block: u32, n: u32) -> Result<(), FsError> {
+        let offset = block as u64 * self.block_size + n as u64 / 8;
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        if byte[0] & 1 << (n % 8) == 0 {
+            return Err(FsError::Corrupt);
+        }
+        byte[0] &= !(1 << (n % 8));
+        self.write(offset, &byte)
+    }
+    /// Takes a free inode, from the group `near` is in if it has one.
+    fn alloc_inode(&self, near: u32, dir: bool) -> Result<u32, FsError> {
+        let start = (near - 1) / self.inodes_per_group;
+        for group in (start..self.groups).chain(0..start) {
+            // the free count is 16 bits at offset 14 of the descriptor
+            if self.desc(group, 12)? >> 16 == 0 {
+                continue;
+            }
+            let bitmap = self.desc(group, 4)?;
+            if let Some(bit) = self.alloc_bit(bitmap, self.inodes_per_group)? {
+                let ino = group * self.inodes_per_group + bit + 1;
+                if ino < self.first_ino || ino > self.inodes {
+                    return Err(FsError::Corrupt);
+                }
+                self.count(group, 14, Some(16), -1)?;
+                if dir {
+                    self.count(group, 16, None, 1)?;
+                }
+                return Ok(ino);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+    fn free_inode(&self, ino: u32, dir: bool) -> Result<(), FsError> {
+        let (group, bit) = (
+            (ino - 1) / self.inodes_per_group,
+            (ino - 1) % self.inodes_per_group,
+        );
+        self.free_bit(self.desc(group, 4)?, bit)?;
+        self.count(group, 14, Some(16), 1)?;
+        if dir {
+            self.count(group, 16, None, -1)?;
+        }
+        Ok(())
+    }
+    /// Allocates a block near the inode `near` and clears it, the blocks of holes and
+    /// indirect blocks must read as 0.
+    fn alloc_block(&self, near: u32) -> Result<u32, FsError> {
+        let start = (near - 1) / self.inodes_per_group;
+        for group in (start..self.groups).chain(0..start) {
+            if self.desc(group, 12)? & 0xffff == 0 {
+                continue;
+            }
+            let first = self.first_data_block + group * self.blocks_per_group;
+            let limit = core::cmp::min(self.blocks_per_group, self.blocks - first);
+            if let Some(bit) = self.alloc_bit(self.desc(group, 0)?, limit)? {
+                self.count(group, 12, Some(12), -1)?;
+                let block = first + bit;
+                let mut zeros = Vec::new();
+                zeros.resize(self.block_size as usize, 0);
+                self.write(block as u64 * self.block_size, &zeros)?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+    fn free_block(&self, block: u32) -> Result<(), FsError> {
+        if block < self.first_data_block || block >= self.blocks {
+            return Err(FsError::Corrupt);
+        }
+        let n = block - self.first_data_block;
+        let group = n / self.blocks_per_group;
+        self.free_bit(self.desc(group, 0)?, n % self.blocks_per_group)?;
+        self.count(group, 12, Some(12), 1)
+    }
+
+    /// Entries of an indirect block.
+    fn per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+    /// A new block for `inode`, counted in its sectors.
+    fn alloc_for(&self, inode: &mut DiskInode) -> Result<u32, FsError> {
+        let block = self.alloc_block(inode.ino)?;
+        inode.sectors += (self.block_size / 512) as u32;
+        Ok(block)
+    }
+    /// Returns the block in slot `slot` of `inode`, after allocating one if it is empty and
+    /// `allocate` is set.
+    fn slot(&self, inode: &mut DiskInode, slot: usize, allocate: bool) -> Result<u32, FsError> {
+        if inode.block[slot] == 0 && allocate {
+            inode.block[slot] = self.alloc_for(inode)?;
+        }
+        Ok(inode.block[slot])
+    }
+    /// Like slot, for entry `index` of the indirect block `block`.
+    fn indirect(
+        &self,
+        inode: &mut DiskInode,
+        block: u32,
+        index: u64,
+        allocate: bool,
+    ) -> Result<u32, FsError> {
+        let offset = block as u64 * self.block_size + index * 4;
+        let mut entry = self.read_u32(offset)?;
+        if entry == 0 && allocate {
+            entry = self.alloc_for(inode)?;
+            self.write(offset, &entry.to_le_bytes())?;
+        }
+        Ok(entry)
+    }
+    /// The block holding block `index` of the file, 0 for a hole unless `allocate` is set.
+    /// Allocating changes `inode`, the caller writes it back.
+    fn bmap(&self, inode: &mut DiskInode, index: u64, allocate: bool) -> Result<u32, FsError> {
+        if index < DIRECT as u64 {
+            return self.slot(inode, index as usize, allocate);
+        }
+        let per = self.per_block();
+        let mut index = index - DIRECT as u64;
+        // blocks reached through the indirect slot, then the double and triple indirect ones
+        let mut span = per;
+        for slot in DIRECT..BLOCK_SLOTS {
+            if index < span {
+                let mut block = self.slot(inode, slot, allocate)?;
+                while block != 0 && span > 1 {
+                    span /= per;
+                    block = self.indirect(inode, block, index / span, allocate)?;
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per;
+        }
+        Err(FsError::TooBig)
+    }
+    fn max_size(&self) -> u64 {
+        let per = self.per_block();
+        let blocks = DIRECT as u64 + per + per * per + per * per * per;
+        // files over 4 GiB need the large file feature, the disks are far smaller anyway
+        core::cmp::min(blocks * self.block_size, u32::MAX as u64)
+    }
+
+    fn read_data(&self, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, inode.size - offset) as usize;
+        let mut inode = *inode;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = position % self.block_size;
+            let chunk = core::cmp::min(len - done, (self.block_size - start) as usize);
+            let block = self.bmap(&mut inode, position / self.block_size, false)?;
+            let buf = &mut buf[done..done + chunk];
+            if block == 0 {
+                buf.fill(0);
+            } else {
+                self.read(block as u64 * self.block_size + start, buf)?;
+            }
+            done += chunk;
+        }
+        // the next block of the file need not follow this one on the disk
+        let next = (offset + len as u64).div_ceil(self.block_size);
+        if next * self.block_size < inode.size {
+            let block = self.bmap(&mut inode, next, false)?;
+            if block != 0 {
+                let block = block as u64 * self.block_size / bcache::BLOCK_SIZE as u64;
+                bcache::read_ahead(self.disk, block);
+            }
+        }
+        Ok(len)
+    }
+    /// Writes at `offset`, allocating the blocks it needs, and stores the inode.
+    fn write_data(&self, inode: &mut DiskInode, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::TooBig)?;
+        if end > self.max_size() {
+            return Err(FsError::TooBig);
+        }
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = position % self.block_size;
+            let chunk = core::cmp::min(buf.len() - done, (self.block_size - start) as usize);
+            let written = self
+                .bmap(inode, position / self.block_size, true)
+                .and_then(|block| {
+                    self.write(
+                        block as u64 * self.block_size + start,
+                        &buf[done..done + chunk],
+                    )
+                });
+            if let Err(e) = written {
+                result = Err(e);
+                break;
+            }
+            done += chunk;
+        }
+        // what was written before running out of space is kept
+        inode.size = core::cmp::max(inode.size, offset + done as u64);
+        self.write_inode(inode)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+    fn free_indirect(&self, block: u32, depth: u32) -> Result<(), FsError> {
+        for i in 0..self.per_block() {
+            let entry = self.read_u32(block as u64 * self.block_size + i * 4)?;
+            if entry == 0 {
+                continue;
+            }
+            if depth > 1 {
+                self.free_indirect(entry, depth - 1)?;
+            } else {
+                self.free_block(entry)?;
+            }
+        }
+        self.free_block(block)
+    }
+    /// Frees every block of the file, extended attributes included, and empties it.
+    fn free_data(&self, inode: &mut DiskInode) -> Result<(), FsError> {
+        if !inode.is_fast_symlink(self.block_size) {
+            for &block in inode.block[..DIRECT].iter().filter(|&&b| b != 0) {
+                self.free_block(block)?;
+            }
+            for (depth, slot) in (DIRECT..BLOCK_SLOTS).enumerate() {
+                if inode.block[slot] != 0 {
+                    self.free_indirect(inode.block[slot], depth as u32 + 1)?;
+                }
+            }
+        }
+        if inode.file_acl != 0 {
+            // the block may be shared by inodes with the same attributes, the second word
+            // counts them
+            let refs = inode.file_acl as u64 * self.block_size + 4;
+            match self.read_u32(refs)? {
+                0 | 1 => self.free_block(inode.file_acl)?,
+                n => self.write(refs, &(n - 1).to_le_bytes())?,
+            }
+            inode.file_acl = 0;
+        }
+        inode.block = [0; BLOCK_SLOTS];
+        inode.sectors = 0;
+        inode.size = 0;
+        Ok(())
+    }
+
+    /// Calls `f` with the offset, inode number and name of the used entries of the directory
+    /// `dir` until it returns Some.
+    fn scan<R>(
+        &self,
+        dir: &DiskInode,
+        mut f: impl FnMut(u64, u32, &[u8]) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut block = Vec::new();
+        block.resize(self.block_size as usize, 0);
+        let mut start = 0;
+        while start < dir.size {
+            self.read_data(dir, start, &mut block)?;
+            let mut at = 0;
+            while at < block.len() {
+                let ino = u32_at(&block, at);
+                let rec_len = u16_at(&block, at + 4) as usize;
+                let name_len = block[at + 6] as usize;
+                if rec_len < entry_len(0) || at + rec_len > block.len() || 8 + name_len > rec_len {
+                    return Err(FsError::Corrupt);
+                }
+                if ino != 0 {
+                    if let Some(res) = f(start + at as u64, ino, &block[at + 8..at + 8 + name_len])
+                    {
+                        return Ok(Some(res));
+                    }
+                }
+                at += rec_len;
+            }
+            start += self.block_size;
+        }
+        Ok(None)
+    }
+    fn find(&self, dir: &DiskInode, name: &str) -> Result<Option<(u64, u32)>, FsError> {
+        self.scan(dir, |offset, ino, entry| {
+            (entry == name.as_bytes()).then_some((offset, ino))
+        })
+    }
+    /// Adds an entry to the directory `dir`, in the spare room of a block or in a new block at
+    /// its end.
+    fn add_entry(
+        &self,
+        dir: &mut DiskInode,
+        name: &str,
+        ino: u32,
+        mode: u32,
+    ) -> Result<(), FsError> {
+        let needed = entry_len(name.len());
+        let mut block = Vec::new();
+        block.resize(self.block_size as usize, 0);
+        let mut start = 0;
+        // where the entry goes, with the record it is carved from
+        let mut found = None;
+        'blocks: while start < dir.size {
+            self.read_data(dir, start, &mut block)?;
+            let mut at = 0;
+            while at < block.len() {
+                let used = match u32_at(&block, at) {
+                    0 => 0,
+                    _ => entry_len(block[at + 6] as usize),
+                };
+                let rec_len = u16_at(&block, at + 4) as usize;
+                if rec_len < entry_len(0) || at + rec_len > block.len() {
+                    return Err(FsError::Corrupt);
+                }
+                if rec_len - used >= needed {
+                    found = Some((at, used, rec_len));
+                    break 'blocks;
+                }
+                at += rec_len;
+            }
+            start += self.block_size;
+        }
+        let (at, used, rec_len) = match found {
+            Some(found) => found,
+            None => {
+                // a new block is one unused record, and the size grows by a block
+                block.fill(0);
+                block[4..6].copy_from_slice(&(self.block_size as u16).to_le_bytes());
+                (0, 0, self.block_size as usize)
+            }
+        };
+        if used != 0 {
+            block[at + 4..at + 6].copy_from_slice(&(used as u16).to_le_bytes());
+        }
+        let entry = &mut block[at + used..at + rec_len];
+        entry[..4].copy_from_slice(&ino.to_le_bytes());
+        entry[4..6].copy_from_slice(&((rec_len - used) as u16).to_le_bytes());
+        entry[6] = name.len() as u8;
+        entry[7] = if self.filetype { file_type(mode) } else { 0 };
+        entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        // the hash index doesn't know the entry, without the flag the directory is read as a
+        // list again
+        dir.flags &= !INDEX_FL;
+        self.write_data(dir, start, &block).map(|_| ())
+    }
+    /// Removes the entry at `offset` of the directory `dir`, merging it into the entry before
+    /// it in its block.
+    fn remove_entry(&self, dir: &mut DiskInode, offset: u64) -> Result<(), FsError> {
+        let start = offset - offset % self.block_size;
+        let mut block = Vec::new();
+        block.resize(self.block_size as usize, 0);
+        self.read_data(dir, start, &mut block)?;
+        let target = (offset - start) as usize;
+        let mut at = 0;
+        let mut previous = None;
+        while at < target {
+            let rec_len = u16_at(&block, at + 4) as usize;
+            if rec_len < entry_len(0) {
+                return Err(FsError::Corrupt);
+            }
+            previous = Some(at);
+            at += rec_len;
+        }
+        if at != target {
+            return Err(FsError::Corrupt);
+        }
+        match previous {
+            Some(previous) => {
+                let merged = u16_at(&block, previous + 4) + u16_at(&block, at + 4);
+                block[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+            }
+            None => block[at..at + 4].fill(0),
+        }
+        dir.flags &= !INDEX_FL;
+        self.write_data(dir, start, &block).map(|_| ())
+    }
+}
+
+/// An inode of a mounted filesystem, read from the disk whenever it is used.
+struct Node {
+    volume: Arc<Volume>,
+    ino: u32,
+}
+
+impl Node {
+    fn dir(&self) -> Result<DiskInode, FsError> {
+        let dir = self.volume.inode(self.ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        Ok(dir)
+    }
+    fn node(&self, ino: u32) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.volume.clone(),
+            ino,
+        })
+    }
+    /// Adds a new inode with `mode` as the entry `name`, a symbolic link to `target` if one
+    /// is given.
+    fn make(&self, name: &str, mode: u32, target: Option<&str>) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let mut dir = self.dir()?;
+        if volume.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        if volume.find(&dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = volume.alloc_inode(self.ino, is_dir)?;
+        // the whole inode is cleared, the extra fields of large inodes included. There is no
+        // real-time clock to take the times from.
+        let mut zeros = Vec::new();
+        zeros.resize(volume.inode_size as usize, 0);
+        volume.write(volume.inode_offset(ino)?, &zeros)?;
+        let mut inode = DiskInode::parse(ino, &[0; OLD_INODE_SIZE as usize]);
+        inode.mode = mode as u16;
+        inode.links = 1;
+        let created = if is_dir {
+            // "." and ".." count as links of the directory and of its parent
+            inode.links = 2;
+            volume
+                .add_entry(&mut inode, ".", ino, mode)
+                .and_then(|_| volume.add_entry(&mut inode, "..", self.ino, S_IFDIR))
+        } else {
+            match target {
+                Some(target) if target.len() < FAST_SYMLINK_MAX => {
+                    let mut raw = inode.to_bytes();
+                    raw[40..40 + target.len()].copy_from_slice(target.as_bytes());
+                    inode = DiskInode::parse(ino, &raw);
+                    inode.size = target.len() as u64;
+                    volume.write_inode(&inode)
+                }
+                Some(target) => volume
+                    .write_data(&mut inode, 0, target.as_bytes())
+                    .map(|_| ()),
+                None => volume.write_inode(&inode),
+            }
+        };
+        let added = created.and_then(|_| volume.add_entry(&mut dir, name, ino, mode));
+        if let Err(e) = added {
+            volume.free_data(&mut inode)?;
+            volume.write(volume.inode_offset(ino)?, &zeros)?;
+            volume.free_inode(ino, is_dir)?;
+            return Err(e);
+        }
+        if is_dir {
+            dir.links += 1;
+            volume.write_inode(&dir)?;
+        }
+        Ok(self.node(ino))
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.inode(self.ino)?;
+        Ok(Stat {
+            ino: self.ino as u64,
+            mode: inode.mode as u32,
+            nlink: inode.links as u32,
+            size: inode.size,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.inode(self.ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        if inode.is_fast_symlink(self.volume.block_size) {
+            return Err(FsError::Invalid);
+        }
+        self.volume.read_data(&inode, offset, buf)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let mut inode = self.volume.inode(self.ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        if inode.is_symlink() {
+            return Err(FsError::Invalid);
+        }
+        self.volume.write_data(&mut inode, offset, buf)
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        match self.volume.find(&self.dir()?, name)? {
+            Some((_, ino)) => Ok(self.node(ino)),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, name: &str, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        self.make(name, mode, None)
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        if volume.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut dir = self.dir()?;
+        let (offset, ino) = volume.find(&dir, name)?.ok_or(FsError::NotFound)?;
+        let mut inode = volume.inode(ino)?;
+        if inode.is_dir() {
+            let other = volume.scan(&inode, |_, _, entry| {
+                (entry != b"." && entry != b"..").then_some(())
+            })?;
+            if other.is_some() {
+                return Err(FsError::NotEmpty);
+            }
+            inode.links = 0;
+            dir.links = dir.links.saturating_sub(1);
+        } else {
+            inode.links = inode.links.saturating_sub(1);
+        }
+        // stores the directory's inode as well
+        volume.remove_entry(&mut dir, offset)?;
+        if inode.links == 0 {
+            let is_dir = inode.is_dir();
+            volume.free_data(&mut inode)?;
+            // a deletion time marks the inode free for fsck. Without a clock it is the time the
+            // filesystem was last written on the host, times below the inode count would be
+            // taken for the links of a list of orphans.
+            let written = volume.read_u32(SUPERBLOCK + 48)?;
+            let dtime = core::cmp::max(written, volume.inodes);
+            inode.raw[20..24].copy_from_slice(&dtime.to_le_bytes());
+            volume.free_inode(ino, is_dir)?;
+        }
+        volume.write_inode(&inode)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let mut count = 0;
+        let found = self.volume.scan(&self.dir()?, |_, ino, name| {
+            count += 1;
+            (count > index).then(|| (ino, String::from_utf8_lossy(name).into_owned()))
+        })?;
+        match found {
+            Some((ino, name)) => Ok(Some(DirEntry {
+                ino: ino as u64,
+                mode: self.volume.inode(ino)?.mode as u32 & S_IFMT,
+                name,
+            })),
+            None => Ok(None),
+        }
+    }
+    fn readlink(&self) -> Result<String, FsError> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.inode(self.ino)?;
+        if !inode.is_symlink() {
+            return Err(FsError::Invalid);
+        }
+        let mut target = Vec::new();
+        if inode.is_fast_symlink(self.volume.block_size) {
+            let len = core::cmp::min(inode.size as usize, FAST_SYMLINK_MAX);
+            target.extend_from_slice(&inode.raw[40..40 + len]);
+        } else {
+            target.resize(
+                core::cmp::min(inode.size, self.volume.block_size) as usize,
+                0,
+            );
+            let read = self.volume.read_data(&inode, 0, &mut target)?;
+            target.truncate(read);
+        }
+        String::from_utf8(target).map_err(|_| FsError::Corrupt)
+    }
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.make(name, S_IFLNK | 0o777, Some(target))
+    }
+}
@@ -0,0 +1,859 @@
+extern crate alloc;
+use crate::bcache;
+use crate::lock::Spinlock;
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, NAME_MAX, S_IFDIR, S_IFMT, S_IFREG};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+const BOOT_SIGNATURE: u16 = 0xaa55;
+const ENTRY_SIZE: u64 = 32;
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+// attributes of a directory entry
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// first byte of the name
+const END_OF_DIR: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+// stands for a first byte of 0xe5, which marks deleted entries
+const KANJI_E5: u8 = 0x05;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+// the UCS-2 characters of a long name entry, by their offset
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// case of a short name, as Windows and Linux store it instead of a long name
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+// 1980-01-01, the earliest date there is, as there is no real-time clock
+const DATE: u16 = 0x21;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Kind {
+    Fat16,
+    Fat32,
+}
+
+/// A FAT16 or FAT32 filesystem with long file names, as `mkfs.vfat` creates it.
+pub struct Fat(Arc<Volume>);
+
+struct Volume {
+    disk: usize,
+    kind: Kind,
+    cluster_size: u64,
+    // data clusters are numbered from 2 on
+    clusters: u32,
+    fat_start: u64,
+    fat_size: u64,
+    fats: u64,
+    // the root directory is an area of its own on FAT16, a cluster chain on FAT32
+    root_start: u64,
+    root_entries: u64,
+    root_cluster: u32,
+    data_start: u64,
+    fsinfo: Option<u64>,
+    fsinfo_stale: AtomicBool,
+    // where the search for a free cluster starts
+    next_free: AtomicU32,
+    // allocation and directory updates span several blocks
+    lock: Spinlock<()>,
+}
+
+#[derive(Copy, Clone)]
+enum Dir {
+    // the FAT16 root directory
+    Fixed,
+    Chain(u32),
+}
+
+/// A used directory entry with the entries of its long name.
+struct Found {
+    // offsets of the long name entries and, last, of the short entry
+    slots: Vec<u64>,
+    short: [u8; ENTRY_SIZE as usize],
+    name: String,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn first_cluster(entry: &[u8]) -> u32 {
+    (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32
+}
+
+fn checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The 8.3 name of a short entry as "NAME.EXT", in lower case where the entry says so.
+fn short_name(entry: &[u8]) -> String {
+    let mut name = Vec::with_capacity(12);
+    let (base, ext) = (&entry[..8], &entry[8..11]);
+    let trimmed = |part: &[u8]| part.len() - part.iter().rev().take_while(|&&c| c == b' ').count();
+    name.extend_from_slice(&base[..trimmed(base)]);
+    if name.first() == Some(&KANJI_E5) {
+        name[0] = DELETED;
+    }
+    if entry[12] & LOWER_BASE != 0 {
+        name.make_ascii_lowercase();
+    }
+    if trimmed(ext) > 0 {
+        name.push(b'.');
+        let start = name.len();
+        name.extend_from_slice(&ext[..trimmed(ext)]);
+        if entry[12] & LOWER_EXT != 0 {
+            name[start..].make_ascii_lowercase();
+        }
+    }
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+/// Characters short names may contain besides letters and digits.
+fn short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// The short entry name of `name` if it is a valid upper case 8.3 name, which needs no long
+/// name.
+fn exact_short(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max: usize| part.len() <= max && part.chars().all(short_char);
+    if base.is_empty() || !valid(base, 8) || !valid(ext, 3) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// A short name "BASIS~N.EXT" for `name` that is not among `taken`.
+fn short_alias(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FsError> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let clean = |part: &str, max: usize| {
+        let mut res = Vec::with_capacity(max);
+        for c in part.chars().filter(|&c| c != ' ' && c != '.') {
+            let c = c.to_ascii_uppercase();
+            if res.len() == max {
+                break;
+            }
+            res.push(if short_char(c) { c as u8 } else { b'_' });
+        }
+        res
+    };
+    let (basis, ext) = (clean(base, 8), clean(ext, 3));
+    let mut short = [b' '; 11];
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1..1_000_000 {
+        let mut tail = [0; 7];
+        let mut len = 0;
+        let mut rest = n;
+        while rest > 0 {
+            tail[6 - len] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+            len += 1;
+        }
+        tail[6 - len] = b'~';
+        let tail = &tail[6 - len..];
+        let keep = core::cmp::min(basis.len(), 8 - tail.len());
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+impl Fat {
+    /// Reads the boot sector of `disk`, fails with Corrupt if it isn't a FAT16 or FAT32
+    /// filesystem.
+    pub fn mount(disk: usize) -> Result<Self, FsError> {
+        let mut bpb = [0; 512];
+        bcache::read(disk, 0, &mut bpb)?;
+        let sector = u16_at(&bpb, 11) as u64;
+        let per_cluster = bpb[13] as u64;
+        let reserved = u16_at(&bpb, 14) as u64;
+        let fats = bpb[16] as u64;
+        let root_entries = u16_at(&bpb, 17) as u64;
+        let total = match u16_at(&bpb, 19) {
+            0 => u32_at(&bpb, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match u16_at(&bpb, 22) {
+            0 => u32_at(&bpb, 36) as u64,
+            size => size as u64,
+        };
+        if u16_at(&bpb, 510) != BOOT_SIGNATURE
+            || !matches!(sector, 512 | 1024 | 2048 | 4096)
+            || !per_cluster.is_power_of_two()
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Corrupt);
+        }
+        let root_sectors = (root_entries * ENTRY_SIZE).div_ceil(sector);
+        let data = reserved + fats * fat_sectors + root_sectors;
+        let clusters = total.checked_sub(data).ok_or(FsError::Corrupt)? / per_cluster;
+        // the number of clusters alone decides the type, FAT12 is not supported
+        let kind = match clusters {
+            0..=4084 => return Err(FsError::Corrupt),
+            4085..=65524 => Kind::Fat16,
+            _ => Kind::Fat32,
+        };
+        let fsinfo = match (kind, u16_at(&bpb, 48)) {
+            (Kind::Fat32, sector_number) if sector_number != 0 && sector_number != 0xffff => {
+                Some(sector_number as u64 * sector)
+            }
+            _ => None,
+        };
+        let volume = Volume {
+            disk,
+            kind,
+            cluster_size: sector * per_cluster,
+            clusters: clusters as u32,
+            fat_start: reserved * sector,
+            fat_size: fat_sectors * sector,
+            fats,
+            root_start: (reserved + fats * fat_sectors) * sector,
+            root_entries,
+            root_cluster: if kind == Kind::Fat32 {
+                u32_at(&bpb, 44)
+            } else {
+                0
+            },
+            data_start: data * sector,
+            fsinfo,
+            fsinfo_stale: AtomicBool::new(false),
+            next_free: AtomicU32::new(2),
+            lock: Spinlock::new(()),
+        };
+        println!(
+            "fat: disk {}, FAT{}, {} clusters of {} bytes",
+            disk,
+            if kind == Kind::Fat32 { 32 } else { 16 },
+            clusters,
+            volume.cluster_size
+        );
+        Ok(Fat(Arc::new(volume)))
+    }
+}
+
+impl Filesystem for Fat {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.0.clone(),
+            entry: None,
+        })
+    }
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(bcache::read(self.disk, offset, buf)?)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        Ok(bcache::write(self.disk, offset, buf)?)
+    }
+    fn entry(&self, offset: u64) -> Result<[u8; ENTRY_SIZE as usize], FsError> {
+        let mut entry = [0; ENTRY_SIZE as usize];
+        self.read(offset, &mut entry)?;
+        Ok(entry)
+    }
+    fn root(&self) -> Dir {
+        match self.kind {
+            Kind::Fat16 => Dir::Fixed,
+            Kind::Fat32 => Dir::Chain(self.root_cluster),
+        }
+    }
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            Kind::Fat16 => 0xffff,
+            Kind::Fat32 => 0x0fff_ffff,
+        }
+    }
+    fn fat_entry(&self, cluster: u32) -> u64 {
+        match self.kind {
+            Kind::Fat16 => cluster as u64 * 2,
+            Kind::Fat32 => cluster as u64 * 4,
+        }
+    }
+    fn get(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_start + self.fat_entry(cluster);
+        match self.kind {
+            Kind::Fat16 => {
+                let mut entry = [0; 2];
+                self.read(offset, &mut entry)?;
+                Ok(u16::from_le_bytes(entry) as u32)
+            }
+            Kind::Fat32 => {
+                let mut entry = [0; 4];
+                self.read(offset, &mut entry)?;
+                Ok(u32::from_le_bytes(entry) & 0x0fff_ffff)
+            }
+        }
+    }
+    /// Sets the entry of `cluster` in every copy of the FAT.
+    fn set(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for fat in 0..self.fats {
+            let offset = self.fat_start + fat * self.fat_size + self.fat_entry(cluster);
+            match self.kind {
+                Kind::Fat16 => self.write(offset, &(value as u16).to_le_bytes())?,
+                Kind::Fat32 => {
+                    // the top four bits are reserved and kept
+                    let mut entry = [0; 4];
+                    self.read(offset, &mut entry)?;
+                    let value = u32::from_le_bytes(entry) & 0xf000_0000 | value;
+                    self.write(offset, &value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// The cluster after `cluster` in its chain, None at the end.
+    fn next(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let next = self.get(cluster)?;
+        if next >= self.end_of_chain() & !7 {
+            return Ok(None);
+        }
+        if next < 2 || next >= self.clusters + 2 {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Some(next))
+    }
+    /// Takes a free cluster and clears it, appending it to the chain ending with `last`.
+    fn alloc_cluster(&self, last: Option<u32>) -> Result<u32, FsError> {
+        let start = self.next_free.load(Ordering::Relaxed);
+        let end = self.clusters + 2;
+        let mut found = None;
+        for cluster in (start..end).chain(2..start) {
+            if self.get(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+        self.next_free.store(cluster + 1, Ordering::Relaxed);
+        self.set(cluster, self.end_of_chain())?;
+        if let Some(last) = last {
+            self.set(last, cluster)?;
+        }
+        self.invalidate_fsinfo()?;
+        // a block at a time, a cluster of up to 64 KiB is too much for the kernel heap
+        let zeros = [0; bcache::BLOCK_SIZE];
+        let start = self.cluster_offset(cluster);
+        let mut done = 0;
+        while done < self.cluster_size {
+            let len = core::cmp::min(self.cluster_size - done, zeros.len() as u64);
+            self.write(start + done, &zeros[..len as usize])?;
+            done += len;
+        }
+        Ok(cluster)
+    }
+    fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        let mut cluster = Some(first).filter(|&c| c != 0);
+        // a looping chain ends once every cluster has been visited
+        for _ in 0..self.clusters {
+            let current = match cluster {
+                Some(current) => current,
+                None => break,
+            };
+            cluster = self.next(current)?;
+            self.set(current, 0)?;
+        }
+        self.next_free.fetch_min(first.max(2), Ordering::Relaxed);
+        self.invalidate_fsinfo()
+    }
+    /// The free cluster count of FAT32's FSInfo sector is only a hint, it is marked unknown
+    /// rather than kept up to date.
+    fn invalidate_fsinfo(&self) -> Result<(), FsError> {
+        let sector = match self.fsinfo {
+            Some(sector) if !self.fsinfo_stale.swap(true, Ordering::Relaxed) => sector,
+            _ => return Ok(()),
+        };
+        let mut lead = [0; 4];
+        self.read(sector, &mut lead)?;
+        if u32::from_le_bytes(lead) == FSINFO_LEAD {
+            self.write(sector + 488, &FSINFO_UNKNOWN.to_le_bytes())?;
+            self.write(sector + 492, &FSINFO_UNKNOWN.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the offset and contents of the entries of `dir` until it returns Some.
+    fn slots<R>(
+        &self,
+        dir: Dir,
+        mut f: impl FnMut(u64, &[u8; ENTRY_SIZE as usize]) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        match dir {
+            Dir::Fixed => {
+                for i in 0..self.root_entries {
+                    let offset = self.root_start + i * ENTRY_SIZE;
+                    if let Some(res) = f(offset, &self.entry(offset)?) {
+                        return Ok(Some(res));
+                    }
+                }
+            }
+            Dir::Chain(first) => {
+                let mut cluster = Some(first).filter(|&c| c != 0);
+                for _ in 0..self.clusters {
+                    let current = match cluster {
+                        Some(current) => current,
+                        None => break,
+                    };
+                    let start = self.cluster_offset(current);
+                    for offset in (start..start + self.cluster_size).step_by(ENTRY_SIZE as usize) {
+                        if let Some(res) = f(offset, &self.entry(offset)?) {
+                            return Ok(Some(res));
+                        }
+                    }
+                    cluster = self.next(current)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Like slots, for the used entries of `dir` with their long names put together.
+    fn entries<R>(
+        &self,
+        dir: Dir,
+        mut f: impl FnMut(Found) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut long: Vec<u16> = Vec::new();
+        let mut slots = Vec::new();
+        // of the long name being put together
+        let mut sum = 0;
+        let mut expected = 0;
+        let found = self.slots(dir, |offset, entry| {
+            if entry[0] == END_OF_DIR {
+                return Some(None);
+            }
+            if entry[0] == DELETED {
+                expected = 0;
+                long.clear();
+                return None;
+            }
+            if entry[11] & 0x3f == ATTR_LONG_NAME {
+                let order = entry[0] & 0x1f;
+                if entry[0] & LAST_LONG_ENTRY != 0 {
+                    long.clear();
+                    long.resize(order as usize * LONG_NAME_CHARS, 0xffff);
+                    slots.clear();
+                    sum = entry[13];
+                    expected = order;
+                }
+                if order == 0 || order != expected || entry[13] != sum {
+                    expected = 0;
+                    long.clear();
+                    return None;
+                }
+                let start = (order as usize - 1) * LONG_NAME_CHARS;
+                for (i, &at) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    long[start + i] = u16_at(entry, at);
+                }
+                slots.push(offset);
+                expected -= 1;
+                return None;
+            }
+            let complete = expected == 0 && !long.is_empty() && checksum(entry) == sum;
+            if entry[11] & ATTR_VOLUME_ID != 0 {
+                long.clear();
+                return None;
+            }
+            let name = if complete {
+                let end = long.iter().position(|&c| c == 0).unwrap_or(long.len());
+                char::decode_utf16(long[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            } else {
+                slots.clear();
+                short_name(entry)
+            };
+            long.clear();
+            expected = 0;
+            slots.push(offset);
+            f(Found {
+                slots: core::mem::take(&mut slots),
+                short: *entry,
+                name,
+            })
+            .map(Some)
+        })?;
+        Ok(found.flatten())
+    }
+    fn find(&self, dir: Dir, name: &str) -> Result<Option<Found>, FsError> {
+        // names are compared without regard to case, like the short names stored in upper case
+        self.entries(dir, |found| {
+            let matches = found.name.eq_ignore_ascii_case(name)
+                || short_name(&found.short).eq_ignore_ascii_case(name);
+            matches.then_some(found)
+        })
+    }
+    /// Offsets of `count` consecutive unused entries of `dir`, which grows if needed.
+    fn free_slots(&self, dir: Dir, count: usize) -> Result<Vec<u64>, FsError> {
+        let mut run = Vec::with_capacity(count);
+        let mut last = None;
+        self.slots(dir, |offset, entry| {
+            if entry[0] == END_OF_DIR || entry[0] == DELETED {
+                run.push(offset);
+            } else {
+                run.clear();
+            }
+            last = Some(offset);
+            (run.len() == count).then_some(())
+        })?;
+        if run.len() == count {
+            return Ok(run);
+        }
+        let mut cluster = match (dir, last) {
+            (Dir::Chain(_), Some(last)) => {
+                ((last - self.data_start) / self.cluster_size) as u32 + 2
+            }
+            _ => return Err(FsError::NoSpace),
+        };
+        while run.len() < count {
+            cluster = self.alloc_cluster(Some(cluster))?;
+            let start = self.cluster_offset(cluster);
+            let new = (start..start + self.cluster_size).step_by(ENTRY_SIZE as usize);
+            run.extend(new.take(count - run.len()));
+        }
+        Ok(run)
+    }
+    /// Reads from `offset` on of the file starting at cluster `first`, up to its `size`.
+    fn read_data(
+        &self,
+        first: u32,
+        size: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut cluster = self.seek(first, offset / self.cluster_size)?;
+        let mut done = 0;
+        while done < len {
+            let current = cluster.ok_or(FsError::Corrupt)?;
+            let start = (offset + done as u64) % self.cluster_size;
+            let chunk = core::cmp::min(len - done, (self.cluster_size - start) as usize);
+            self.read(
+                self.cluster_offset(current) + start,
+                &mut buf[done..done + chunk],
+            )?;
+            done += chunk;
+            if done < len {
+                cluster = self.next(current)?;
+            }
+        }
+        Ok(len)
+    }
+    /// The cluster `index` clusters into the chain starting at `first`, None past its end.
+    fn seek(&self, first: u32, index: u64) -> Result<Option<u32>, FsError> {
+        let mut cluster = Some(first).filter(|&c| c != 0);
+        for _ in 0..index {
+            cluster = match cluster {
+                Some(current) => self.next(current)?,
+                None => break,
+            };
+        }
+        Ok(cluster)
+    }
+}
+
+/// A file or directory, known by its directory entry.
+struct Node {
+    volume: Arc<Volume>,
+    // offset of the short entry, None for the root directory
+    entry: Option<u64>,
+}
+
+impl Node {
+    fn dir(&self) -> Result<Dir, FsError> {
+        let offset = match self.entry {
+            Some(offset) => offset,
+            None => return Ok(self.volume.root()),
+        };
+        let entry = self.volume.entry(offset)?;
+        if entry[11] & ATTR_DIRECTORY == 0 {
+            return Err(FsError::NotDir);
+        }
+        // ".." of a directory in the root is cluster 0
+        Ok(match first_cluster(&entry) {
+            0 => self.volume.root(),
+            cluster => Dir::Chain(cluster),
+        })
+    }
+    fn node(&self, found: &Found) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.volume.clone(),
+            entry: found.slots.last().copied(),
+        })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let _guard = self.volume.lock.lock();
+        let offset = match self.entry {
+            Some(offset) => offset,
+            None => {
+                return Ok(Stat {
+                    ino: 1,
+                    mode: S_IFDIR | 0o755,
+                    nlink: 1,
+                    size: 0,
+                })
+            }
+        };
+        let entry = self.volume.entry(offset)?;
+        let attr = entry[11];
+        // any file may be a program, like with the default umask of Linux
+        let mode = if attr & ATTR_DIRECTORY != 0 {
+            S_IFDIR | 0o755
+        } else if attr & ATTR_READ_ONLY != 0 {
+            S_IFREG | 0o555
+        } else {
+            S_IFREG | 0o755
+        };
+        Ok(Stat {
+            ino: offset / ENTRY_SIZE,
+            mode,
+            nlink: 1,
+            size: u32_at(&entry, 28) as u64,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let entry = match self.entry {
+            Some(at) => self.volume.entry(at)?,
+            None => return Err(FsError::IsDir),
+        };
+        if entry[11] & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDir);
+        }
+        let size = u32_at(&entry, 28) as u64;
+        self.volume
+            .read_data(first_cluster(&entry), size, offset, buf)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let at = self.entry.ok_or(FsError::IsDir)?;
+        let mut entry = volume.entry(at)?;
+        if entry[11] & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDir);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::TooBig)?;
+        if end > u32::MAX as u64 {
+            return Err(FsError::TooBig);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut first = first_cluster(&entry);
+        let mut done = 0;
+        let mut written = || -> Result<(), FsError> {
+            if first == 0 {
+                first = volume.alloc_cluster(None)?;
+            }
+            // the chain is extended up to the offset, the clusters of a gap are cleared
+            let mut cluster = first;
+            for _ in 0..offset / volume.cluster_size {
+                cluster = match volume.next(cluster)? {
+                    Some(next) => next,
+                    None => volume.alloc_cluster(Some(cluster))?,
+                };
+            }
+            loop {
+                let start = (offset + done as u64) % volume.cluster_size;
+                let chunk =
+                    core::cmp::min(buf.len() - done, (volume.cluster_size - start) as usize);
+                volume.write(
+                    volume.cluster_offset(cluster) + start,
+                    &buf[done..done + chunk],
+                )?;
+                done += chunk;
+                if done == buf.len() {
+                    return Ok(());
+                }
+                cluster = match volume.next(cluster)? {
+                    Some(next) => next,
+                    None => volume.alloc_cluster(Some(cluster))?,
+                };
+            }
+        };
+        let result = written();
+        // what was written before running out of space is kept
+        let size = core::cmp::max(u32_at(&entry, 28), (offset + done as u64) as u32);
+        entry[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry[11] |= ATTR_ARCHIVE;
+        volume.write(at, &entry)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        match self.volume.find(self.dir()?, name)? {
+            Some(found) => Ok(self.node(&found)),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, name: &str, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let dir = self.dir()?;
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+            return Err(FsError::Invalid);
+        }
+        if volume.find(dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+        let (short, long) = match exact_short(name) {
+            Some(short) => (short, Vec::new()),
+            None => {
+                let mut taken = Vec::new();
+                volume.entries(dir, |found| {
+                    let mut short = [0; 11];
+                    short.copy_from_slice(&found.short[..11]);
+                    taken.push(short);
+                    None::<()>
+                })?;
+                (short_alias(name, &taken)?, name.encode_utf16().collect())
+            }
+        };
+        let long_entries = long.len().div_ceil(LONG_NAME_CHARS);
+        let slots = volume.free_slots(dir, long_entries + 1)?;
+
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let cluster = if is_dir {
+            volume.alloc_cluster(None)?
+        } else {
+            0
+        };
+        let mut entry = [0; ENTRY_SIZE as usize];
+        entry[..11].copy_from_slice(&short);
+        entry[11] = if is_dir { ATTR_DIRECTORY } else { ATTR_ARCHIVE };
+        entry[16..18].copy_from_slice(&DATE.to_le_bytes());
+        entry[18..20].copy_from_slice(&DATE.to_le_bytes());
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        if is_dir {
+            // "." and "..", the latter is cluster 0 for the root
+            let parent = match dir {
+                Dir::Chain(parent) if parent != volume.root_cluster => parent,
+                _ => 0,
+            };
+            let mut dots = entry;
+            let start = volume.cluster_offset(cluster);
+            dots[..11].copy_from_slice(b".          ");
+            volume.write(start, &dots)?;
+            dots[..11].copy_from_slice(b"..         ");
+            dots[20..22].copy_from_slice(&((parent >> 16) as u16).to_le_bytes());
+            dots[26..28].copy_from_slice(&(parent as u16).to_le_bytes());
+            volume.write(start + ENTRY_SIZE, &dots)?;
+        }
+
+        // the long name entries come first, the one with the end of the name leading
+        let sum = checksum(&short);
+        for (i, &offset) in slots[..long_entries].iter().enumerate() {
+            let order = long_entries - i;
+            let mut long_entry = [0; ENTRY_SIZE as usize];
+            long_entry[0] = order as u8 | if i == 0 { LAST_LONG_ENTRY } else { 0 };
+            long_entry[11] = ATTR_LONG_NAME;
+            long_entry[13] = sum;
+            for (j, &at) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let index = (order - 1) * LONG_NAME_CHARS + j;
+                // the name ends with a NUL if there is room, then 0xffff pads it
+                let c = match index.cmp(&long.len()) {
+                    core::cmp::Ordering::Less => long[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                long_entry[at..at + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            volume.write(offset, &long_entry)?;
+        }
+        let at = slots[long_entries];
+        volume.write(at, &entry)?;
+        Ok(Arc::new(Node {
+            volume: volume.clone(),
+            entry: Some(at),
+        }))
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let found = volume.find(self.dir()?, name)?.ok_or(FsError::NotFound)?;
+        let cluster = first_cluster(&found.short);
+        if found.short[11] & ATTR_DIRECTORY != 0 {
+            let other = volume.entries(Dir::Chain(cluster), |entry| {
+                (entry.name != "." && entry.name != "..").then_some(())
+            })?;
+            if other.is_some() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        for &offset in &found.slots {
+            volume.write(offset, &[DELETED])?;
+        }
+        volume.free_chain(cluster)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let mut count = 0;
+        let found = self.volume.entries(self.dir()?, |found| {
+            count += 1;
+            (count > index).then_some(found)
+        })?;
+        Ok(found.map(|found| DirEntry {
+            ino: found.slots[found.slots.len() - 1] / ENTRY_SIZE,
+            mode: if found.short[11] & ATTR_DIRECTORY != 0 {
+                S_IFDIR
+            } else {
+                S_IFREG
+            },
+            name: found.name,
+        }))
+    }
+}
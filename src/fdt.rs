@@ -0,0 +1,436 @@
+use crate::lock::SpinOnce;
+use core::fmt::{Display, Formatter};
+
+const MAGIC: u32 = 0xd00d_feed;
+// the layout with the sizes of the strings and structure blocks, every later version is
+// backwards compatible with it
+const LAST_COMP_VERSION: u32 = 17;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// deep enough for QEMU's virt machine, /soc/pci/... is the deepest it gets
+const MAX_DEPTH: usize = 8;
+const MAX_MEMORY: usize = 4;
+const MAX_RESERVED: usize = 8;
+pub const MAX_VIRTIO: usize = 8;
+
+static MACHINE: SpinOnce<Machine> = SpinOnce::new();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FdtError {
+    BadMagic,
+    BadVersion,
+    Truncated,
+    BadToken,
+    BadString,
+    TooDeep,
+    NoMemory,
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            FdtError::BadMagic => "not a flattened device tree",
+            FdtError::BadVersion => "unsupported device tree version",
+            FdtError::Truncated => "device tree is truncated",
+            FdtError::BadToken => "malformed structure block",
+            FdtError::BadString => "name is not a valid string",
+            FdtError::TooDeep => "nodes are nested too deeply",
+            FdtError::NoMemory => "device tree describes no memory",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// A flattened device tree (DTB) as handed over by QEMU or the SBI firmware in a1.
+pub struct DeviceTree<'a> {
+    reservations: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+}
+
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Property(&'a str, &'a [u8]),
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, FdtError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// The NUL terminated string at the start of `data`.
+fn c_str(data: &[u8]) -> Result<&str, FdtError> {
+    let len = data
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(FdtError::BadString)?;
+    core::str::from_utf8(&data[..len]).map_err(|_| FdtError::BadString)
+}
+
+/// Reads a number of `cells` big endian 32 bit cells.
+fn cells(data: &[u8], cells: u32) -> Result<(usize, &[u8]), FdtError> {
+    let mut value = 0;
+    for i in 0..cells as usize {
+        value = value << 32 | be32(data, i * 4)? as usize;
+    }
+    Ok((value, &data[cells as usize * 4..]))
+}
+
+impl DeviceTree<'static> {
+    /// Reads the header at physical address `address`, which must stay valid for as long as the
+    /// tree is used.
+    pub unsafe fn from_address(address: usize) -> Result<Self, FdtError> {
+        if address == 0 || address % 8 != 0 {
+            return Err(FdtError::BadMagic);
+        }
+        let header = core::slice::from_raw_parts(address as *const u8, HEADER_SIZE);
+        if be32(header, 0)? != MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let size = be32(header, 4)? as usize;
+        Self::new(core::slice::from_raw_parts(address as *const u8, size))
+    }
+}
+
+impl<'a> DeviceTree<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        if data.len() < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if be32(data, 0)? != MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        if be32(data, 20)? < LAST_COMP_VERSION || be32(data, 24)? > LAST_COMP_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+        let block = |offset: usize, size: usize| -> Result<&'a [u8], FdtError> {
+            let start = be32(data, offset)? as usize;
+            let size = if size == 0 {
+                data.len().saturating_sub(start)
+            } else {
+                be32(data, size)? as usize
+            };
+            data.get(start..start + size).ok_or(FdtError::Truncated)
+        };
+        Ok(Self {
+            // terminated by an empty entry instead of a size
+            reservations: block(16, 0)?,
+            structure: block(8, 36)?,
+            strings: block(12, 32)?,
+        })
+    }
+
+    /// Entries of the memory reservation block as (address, size).
+    pub fn reservations(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.reservations
+            .chunks_exact(16)
+            .map(|entry| {
+                let (address, rest) = cells(entry, 2).unwrap_or((0, &[]));
+                let (size, _) = cells(rest, 2).unwrap_or((0, &[]));
+                (address, size)
+            })
+            .take_while(|&(_, size)| size != 0)
+    }
+
+    /// Calls `visit` for the tokens of the structure block in order, NOPs skipped.
+    pub fn walk<F>(&self, mut visit: F) -> Result<(), FdtError>
+    where
+        F: FnMut(Token<'a>) -> Result<(), FdtError>,
+    {
+        let data = self.structure;
+        let mut offset = 0;
+        loop {
+            let token = be32(data, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(data.get(offset..).ok_or(FdtError::Truncated)?)?;
+                    offset = align4(offset + name.len() + 1);
+                    visit(Token::BeginNode(name))?;
+                }
+                FDT_END_NODE => visit(Token::EndNode)?,
+                FDT_PROP => {
+                    let len = be32(data, offset)? as usize;
+                    let name = be32(data, offset + 4)? as usize;
+                    let value = data
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or(FdtError::Truncated)?;
+                    let name = c_str(self.strings.get(name..).ok_or(FdtError::BadString)?)?;
+                    offset = align4(offset + 8 + len);
+                    visit(Token::Property(name, value))?;
+                }
+                FDT_NOP => {}
+                FDT_END => return Ok(()),
+                _ => return Err(FdtError::BadToken),
+            }
+        }
+    }
+}
+
+/// A physical address range.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Region {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Region {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start..self.end()).contains(&address)
+    }
+}
+
+/// A memory mapped device, the first range of its reg property and the first of its
+/// interrupts, 0 if it has none.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Device {
+    pub reg: Region,
+    pub irq: u32,
+}
+
+/// What the kernel and the firmware need to know about the machine, taken out of the device
+/// tree so the blob itself may be overwritten once it has been read.
+#[derive(Default)]
+pub struct Machine {
+    memory: [Region; MAX_MEMORY],
+    memory_count: usize,
+    reserved: [Region; MAX_RESERVED],
+    reserved_count: usize,
+    /// Bit n is set for each enabled hart with id n.
+    pub harts: usize,
+    /// Frequency of the time CSR (mtime) in Hz.
+    pub timebase: u64,
+    pub uart: Option<Device>,
+    pub plic: Option<Device>,
+    pub clint: Option<Device>,
+    /// SiFive test device, QEMU's power off and reset switch.
+    pub test: Option<Device>,
+    virtio: [Device; MAX_VIRTIO],
+    virtio_count: usize,
+}
+
+// the properties of a node still open while its children are parsed
+#[derive(Copy, Clone)]
+struct Node<'a> {
+    name: &'a str,
+    address_cells: u32,
+    size_cells: u32,
+    device_type: &'a str,
+    compatible: &'a [u8],
+    status: &'a str,
+    reg: Option<&'a [u8]>,
+    interrupts: Option<u32>,
+}
+
+impl<'a> Node<'a> {
+    const fn new(name: &'a str) -> Self {
+        Node {
+            name,
+            // the defaults the specification gives for a node without the properties
+            address_cells: 2,
+            size_cells: 1,
+            device_type: "",
+            compatible: &[],
+            status: "okay",
+            reg: None,
+            interrupts: None,
+        }
+    }
+    fn is_compatible(&self, names: &[&str]) -> bool {
+        self.compatible
+            .split(|&c| c == 0)
+            .any(|c| names.iter().any(|name| name.as_bytes() == c))
+    }
+    fn enabled(&self) -> bool {
+        self.status == "okay" || self.status == "ok"
+    }
+}
+
+impl Machine {
+    pub fn parse(tree: &DeviceTree<'_>) -> Result<Machine, FdtError> {
+        let mut machine = Machine::default();
+        for (start, size) in tree.reservations() {
+            machine.add_reserved(Region { start, size });
+        }
+        let mut stack = [Node::new(""); MAX_DEPTH];
+        let mut depth = 0;
+        tree.walk(|token| {
+            match token {
+                Token::BeginNode(name) => {
+                    if depth == MAX_DEPTH {
+                        return Err(FdtError::TooDeep);
+                    }
+                    stack[depth] = Node::new(name);
+                    depth += 1;
+                }
+                Token::Property(name, value) => {
+                    let node = &mut stack[depth.checked_sub(1).ok_or(FdtError::BadToken)?];
+                    let string = || c_str(value).unwrap_or("");
+                    match name {
+                        "#address-cells" => node.address_cells = be32(value, 0)?,
+                        "#size-cells" => node.size_cells = be32(value, 0)?,
+                        "device_type" => node.device_type = string(),
+                        "compatible" => node.compatible = value,
+                        "status" => node.status = string(),
+                        "reg" => node.reg = Some(value),
+                        "interrupts" => node.interrupts = Some(be32(value, 0)?),
+                        "timebase-frequency" => {
+                            machine.timebase = if value.len() == 8 {
+                                cells(value, 2)?.0 as u64
+                            } else {
+                                be32(value, 0)? as u64
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Token::EndNode => {
+                    depth = depth.checked_sub(1).ok_or(FdtError::BadToken)?;
+                    // the root has no parent to take the cell counts of reg from
+                    if depth > 0 {
+                        machine.add_node(&stack[depth], &stack[depth - 1])?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        if machine.memory_count == 0 {
+            return Err(FdtError::NoMemory);
+        }
+        Ok(machine)
+    }
+
+    fn add_node(&mut self, node: &Node<'_>, parent: &Node<'_>) -> Result<(), FdtError> {
+        if !node.enabled() {
+            return Ok(());
+        }
+        let mut regions = [Region::default(); MAX_MEMORY];
+        let mut count = 0;
+        if let Some(mut reg) = node.reg {
+            let entry = (parent.address_cells + parent.size_cells) as usize * 4;
+            while entry != 0 && reg.len() >= entry && count < MAX_MEMORY {
+                let (start, rest) = cells(reg, parent.address_cells)?;
+                let (size, rest) = cells(rest, parent.size_cells)?;
+                regions[count] = Region { start, size };
+                count += 1;
+                reg = rest;
+            }
+        }
+        let device = Device {
+            reg: regions[0],
+            irq: node.interrupts.unwrap_or(0),
+        };
+        if node.device_type == "memory" {
+            for &region in &regions[..count] {
+                if self.memory_count < MAX_MEMORY {
+                    self.memory[self.memory_count] = region;
+                    self.memory_count += 1;
+                }
+            }
+        } else if parent.name == "reserved-memory" {
+            for &region in &regions[..count] {
+                self.add_reserved(region);
+            }
+        } else if node.device_type == "cpu" {
+            // the reg of a cpu node is its hart id
+            if count > 0 && device.reg.start < usize::BITS as usize {
+                self.harts |= 1 << device.reg.start;
+            }
+        } else if count > 0 {
+            if node.is_compatible(&["ns16550a", "ns16550"]) {
+                self.uart.get_or_insert(device);
+            } else if node.is_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+                self.plic.get_or_insert(device);
+            } else if node.is_compatible(&["riscv,clint0", "sifive,clint0"]) {
+                self.clint.get_or_insert(device);
+            } else if node.is_compatible(&["sifive,test0", "sifive,test1"]) {
+                self.test.get_or_insert(device);
+            } else if node.is_compatible(&["virtio,mmio"]) && self.virtio_count < MAX_VIRTIO {
+                self.virtio[self.virtio_count] = device;
+                self.virtio_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_reserved(&mut self, region: Region) {
+        if self.reserved_count < MAX_RESERVED {
+            self.reserved[self.reserved_count] = region;
+            self.reserved_count += 1;
+        }
+    }
+
+    pub fn memory(&self) -> &[Region] {
+        &self.memory[..self.memory_count]
+    }
+    /// Memory that must not be handed out, from the reservation block and /reserved-memory.
+    pub fn reserved(&self) -> &[Region] {
+        &self.reserved[..self.reserved_count]
+    }
+    /// virtio-mmio transports in the order of the device tree, not all of them have a device
+    /// behind them.
+    pub fn virtio(&self) -> &[Device] {
+        &self.virtio[..self.virtio_count]
+    }
+}
+
+impl Display for Machine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for region in self.memory() {
+            writeln!(f, "memory 0x{:x} -> 0x{:x}", region.start, region.end())?;
+        }
+        for region in self.reserved() {
+            writeln!(f, "reserved 0x{:x} -> 0x{:x}", region.start, region.end())?;
+        }
+        writeln!(f, "harts 0b{:b}, timebase {} Hz", self.harts, self.timebase)?;
+        let devices = [
+            ("uart", self.uart),
+            ("plic", self.plic),
+            ("clint", self.clint),
+            ("test", self.test),
+        ];
+        for (name, device) in devices.iter() {
+            if let Some(device) = device {
+                writeln!(
+                    f,
+                    "{} at 0x{:x}, irq {}",
+                    name, device.reg.start, device.irq
+                )?;
+            }
+        }
+        for device in self.virtio() {
+            writeln!(
+                f,
+                "virtio-mmio at 0x{:x}, irq {}",
+                device.reg.start, device.irq
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the device tree at physical address `fdt`, before the page allocator may hand out
+/// the memory it lives in.
+pub fn init(fdt: usize) -> Result<&'static Machine, FdtError> {
+    let tree = unsafe { DeviceTree::from_address(fdt)? };
+    let machine = Machine::parse(&tree)?;
+    Ok(MACHINE.call_once(|| machine))
+}
+
+pub fn machine() -> &'static Machine {
+    MACHINE.get().expect("device tree not parsed")
+}
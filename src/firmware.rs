@@ -0,0 +1,257 @@
+use crate::cpu::{self, MAX_HARTS};
+use crate::fdt::{DeviceTree, Machine};
+use crate::sbi::*;
+use crate::uart;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+// QEMU virt's device addresses until sbi_init reads the device tree. Not zero, so they live in
+// .data and survive the kernel clearing the bss.
+// the CLINT, only machine mode may program it
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(0x0200_0000);
+static UART_BASE: AtomicUsize = AtomicUsize::new(0x1000_0000);
+// SiFive test device, writing it ends the simulation
+static TEST_BASE: AtomicUsize = AtomicUsize::new(0x10_0000);
+const MSIP: usize = 0x0;
+const MTIMECMP: usize = 0x4000;
+const TEST_PASS: u32 = 0x5555;
+const TEST_FAIL: u32 = 0x3333;
+const TEST_RESET: u32 = 0x7777;
+
+/// SBI specification 1.0
+const SPEC_VERSION: usize = 1 << 24;
+// not taken from the SBI implementation ID registry
+const IMPL_ID: usize = 0xffff;
+const IMPL_VERSION: usize = 1;
+
+const SSIP: usize = 1 << 1;
+const STIP: usize = 1 << 5;
+const MTIE: usize = 1 << 7;
+const MSIP_BIT: usize = 1 << 3;
+
+extern "C" {
+    static PARKED_HARTS: AtomicU32;
+}
+
+// where the kernel started each hart with hart_start, 0 while it hasn't
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STARTED: AtomicUsize = AtomicUsize::new(0);
+static START_ADDRESS: [AtomicUsize; MAX_HARTS] = [NOT_STARTED; MAX_HARTS];
+static START_OPAQUE: [AtomicUsize; MAX_HARTS] = [NOT_STARTED; MAX_HARTS];
+
+#[repr(C)]
+struct HartStart {
+    address: usize,
+    opaque: usize,
+}
+
+/// Called by boot.S on hart 0 before it enters the kernel, with the bss not cleared yet. Takes
+/// the devices the firmware drives from the device tree at `fdt`, keeps the defaults if there
+/// is none.
+#[no_mangle]
+extern "C" fn sbi_init(fdt: usize) {
+    let machine =
+        match unsafe { DeviceTree::from_address(fdt) }.and_then(|tree| Machine::parse(&tree)) {
+            Ok(machine) => machine,
+            Err(e) => {
+                let _ = write!(RawConsole, "sbi: device tree at 0x{:x}: {}\r\n", fdt, e);
+                return;
+            }
+        };
+    let devices = [
+        (&CLINT_BASE, machine.clint),
+        (&UART_BASE, machine.uart),
+        (&TEST_BASE, machine.test),
+    ];
+    for (base, device) in devices.iter() {
+        if let Some(device) = device {
+            base.store(device.reg.start, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keeps a secondary hart in machine mode until the kernel starts it with hart_start, boot.S
+/// then enters the returned address in supervisor mode.
+#[no_mangle]
+extern "C" fn sbi_hart_wait(hart: usize) -> HartStart {
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+        if cpu::mip_read() & MSIP_BIT == 0 {
+            continue;
+        }
+        unsafe { msip(hart).write_volatile(0) };
+        let address = START_ADDRESS[hart].load(Ordering::Acquire);
+        if address != 0 {
+            let opaque = START_OPAQUE[hart].load(Ordering::Relaxed);
+            return HartStart { address, opaque };
+        }
+    }
+}
+
+/// Machine mode half of the kernel. boot.S delegates every exception and supervisor interrupt
+/// to the kernel, this handles what is left: SBI calls and the machine timer and software
+/// interrupts, which are passed on as their supervisor counterparts. It is entered through
+/// sbi_trap_vector with the registers of the interrupted hart in `regs`, runs untranslated on
+/// its own stack and must never take one of the kernel's locks.
+#[no_mangle]
+extern "C" fn sbi_trap(regs: &mut [usize; 32]) {
+    let cause = cpu::mcause_read();
+    let hart = cpu::mhartid_read();
+    if cause & 1 << 63 != 0 {
+        match cause & 0xfff {
+            3 => {
+                unsafe { msip(hart).write_volatile(0) };
+                cpu::mip_set(SSIP);
+            }
+            7 => {
+                // stays pending until the kernel programs the next deadline
+                cpu::mie_clear(MTIE);
+                cpu::mip_set(STIP);
+            }
+            cause => halt(format_args!(
+                "unexpected interrupt {} on hart {}",
+                cause, hart
+            )),
+        }
+        return;
+    }
+    match cause {
+        9 => {
+            ecall(hart, regs);
+            cpu::mepc_write(cpu::mepc_read() + 4);
+        }
+        cause => halt(format_args!(
+            "unexpected exception {} on hart {} at 0x{:x}",
+            cause,
+            hart,
+            cpu::mepc_read()
+        )),
+    }
+}
+
+/// Handles an SBI call from supervisor mode, the extension ID is in a7 and the function ID in
+/// a6. The error code is returned in a0 and the value in a1.
+fn ecall(hart: usize, regs: &mut [usize; 32]) {
+    let (ext, fid, arg0, arg1) = (regs[17], regs[16], regs[10], regs[11]);
+    match ext {
+        EXT_CONSOLE_PUTCHAR => {
+            uart::uart_put(UART_BASE.load(Ordering::Relaxed), arg0 as u8);
+            regs[10] = 0;
+            return;
+        }
+        EXT_CONSOLE_GETCHAR => {
+            regs[10] = uart::uart_get(UART_BASE.load(Ordering::Relaxed))
+                .map_or(usize::MAX, |c| c as usize);
+            return;
+        }
+        _ => {}
+    }
+    let (error, value) = match (ext, fid) {
+        (EXT_BASE, _) => base(fid, arg0),
+        (EXT_TIME, 0) => {
+            unsafe { mtimecmp(hart).write_volatile(arg0 as u64) };
+            cpu::mip_clear(STIP);
+            cpu::mie_set(MTIE);
+            (SUCCESS, 0)
+        }
+        (EXT_IPI, 0) => send_ipi(arg0, arg1),
+        (EXT_HSM, HSM_HART_START) => hart_start(arg0, arg1, regs[12]),
+        (EXT_SRST, SRST_SYSTEM_RESET) => system_reset(arg0, arg1),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    };
+    regs[10] = error as usize;
+    regs[11] = value;
+}
+
+fn base(fid: usize, arg: usize) -> (isize, usize) {
+    let value = match fid {
+        BASE_GET_SPEC_VERSION => SPEC_VERSION,
+        BASE_GET_IMPL_ID => IMPL_ID,
+        BASE_GET_IMPL_VERSION => IMPL_VERSION,
+        BASE_PROBE_EXTENSION => matches!(
+            arg,
+            EXT_BASE
+                | EXT_TIME
+                | EXT_IPI
+                | EXT_HSM
+                | EXT_SRST
+                | EXT_CONSOLE_PUTCHAR
+                | EXT_CONSOLE_GETCHAR
+        ) as usize,
+        // 0 is a legal answer for a machine that doesn't implement these
+        BASE_GET_MVENDORID | BASE_GET_MARCHID | BASE_GET_MIMPID => 0,
+        _ => return (ERR_NOT_SUPPORTED, 0),
+    };
+    (SUCCESS, value)
+}
+
+/// Raises a machine software interrupt on the harts in `mask`, bit n stands for hart `base + n`
+/// and a base of -1 for all harts.
+fn send_ipi(mask: usize, base: usize) -> (isize, usize) {
+    let harts = if base == usize::MAX {
+        (1 << MAX_HARTS) - 1
+    } else if base < MAX_HARTS && mask >> (MAX_HARTS - base) == 0 {
+        mask << base
+    } else {
+        return (ERR_INVALID_PARAM, 0);
+    };
+    for hart in (0..MAX_HARTS).filter(|h| harts & 1 << h != 0) {
+        unsafe { msip(hart).write_volatile(1) };
+    }
+    (SUCCESS, 0)
+}
+
+/// Releases `hart` from sbi_hart_wait. Only harts parked in boot.S can be started, once.
+fn hart_start(hart: usize, address: usize, opaque: usize) -> (isize, usize) {
+    let parked = unsafe { PARKED_HARTS.load(Ordering::Acquire) } as usize;
+    if hart >= MAX_HARTS || parked & 1 << hart == 0 {
+        return (ERR_INVALID_PARAM, 0);
+    }
+    if START_ADDRESS[hart].load(Ordering::Relaxed) != 0 {
+        return (ERR_ALREADY_AVAILABLE, 0);
+    }
+    START_OPAQUE[hart].store(opaque, Ordering::Relaxed);
+    START_ADDRESS[hart].store(address, Ordering::Release);
+    unsafe { msip(hart).write_volatile(1) };
+    (SUCCESS, 0)
+}
+
+/// QEMU leaves as soon as the test device is written, on other machines this fails.
+fn system_reset(kind: usize, reason: usize) -> (isize, usize) {
+    let command = match (kind, reason) {
+        (RESET_SHUTDOWN, RESET_REASON_NONE) => TEST_PASS,
+        // exit status 1
+        (RESET_SHUTDOWN, _) => TEST_FAIL | 1 << 16,
+        (RESET_COLD_REBOOT | RESET_WARM_REBOOT, _) => TEST_RESET,
+        _ => return (ERR_INVALID_PARAM, 0),
+    };
+    unsafe { (TEST_BASE.load(Ordering::Relaxed) as *mut u32).write_volatile(command) };
+    (ERR_FAILED, 0)
+}
+
+fn msip(hart: usize) -> *mut u32 {
+    (CLINT_BASE.load(Ordering::Relaxed) + MSIP + hart * 4) as *mut u32
+}
+
+fn mtimecmp(hart: usize) -> *mut u64 {
+    (CLINT_BASE.load(Ordering::Relaxed) + MTIMECMP + hart * 8) as *mut u64
+}
+
+/// Reports a trap the firmware can't handle and stops the hart. The kernel's panic handler
+/// would end up back here through the SBI console.
+fn halt(args: core::fmt::Arguments<'_>) -> ! {
+    let _ = RawConsole.write_fmt(args);
+    let _ = RawConsole.write_str("\r\n");
+    crate::abort()
+}
+
+struct RawConsole;
+
+impl Write for RawConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.bytes() {
+            uart::uart_put(UART_BASE.load(Ordering::Relaxed), c);
+        }
+        Ok(())
+    }
+}
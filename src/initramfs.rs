@@ -0,0 +1,249 @@
+extern crate alloc;
+use crate::lock::SpinOnce;
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, S_IFDIR, S_IFMT};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+extern "C" {
+    static INITRAMFS_START: usize;
+    static INITRAMFS_END: usize;
+}
+
+// packed from the rootfs directory by build.rs
+#[used]
+#[link_section = ".initramfs"]
+static ARCHIVE: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const HEADER_SIZE: usize = 110;
+const MAGIC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+static INITRAMFS: SpinOnce<Vec<File>> = SpinOnce::new();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CpioError {
+    BadMagic,
+    BadHeader,
+    BadName,
+    Truncated,
+}
+
+impl Display for CpioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            CpioError::BadMagic => "not a cpio newc archive",
+            CpioError::BadHeader => "malformed cpio header",
+            CpioError::BadName => "file name is not valid UTF-8",
+            CpioError::Truncated => "archive is truncated",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+pub struct File {
+    name: &'static str,
+    mode: u32,
+    data: &'static [u8],
+}
+
+impl File {
+    /// Path relative to the archive root, without leading or trailing slashes.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+fn archive() -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            INITRAMFS_START as *const u8,
+            INITRAMFS_END - INITRAMFS_START,
+        )
+    }
+}
+
+fn hex_field(header: &[u8], index: usize) -> Result<usize, CpioError> {
+    let field = &header[6 + index * 8..][..8];
+    let mut value = 0;
+    for &c in field {
+        let digit = (c as char).to_digit(16).ok_or(CpioError::BadHeader)?;
+        value = value << 4 | digit as usize;
+    }
+    Ok(value)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Strips the "./" and "/" prefixes cpio tools emit, "." itself becomes the root "".
+fn normalize(name: &str) -> &str {
+    let name = name.trim_start_matches("./").trim_start_matches('/');
+    let name = name.trim_end_matches('/');
+    if name == "." {
+        ""
+    } else {
+        name
+    }
+}
+
+fn parse(data: &'static [u8]) -> Result<Vec<File>, CpioError> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+    // an empty archive is valid, it simply contains no files
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+        let mode = hex_field(header, 1)? as u32;
+        let file_size = hex_field(header, 6)?;
+        let name_size = hex_field(header, 11)?;
+        if name_size == 0 {
+            return Err(CpioError::BadHeader);
+        }
+
+        let name_start = offset + HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size - 1)
+            .ok_or(CpioError::Truncated)?;
+        let name = core::str::from_utf8(name).map_err(|_| CpioError::BadName)?;
+        if name == TRAILER {
+            break;
+        }
+
+        let data_start = align4(name_start + name_size);
+        let file_data = data
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        files.push(File {
+            name: normalize(name),
+            mode,
+            data: file_data,
+        });
+        offset = align4(data_start + file_size);
+    }
+    Ok(files)
+}
+
+/// Parses the linked-in archive, returns the number of entries found.
+pub fn init() -> Result<usize, CpioError> {
+    let files = parse(archive())?;
+    let count = files.len();
+    INITRAMFS.call_once(|| files);
+    Ok(count)
+}
+
+pub fn files() -> &'static [File] {
+    match INITRAMFS.get() {
+        Some(files) => files,
+        None => &[],
+    }
+}
+
+/// The archive as a read-only filesystem. Directories must have entries of their own, as `find`
+/// piped into `cpio` creates them.
+pub struct Initramfs;
+
+struct Node {
+    ino: u64,
+    // None for the root, which the archive need not list
+    file: Option<&'static File>,
+}
+
+impl Node {
+    fn name(&self) -> &'static str {
+        self.file.map_or("", File::name)
+    }
+    fn is_dir(&self) -> bool {
+        self.file.map_or(true, File::is_dir)
+    }
+    /// The entries directly in this directory, with their index in the archive.
+    fn children(&self) -> impl Iterator<Item = (usize, &'static File)> + '_ {
+        files()
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| !f.name.is_empty() && split(f.name).0 == self.name())
+    }
+}
+
+/// Splits an archive path into its directory, "" in the root, and its last component.
+fn split(name: &str) -> (&str, &str) {
+    name.rsplit_once('/').unwrap_or(("", name))
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        Ok(Stat {
+            ino: self.ino,
+            mode: self.file.map_or(S_IFDIR | 0o755, File::mode),
+            nlink: 1,
+            size: self.file.map_or(0, |f| f.data.len() as u64),
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = match self.file {
+            Some(file) if !file.is_dir() => file.data,
+            _ => return Err(FsError::IsDir),
+        };
+        let start = core::cmp::min(offset, data.len() as u64) as usize;
+        let len = core::cmp::min(buf.len(), data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+    fn write(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if !self.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        match self.children().find(|(_, f)| split(f.name).1 == name) {
+            Some((index, file)) => Ok(Arc::new(Node {
+                ino: index as u64 + 2,
+                file: Some(file),
+            })),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, _name: &str, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        if !self.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        Ok(self.children().nth(index).map(|(i, f)| DirEntry {
+            ino: i as u64 + 2,
+            mode: f.mode & S_IFMT,
+            name: String::from(split(f.name).1),
+        }))
+    }
+}
+
+impl Filesystem for Initramfs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node { ino: 1, file: None })
+    }
+}
@@ -1,7 +1,7 @@
+use crate::lock::Spinlock;
 use crate::page::{Table, PAGE_SIZE};
-use crate::{cpu, page, trap, Pmem};
+use crate::{cpu, fdt, page, trap, Pmem};
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::RefCell;
 use core::fmt::{Display, Formatter};
 use core::ops::Deref;
 
@@ -16,6 +16,17 @@ extern "C" {
     static BSS_END: usize;
     static KERNEL_STACK_START: usize;
     static KERNEL_STACK_END: usize;
+    static HEAP_START: usize;
+    pub static mut KERNEL_TABLE: usize;
+}
+
+/// Root table of the kernel's address space, every process' table maps the kernel from it.
+pub fn kernel_table() -> &'static Table {
+    unsafe { &*(KERNEL_TABLE as *const Table) }
+}
+
+pub fn kernel_satp() -> usize {
+    unsafe { cpu::build_satp(cpu::SatpMode::Sv39, 0, KERNEL_TABLE) }
 }
 
 const PAGES_POW: usize = 6;
@@ -139,6 +150,9 @@ pub struct Kmem {
     data_start: *mut u8,
 }
 
+// owns the memory behind its pointers, GA's lock serializes access from different harts
+unsafe impl Send for Kmem {}
+
 impl Kmem {
     pub fn init(pmem: &mut Pmem) -> Self {
         let k_alloc = pmem.zalloc(1 + (1 << PAGES_POW));
@@ -258,11 +272,12 @@ impl Kmem {
     pub fn init_trap_memory(&self, mm: &mut Pmem) {
         let satp_value = cpu::build_satp(cpu::SatpMode::Sv39, 0, self.page_table as usize);
         unsafe {
-            cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[0] as *mut _) as usize);
-            cpu::sscratch_write(cpu::mscratch_read());
-            cpu::KERNEL_TRAP_FRAME[0].satp = satp_value;
-            let stack = mm.zalloc(1).leak().add(PAGE_SIZE);
-            cpu::KERNEL_TRAP_FRAME[0].stack = stack;
+            cpu::sscratch_write((&mut cpu::KERNEL_TRAP_FRAME[cpu::hartid()] as *mut _) as usize);
+            for (hart, frame) in cpu::KERNEL_TRAP_FRAME.iter_mut().enumerate() {
+                frame.satp = satp_value;
+                frame.stack = mm.zalloc(1).leak().add(PAGE_SIZE);
+                frame.hartid = hart;
+            }
         }
     }
     pub fn init_mmu(&self) {
@@ -271,10 +286,11 @@ impl Kmem {
         cpu::satp_write(satp_value);
         cpu::satp_fence_asid(0);
     }
-    //call after trap frames have been initialized
+    /// Identity maps the kernel image, the devices and all of the memory the page allocator
+    /// hands out, page tables and the kernel heap included.
     pub fn id_map_kernel(&mut self, alloc: &mut Pmem) {
         use page::entry_bits;
-        use page::id_map_range;
+        use page::{id_map_range, id_map_range_large};
         let kheap_head = self.get_head() as usize;
         let kheap_pages = self.get_allocations();
         let root = self.get_root();
@@ -299,24 +315,43 @@ impl Kmem {
         }
 
         unsafe {
-            let stack = cpu::KERNEL_TRAP_FRAME[0].stack;
+            let frames = cpu::KERNEL_TRAP_FRAME.as_ptr() as usize;
             id_map_battery!(
-                kheap_head, kheap_head + kheap_pages * PAGE_SIZE, entry_bits::READ_WRITE;
-                alloc.descriptors().as_ptr() as usize, alloc.descriptors().as_ptr() as usize + alloc.descriptors().len() * core::mem::size_of::<page::Page>(), entry_bits::READ_WRITE;
                 TEXT_START, TEXT_END, entry_bits::READ_EXECUTE;
                 RODATA_START, RODATA_END, entry_bits::READ_EXECUTE;
                 DATA_START, DATA_END, entry_bits::READ_WRITE;
                 BSS_START, BSS_END, entry_bits::READ_WRITE;
                 KERNEL_STACK_START, KERNEL_STACK_END, entry_bits::READ_WRITE;
-                stack.sub(PAGE_SIZE) as usize, stack as usize, entry_bits::READ_WRITE;
-                cpu::mscratch_read(), cpu::mscratch_read() + core::mem::size_of::<cpu::TrapFrame>(), entry_bits::READ_WRITE;
-                0x10000000, 0x1000000F, entry_bits::READ_WRITE
+                frames, frames + core::mem::size_of_val(&cpu::KERNEL_TRAP_FRAME), entry_bits::READ_WRITE
+            );
+        }
+        if let Some(uart) = fdt::machine().uart {
+            id_map_range(
+                root,
+                alloc,
+                uart.reg.start,
+                uart.reg.end(),
+                entry_bits::READ_WRITE,
+            );
+        }
+        for transport in fdt::machine().virtio() {
+            id_map_range(
+                root,
+                alloc,
+                transport.reg.start,
+                transport.reg.end(),
+                entry_bits::READ_WRITE,
             );
         }
 
-        for &address in trap::plic::get_addresses() {
+        for &address in trap::plic::get_addresses().iter() {
             id_map_range(root, alloc, address, address, entry_bits::READ_WRITE);
         }
+        // megapages from the first 2 MiB boundary on, nothing above maps into them
+        let end = alloc.end();
+        unsafe {
+            id_map_range_large(root, alloc, HEAP_START, end, entry_bits::READ_WRITE);
+        }
     }
 }
 
@@ -372,11 +407,11 @@ impl Display for BuddyLeaf {
     }
 }
 
-pub struct KmemAllocator(pub RefCell<Option<Kmem>>);
+pub struct KmemAllocator(pub Spinlock<Option<Kmem>>);
 
 impl Display for KmemAllocator {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        if let Some(ref km) = self.0.borrow().deref() {
+        if let Some(ref km) = self.0.lock().deref() {
             km.fmt(f)
         } else {
             write!(f, "NONE")
@@ -386,12 +421,7 @@ impl Display for KmemAllocator {
 
 unsafe impl GlobalAlloc for KmemAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        println!(
-            "allocating memory: size {} align {}",
-            layout.size(),
-            layout.align()
-        );
-        if let Some(km) = self.0.borrow().deref() {
+        if let Some(km) = self.0.lock().deref() {
             let size = layout.size() - 1;
             let log2 = usize::BITS - size.leading_zeros();
             km.kzalloc(core::cmp::max(log2 as usize, 7))
@@ -400,9 +430,8 @@ unsafe impl GlobalAlloc for KmemAllocator {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
-        println!("freeing memory: size {} align {}", l.size(), l.align());
-        if let Some(km) = self.0.borrow().deref() {
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if let Some(km) = self.0.lock().deref() {
             km.kfree(ptr)
         } else {
             panic!("memory corruption")
@@ -410,10 +439,8 @@ unsafe impl GlobalAlloc for KmemAllocator {
     }
 }
 
-// TODO not actually sync, but right now we only support one HART
-unsafe impl Sync for KmemAllocator {}
 #[global_allocator]
-pub static GA: KmemAllocator = KmemAllocator(RefCell::new(None));
+pub static GA: KmemAllocator = KmemAllocator(Spinlock::new(None));
 
 #[alloc_error_handler]
 fn alloc_error_handler(l: Layout) -> ! {
@@ -11,18 +11,18 @@
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
 #![feature(int_roundings)]
+extern crate alloc;
 use crate::kmem::Kmem;
+use crate::lock::{SpinOnce, Spinlock, SpinlockGuard};
 use crate::page::{Pmem, Table, PAGE_SIZE};
+use alloc::sync::Arc;
 use core::arch::asm;
-use core::cell::{RefCell, RefMut};
+use core::fmt::Write;
 
 #[macro_export]
 macro_rules! print {
     ($($args:tt)+) => {
-        {
-            use core::fmt::Write;
-            let _ = write!($crate::uart::get_uart(), $($args)+);
-        }
+        $crate::uart::print(format_args!($($args)+))
     };
 }
 
@@ -43,17 +43,21 @@ macro_rules! println {
 pub extern "C" fn eh_personality() {}
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
-    print!("Aborting: ");
-    if let Some(p) = info.location() {
-        println!(
-            "line {}, file {}: {}",
+    // the firmware's console, the panicking hart may hold the UART lock
+    let mut console = sbi::Console;
+    let _ = write!(console, "Aborting: ");
+    let _ = if let Some(p) = info.location() {
+        write!(
+            console,
+            "line {}, file {}: {}\r\n",
             p.line(),
             p.file(),
             info.message().unwrap()
-        );
+        )
     } else {
-        println!("no information available.");
-    }
+        write!(console, "no information available.\r\n")
+    };
+    sbi::system_reset(sbi::RESET_SHUTDOWN, sbi::RESET_REASON_FAILURE);
     abort();
 }
 
@@ -67,66 +71,205 @@ pub extern "C" fn abort() -> ! {
 }
 
 extern "C" {
-    static mut KERNEL_TABLE: usize;
-    fn switch_to_user(frame: usize, mepc: usize, satp: usize) -> !;
+    static HEAP_START: usize;
+    fn _start_hart();
+    fn switch_to_user(frame: usize, pc: usize, satp: usize) -> !;
 }
 
+/// Entered by the boot hart, in supervisor mode with translation off. `fdt` is the physical
+/// address of the device tree.
 #[no_mangle]
-pub extern "C" fn kinit() {
-    uart::initialize();
+pub extern "C" fn kinit(hart: usize, fdt: usize) {
+    // read before the page allocator hands out the memory the blob lives in
+    let machine = match fdt::init(fdt) {
+        Ok(machine) => machine,
+        Err(e) => panic!("device tree at 0x{:x}: {}", fdt, e),
+    };
+    uart::initialize(machine.uart.expect("no uart in the device tree").reg.start);
     println!("uart initialized");
+    println!("booting on hart {}, device tree at 0x{:x}", hart, fdt);
+    print!("{}", machine);
+    assert_ne!(
+        machine.timebase, 0,
+        "no timebase-frequency in the device tree"
+    );
 
-    let mut mm = Pmem::init();
+    let (major, minor) = sbi::spec_version();
+    println!("SBI specification v{}.{}", major, minor);
+    assert!(
+        sbi::probe_extension(sbi::EXT_TIME) && sbi::probe_extension(sbi::EXT_IPI),
+        "the SBI implementation lacks the timer or IPI extension"
+    );
+
+    // pages are handed out from the end of the kernel image to the end of its memory region
+    let heap_start = unsafe { HEAP_START };
+    let memory = machine
+        .memory()
+        .iter()
+        .find(|region| region.contains(heap_start))
+        .expect("the kernel is not in memory the device tree describes");
+    let mut mm = Pmem::init(heap_start, memory.end());
+    for region in machine.reserved() {
+        mm.reserve(region.start, region.end());
+    }
+    trap::plic::init(machine.plic.expect("no plic in the device tree").reg.start);
     let mut kmem = Kmem::init(&mut mm);
-    //kmem.init_mmu();
     kmem.init_trap_memory(&mut mm);
     kmem.id_map_kernel(&mut mm);
     let root_u: *mut Table = kmem.get_root();
+    unsafe {
+        kmem::KERNEL_TABLE = root_u as usize;
+    }
+    kmem.init_mmu();
 
     println!("\nALLOCATIONS:\n{}", mm);
     #[cfg(debug_assertions)]
     {
-        let p = 0x800060a8_usize;
+        let p = 0x802060a8_usize;
         let m = Table::virt_to_phys(kmem.get_root(), p as *const u8).unwrap_or(0);
         assert_eq!(p, m);
     }
-    kmem::GA.0.replace(Some(kmem));
-    unsafe {
-        MM = Some(RefCell::new(mm));
-        KERNEL_TABLE = root_u as usize;
+    *kmem::GA.0.lock() = Some(kmem);
+    MM.call_once(|| Spinlock::new(mm));
+    match initramfs::init() {
+        Ok(entries) => {
+            println!("initramfs: {} entries", entries);
+            for file in initramfs::files() {
+                println!("  /{} ({} bytes)", file.name(), file.data().len());
+            }
+        }
+        Err(e) => println!("initramfs: {}", e),
     }
-    sched::init();
 
     trap::plic::set_threshold(0);
-    trap::plic::enable_interrupt(10);
-    trap::plic::set_priority(10, 1);
+    if let Some(uart) = machine.uart {
+        trap::plic::enable_interrupt(uart.irq as usize);
+        trap::plic::set_priority(uart.irq as usize, 1);
+    }
+    virtio::register(&entropy::DRIVER);
+    virtio::register(&block::DRIVER);
+    virtio::probe();
+    bcache::init();
+    devfs::register("console", &uart::CONSOLE);
+    devfs::register("ttyS0", &uart::CONSOLE);
+    devfs::register("null", &devfs::NULL);
+    devfs::register("zero", &devfs::ZERO);
+    if entropy::available() {
+        devfs::register("random", &entropy::RANDOM);
+    }
+    mount_root();
+    sched::init();
 
-    let (frame, mepc, satp) = sched::schedule();
-    assert!(!frame.is_null(), "no user process");
+    // the device tree lists the harts, the SBI implementation starts them
+    if sbi::probe_extension(sbi::EXT_HSM) {
+        for other in (0..usize::BITS as usize).filter(|&h| h != hart && machine.harts & 1 << h != 0)
+        {
+            if other >= cpu::MAX_HARTS {
+                println!(
+                    "ignoring hart {}, only {} are supported",
+                    other,
+                    cpu::MAX_HARTS
+                );
+            } else if sbi::hart_start(other, _start_hart as usize, 0) == sbi::SUCCESS {
+                println!("starting hart {}", other);
+            }
+        }
+    }
 
-    unsafe {
-        // enable timer
-        let mtimecmp = 0x0200_4000 as *mut u64;
-        let mtime = 0x0200_bff8 as *const u64;
-        mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
+    sched::start(hart);
+}
 
-        // user mode
-        switch_to_user(frame as usize, mepc, satp);
+/// The filesystem on `disk`, of the first driver that recognizes it.
+fn probe(disk: usize) -> Result<Arc<dyn vfs::Filesystem>, vfs::FsError> {
+    if let Ok(fs) = minix::Minix::mount(disk) {
+        return Ok(Arc::new(fs));
+    }
+    if let Ok(fs) = ext2::Ext2::mount(disk) {
+        return Ok(Arc::new(fs));
+    }
+    Ok(Arc::new(fat::Fat::mount(disk)?))
+}
+
+/// Most physical memory the files in /tmp may take.
+const TMP_SIZE: usize = 4 << 20;
+
+/// Mounts the filesystem on the first disk as the root, with the initramfs on /initramfs if
+/// the directory exists. Without a disk filesystem the initramfs is the root. A tmpfs goes on
+/// /tmp, the procfs on /proc and the devfs on /dev if the root has those directories.
+fn mount_root() {
+    let disk = match probe(0) {
+        Ok(fs) => Some(fs),
+        Err(e) => {
+            println!("vfs: no filesystem on disk 0: {}", e);
+            None
+        }
+    };
+    let initramfs = Arc::new(initramfs::Initramfs);
+    let mounted = match disk {
+        Some(fs) => vfs::mount("/", fs).and_then(|_| match vfs::mount("/initramfs", initramfs) {
+            Err(vfs::FsError::NotFound) => Ok(()),
+            other => other,
+        }),
+        None => vfs::mount("/", initramfs),
+    };
+    if let Err(e) = mounted {
+        println!("vfs: mounting the root: {}", e);
+        return;
+    }
+    let virtual_fs: [(&str, Arc<dyn vfs::Filesystem>); 3] = [
+        ("/tmp", Arc::new(tmpfs::Tmpfs::new(TMP_SIZE))),
+        ("/proc", Arc::new(procfs::Procfs)),
+        ("/dev", Arc::new(devfs::Devfs)),
+    ];
+    for (path, fs) in virtual_fs {
+        match vfs::mount(path, fs) {
+            Ok(()) | Err(vfs::FsError::NotFound) => {}
+            Err(e) => println!("vfs: mounting {}: {}", path, e),
+        }
+    }
+}
+
+/// Entered by the other harts once kinit started them, in supervisor mode with translation off.
+#[no_mangle]
+pub extern "C" fn kinit_hart(hart: usize) {
+    unsafe {
+        cpu::sscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hart] as *mut _) as usize);
     }
+    cpu::satp_write(kmem::kernel_satp());
+    cpu::satp_fence_asid(0);
+    sched::start(hart);
 }
 
-static mut MM: Option<RefCell<Pmem>> = None;
+static MM: SpinOnce<Spinlock<Pmem>> = SpinOnce::new();
 
-pub fn get_mm() -> RefMut<'static, Pmem> {
-    unsafe { MM.as_mut().unwrap().borrow_mut() }
+pub fn get_mm() -> SpinlockGuard<'static, Pmem> {
+    MM.get().expect("page allocator not initialized").lock()
 }
 
 mod assembly;
+mod bcache;
+mod block;
 mod cpu;
+mod devfs;
+mod elf;
+mod entropy;
+mod ext2;
+mod fat;
+mod fdt;
+mod firmware;
+mod initramfs;
 mod kmem;
+mod lock;
+mod minix;
 mod page;
 mod process;
+mod procfs;
+mod sbi;
 mod sched;
 mod syscall;
+mod timer;
+mod tmpfs;
 mod trap;
 mod uart;
+mod vfs;
+mod virtio;
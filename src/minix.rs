@@ -0,0 +1,583 @@
+extern crate alloc;
+use crate::bcache;
+use crate::lock::Spinlock;
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, S_IFDIR, S_IFLNK, S_IFMT};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const MAGIC: u16 = 0x4d5a;
+/// The superblock is the second kilobyte of the disk, whatever the block size.
+const SUPERBLOCK: u64 = 1024;
+const INODE_SIZE: u64 = 64;
+const DIRENT_SIZE: u64 = 64;
+const NAME_LEN: usize = 60;
+const ROOT_INO: u32 = 1;
+
+// zone slots of an inode, the triple indirect one is not supported
+const DIRECT: usize = 7;
+const INDIRECT: usize = 7;
+const DOUBLE_INDIRECT: usize = 8;
+
+/// A Minix v3 filesystem, as `mkfs.minix -3` creates it.
+pub struct Minix(Arc<Volume>);
+
+struct Volume {
+    disk: usize,
+    block_size: u64,
+    ninodes: u32,
+    zones: u32,
+    imap_start: u64,
+    imap_blocks: u64,
+    zmap_start: u64,
+    zmap_blocks: u64,
+    inode_start: u64,
+    first_data: u32,
+    // operations touch several blocks that must change together: bitmaps, inodes and
+    // directories
+    lock: Spinlock<()>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct DiskInode {
+    mode: u16,
+    nlinks: u16,
+    uid: u16,
+    gid: u16,
+    size: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    zones: [u32; 10],
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+impl DiskInode {
+    fn parse(bytes: &[u8; INODE_SIZE as usize]) -> Self {
+        let mut zones = [0; 10];
+        for (i, zone) in zones.iter_mut().enumerate() {
+            *zone = u32_at(bytes, 24 + i * 4);
+        }
+        DiskInode {
+            mode: u16_at(bytes, 0),
+            nlinks: u16_at(bytes, 2),
+            uid: u16_at(bytes, 4),
+            gid: u16_at(bytes, 6),
+            size: u32_at(bytes, 8),
+            atime: u32_at(bytes, 12),
+            mtime: u32_at(bytes, 16),
+            ctime: u32_at(bytes, 20),
+            zones,
+        }
+    }
+    fn to_bytes(self) -> [u8; INODE_SIZE as usize] {
+        let mut bytes = [0; INODE_SIZE as usize];
+        bytes[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.nlinks.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.atime.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.mtime.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.ctime.to_le_bytes());
+        for (i, zone) in self.zones.iter().enumerate() {
+            bytes[24 + i * 4..][..4].copy_from_slice(&zone.to_le_bytes());
+        }
+        bytes
+    }
+    fn is_dir(&self) -> bool {
+        self.mode as u32 & S_IFMT == S_IFDIR
+    }
+}
+
+impl Minix {
+    /// Reads the superblock of `disk`, fails with Corrupt if it isn't a Minix v3 filesystem.
+    pub fn mount(disk: usize) -> Result<Self, FsError> {
+        let mut sb = [0; 32];
+        bcache::read(disk, SUPERBLOCK, &mut sb)?;
+        if u16_at(&sb, 24) != MAGIC {
+            return Err(FsError::Corrupt);
+        }
+        let block_size = u16_at(&sb, 28) as u64;
+        // zones bigger than blocks went out of use with Minix v1
+        if !matches!(block_size, 1024 | 2048 | 4096) || u16_at(&sb, 12) != 0 {
+            return Err(FsError::Corrupt);
+        }
+        let imap_blocks = u16_at(&sb, 6) as u64;
+        let zmap_blocks = u16_at(&sb, 8) as u64;
+        let volume = Volume {
+            disk,
+            block_size,
+            ninodes: u32_at(&sb, 0),
+            zones: u32_at(&sb, 20),
+            imap_start: 2,
+            imap_blocks,
+            zmap_start: 2 + imap_blocks,
+            zmap_blocks,
+            inode_start: 2 + imap_blocks + zmap_blocks,
+            first_data: u16_at(&sb, 10) as u32,
+            lock: Spinlock::new(()),
+        };
+        if !volume.inode(ROOT_INO)?.is_dir() {
+            return Err(FsError::Corrupt);
+        }
+        println!(
+            "minix: disk {}, {} inodes, {} zones of {} bytes",
+            disk, volume.ninodes, volume.zones, block_size
+        );
+        Ok(Minix(Arc::new(volume)))
+    }
+}
+
+impl Filesystem for Minix {
+    fn name(&self) -> &'static str {
+        "minix"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.0.clone(),
+            ino: ROOT_INO,
+        })
+    }
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(bcache::read(self.disk, offset, buf)?)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        Ok(bcache::write(self.disk, offset, buf)?)
+    }
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut word = [0; 4];
+        self.read(offset, &mut word)?;
+        Ok(u32::from_le_bytes(word))
+    }
+    fn inode_offset(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.ninodes {
+            return Err(FsError::Corrupt);
+        }
+        Ok(self.inode_start * self.block_size + (ino - 1) as u64 * INODE_SIZE)
+    }
+    fn inode(&self, ino: u32) -> Result<DiskInode, FsError> {
+        let mut bytes = [0; INODE_SIZE as usize];
+        self.read(self.inode_offset(ino)?, &mut bytes)?;
+        Ok(DiskInode::parse(&bytes))
+    }
+    fn write_inode(&self, ino: u32, inode: &DiskInode) -> Result<(), FsError> {
+        self.write(self.inode_offset(ino)?, &inode.to_bytes())
+    }
+
+    /// Sets the first clear bit below `limit` of the bitmap at block `start`, returns its
+    /// number.
+    fn alloc_bit(&self, start: u64, blocks: u64, limit: u32) -> Result<u32, FsError> {
+        let mut buf = Vec::new();
+        buf.resize(self.block_size as usize, 0);
+        for block in 0..blocks {
+            let offset = (start + block) * self.block_size;
+            self.read(offset, &mut buf)?;
+            if let Some(i) = buf.iter().position(|&byte| byte != 0xff) {
+                let bit = buf[i].trailing_ones();
+                let n = ((block * self.block_size + i as u64) * 8) as u32 + bit;
+                if n >= limit {
+                    break;
+                }
+                self.write(offset + i as u64, &[buf[i] | 1 << bit])?;
+                return Ok(n);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+    fn free_bit(&self, start: u64, n: u32) -> Result<(), FsError> {
+        let offset = start * self.block_size + n as u64 / 8;
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        byte[0] &= !(1 << (n % 8));
+        self.write(offset, &byte)
+    }
+    fn alloc_inode(&self) -> Result<u32, FsError> {
+        self.alloc_bit(self.imap_start, self.imap_blocks, self.ninodes + 1)
+    }
+    /// Allocates a zone and clears it, the zones of holes and indirect blocks must read as 0.
+    fn alloc_zone(&self) -> Result<u32, FsError> {
+        // bit 0 stands for no zone, bit 1 for the first data zone
+        let limit = self.zones - self.first_data + 1;
+        let zone = self.alloc_bit(self.zmap_start, self.zmap_blocks, limit)? + self.first_data - 1;
+        let mut zeros = Vec::new();
+        zeros.resize(self.block_size as usize, 0);
+        self.write(zone as u64 * self.block_size, &zeros)?;
+        Ok(zone)
+    }
+    fn free_zone(&self, zone: u32) -> Result<(), FsError> {
+        if zone < self.first_data || zone >= self.zones {
+            return Err(FsError::Corrupt);
+        }
+        self.free_bit(self.zmap_start, zone - self.first_data + 1)
+    }
+
+    /// Entries of an indirect block.
+    fn per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+    /// Returns the zone in `slot`, after allocating one if it is empty and `allocate` is set.
+    fn slot(&self, slot: &mut u32, allocate: bool) -> Result<u32, FsError> {
+        if *slot == 0 && allocate {
+            *slot = self.alloc_zone()?;
+        }
+        Ok(*slot)
+    }
+    /// Like slot, for entry `index` of the indirect block `block`.
+    fn indirect(&self, block: u32, index: u64, allocate: bool) -> Result<u32, FsError> {
+        let offset = block as u64 * self.block_size + index * 4;
+        let mut zone = self.read_u32(offset)?;
+        if zone == 0 && allocate {
+            zone = self.alloc_zone()?;
+            self.write(offset, &zone.to_le_bytes())?;
+        }
+        Ok(zone)
+    }
+    /// The zone holding block `index` of the file, 0 for a hole unless `allocate` is set.
+    /// Allocating may change the zones of `inode`, the caller writes it back.
+    fn bmap(&self, inode: &mut DiskInode, index: u64, allocate: bool) -> Result<u32, FsError> {
+        let per = self.per_block();
+        if index < DIRECT as u64 {
+            return self.slot(&mut inode.zones[index as usize], allocate);
+        }
+        let index = index - DIRECT as u64;
+        if index < per {
+            let block = self.slot(&mut inode.zones[INDIRECT], allocate)?;
+            if block == 0 {
+                return Ok(0);
+            }
+            return self.indirect(block, index, allocate);
+        }
+        let index = index - per;
+        if index < per * per {
+            let outer = self.slot(&mut inode.zones[DOUBLE_INDIRECT], allocate)?;
+            if outer == 0 {
+                return Ok(0);
+            }
+            let block = self.indirect(outer, index / per, allocate)?;
+            if block == 0 {
+                return Ok(0);
+            }
+            return self.indirect(block, index % per, allocate);
+        }
+        Err(FsError::TooBig)
+    }
+    fn max_size(&self) -> u64 {
+        let per = self.per_block();
+        let blocks = DIRECT as u64 + per + per * per;
+        core::cmp::min(blocks * self.block_size, u32::MAX as u64)
+    }
+
+    fn read_data(&self, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = inode.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut inode = *inode;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = position % self.block_size;
+            let chunk = core::cmp::min(len - done, (self.block_size - start) as usize);
+            let zone = self.bmap(&mut inode, position / self.block_size, false)?;
+            let buf = &mut buf[done..done + chunk];
+            if zone == 0 {
+                buf.fill(0);
+            } else {
+                self.read(zone as u64 * self.block_size + start, buf)?;
+            }
+            done += chunk;
+        }
+        // the next block of the file need not follow this one on the disk
+        let next = (offset + len as u64).div_ceil(self.block_size);
+        if next * self.block_size < size {
+            let zone = self.bmap(&mut inode, next, false)?;
+            if zone != 0 {
+                let block = zone as u64 * self.block_size / bcache::BLOCK_SIZE as u64;
+                bcache::read_ahead(self.disk, block);
+            }
+        }
+        Ok(len)
+    }
+    /// Writes at `offset`, allocating the zones it needs, and stores the inode.
+    fn write_data(
+        &self,
+        ino: u32,
+        inode: &mut DiskInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, FsError> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::TooBig)?;
+        if end > self.max_size() {
+            return Err(FsError::TooBig);
+        }
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = position % self.block_size;
+            let chunk = core::cmp::min(buf.len() - done, (self.block_size - start) as usize);
+            let written = self
+                .bmap(inode, position / self.block_size, true)
+                .and_then(|zone| {
+                    self.write(
+                        zone as u64 * self.block_size + start,
+                        &buf[done..done + chunk],
+                    )
+                });
+            if let Err(e) = written {
+                result = Err(e);
+                break;
+            }
+            done += chunk;
+        }
+        // what was written before running out of space is kept
+        inode.size = core::cmp::max(inode.size, (offset + done as u64) as u32);
+        self.write_inode(ino, inode)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+    fn free_indirect(&self, block: u32, depth: u32) -> Result<(), FsError> {
+        for i in 0..self.per_block() {
+            let zone = self.read_u32(block as u64 * self.block_size + i * 4)?;
+            if zone == 0 {
+                continue;
+            }
+            if depth > 1 {
+                self.free_indirect(zone, depth - 1)?;
+            } else {
+                self.free_zone(zone)?;
+            }
+        }
+        self.free_zone(block)
+    }
+    /// Frees every zone of the file and empties it.
+    fn free_data(&self, inode: &mut DiskInode) -> Result<(), FsError> {
+        for &zone in inode.zones[..DIRECT].iter().filter(|&&z| z != 0) {
+            self.free_zone(zone)?;
+        }
+        if inode.zones[INDIRECT] != 0 {
+            self.free_indirect(inode.zones[INDIRECT], 1)?;
+        }
+        if inode.zones[DOUBLE_INDIRECT] != 0 {
+            self.free_indirect(inode.zones[DOUBLE_INDIRECT], 2)?;
+        }
+        inode.zones = [0; 10];
+        inode.size = 0;
+        Ok(())
+    }
+
+    /// Calls `f` with the offset, inode number and name of the used entries of the directory
+    /// `dir` until it returns Some.
+    fn scan<R>(
+        &self,
+        dir: &DiskInode,
+        mut f: impl FnMut(u64, u32, &[u8]) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut entry = [0; DIRENT_SIZE as usize];
+        let mut offset = 0;
+        while self.read_data(dir, offset, &mut entry)? == entry.len() {
+            let ino = u32_at(&entry, 0);
+            let name = &entry[4..];
+            let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN)];
+            if ino != 0 {
+                if let Some(res) = f(offset, ino, name) {
+                    return Ok(Some(res));
+                }
+            }
+            offset += DIRENT_SIZE;
+        }
+        Ok(None)
+    }
+    fn find(&self, dir: &DiskInode, name: &str) -> Result<Option<(u64, u32)>, FsError> {
+        self.scan(dir, |offset, ino, entry| {
+            (entry == name.as_bytes()).then_some((offset, ino))
+        })
+    }
+    /// Adds an entry to the directory `dir`, in the first unused slot or at its end.
+    fn add_entry(
+        &self,
+        dir_ino: u32,
+        dir: &mut DiskInode,
+        name: &str,
+        ino: u32,
+    ) -> Result<(), FsError> {
+        let mut free = dir.size as u64;
+        let mut entry = [0; DIRENT_SIZE as usize];
+        let mut offset = 0;
+        while self.read_data(dir, offset, &mut entry)? == entry.len() {
+            if u32_at(&entry, 0) == 0 {
+                free = offset;
+                break;
+            }
+            offset += DIRENT_SIZE;
+        }
+        let mut entry = [0; DIRENT_SIZE as usize];
+        entry[..4].copy_from_slice(&ino.to_le_bytes());
+        entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+        self.write_data(dir_ino, dir, free, &entry).map(|_| ())
+    }
+}
+
+/// An inode of a mounted filesystem, read from the disk whenever it is used.
+struct Node {
+    volume: Arc<Volume>,
+    ino: u32,
+}
+
+impl Node {
+    fn dir(&self) -> Result<DiskInode, FsError> {
+        let dir = self.volume.inode(self.ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        Ok(dir)
+    }
+    fn node(&self, ino: u32) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.volume.clone(),
+            ino,
+        })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.inode(self.ino)?;
+        Ok(Stat {
+            ino: self.ino as u64,
+            mode: inode.mode as u32,
+            nlink: inode.nlinks as u32,
+            size: inode.size as u64,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.inode(self.ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        self.volume.read_data(&inode, offset, buf)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let _guard = self.volume.lock.lock();
+        let mut inode = self.volume.inode(self.ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        self.volume.write_data(self.ino, &mut inode, offset, buf)
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        match self.volume.find(&self.dir()?, name)? {
+            Some((_, ino)) => Ok(self.node(ino)),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, name: &str, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let mut dir = self.dir()?;
+        if name.len() > NAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        if volume.find(&dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+        let ino = volume.alloc_inode()?;
+        // there is no real-time clock to take the times from
+        let mut inode = DiskInode {
+            mode: mode as u16,
+            nlinks: 1,
+            ..Default::default()
+        };
+        let created = if inode.is_dir() {
+            // "." and ".." count as links of the directory and of its parent
+            inode.nlinks = 2;
+            volume
+                .add_entry(ino, &mut inode, ".", ino)
+                .and_then(|_| volume.add_entry(ino, &mut inode, "..", self.ino))
+        } else {
+            volume.write_inode(ino, &inode)
+        };
+        let added = created.and_then(|_| volume.add_entry(self.ino, &mut dir, name, ino));
+        if let Err(e) = added {
+            volume.free_data(&mut inode)?;
+            volume.write_inode(ino, &DiskInode::default())?;
+            volume.free_bit(volume.imap_start, ino)?;
+            return Err(e);
+        }
+        if inode.is_dir() {
+            dir.nlinks += 1;
+            volume.write_inode(self.ino, &dir)?;
+        }
+        Ok(self.node(ino))
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _guard = self.volume.lock.lock();
+        let volume = &self.volume;
+        let mut dir = self.dir()?;
+        let (offset, ino) = volume.find(&dir, name)?.ok_or(FsError::NotFound)?;
+        let mut inode = volume.inode(ino)?;
+        if inode.is_dir() {
+            let other = volume.scan(&inode, |_, _, entry| {
+                (entry != b"." && entry != b"..").then_some(())
+            })?;
+            if other.is_some() {
+                return Err(FsError::NotEmpty);
+            }
+            inode.nlinks = 0;
+            dir.nlinks = dir.nlinks.saturating_sub(1);
+        } else {
+            inode.nlinks = inode.nlinks.saturating_sub(1);
+        }
+        // clearing the inode number frees the entry, and stores the directory's inode
+        volume.write_data(self.ino, &mut dir, offset, &0u32.to_le_bytes())?;
+        if inode.nlinks == 0 {
+            volume.free_data(&mut inode)?;
+            inode.mode = 0;
+            volume.free_bit(volume.imap_start, ino)?;
+        }
+        volume.write_inode(ino, &inode)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _guard = self.volume.lock.lock();
+        let mut count = 0;
+        let found = self.volume.scan(&self.dir()?, |_, ino, name| {
+            count += 1;
+            (count > index).then(|| (ino, String::from_utf8_lossy(name).into_owned()))
+        })?;
+        match found {
+            Some((ino, name)) => Ok(Some(DirEntry {
+                ino: ino as u64,
+                mode: self.volume.inode(ino)?.mode as u32 & S_IFMT,
+                name,
+            })),
+            None => Ok(None),
+        }
+    }
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        // the target is the contents of the link
+        let link = self.create(name, S_IFLNK | 0o777)?;
+        link.write(0, target.as_bytes())?;
+        Ok(link)
+    }
+}
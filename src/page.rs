@@ -8,12 +8,9 @@ use core::ops::DerefMut;
 
 // ========================= PAGES =========================
 
-extern "C" {
-    static HEAP_START: usize;
-    static HEAP_SIZE: usize;
-}
-
 pub const PAGE_SIZE: usize = 1 << 12;
+/// Size of a leaf entry in a level 1 table.
+pub const MEGAPAGE_SIZE: usize = PAGE_SIZE << 9;
 
 #[repr(u8)]
 #[derive(PartialEq, Eq, Ord, PartialOrd)]
@@ -22,9 +19,11 @@ pub enum PageBits {
     Taken = 1,
     Last = 2,
 }
-#[repr(transparent)]
+#[repr(C)]
 pub struct Page {
     flags: PageBits,
+    // number of mappings sharing this page, pages shared after fork are copied on write
+    refs: u16,
 }
 
 pub struct Pmem {
@@ -32,6 +31,9 @@ pub struct Pmem {
     alloc_start: usize,
     _traits: PhantomData<*mut u8>,
 }
+
+// only shared between harts through the MM lock
+unsafe impl Send for Pmem {}
 #[non_exhaustive]
 pub struct IPage(usize, *mut u8);
 
@@ -61,25 +63,52 @@ impl Drop for IPage {
 }
 
 impl Pmem {
-    pub fn init() -> Pmem {
+    /// Hands out the pages between `start` and `end`, its descriptors take up the beginning of
+    /// the range.
+    pub fn init(start: usize, end: usize) -> Pmem {
         unsafe {
-            let num_pages = HEAP_SIZE / PAGE_SIZE;
-            let ptr = HEAP_START as *mut MaybeUninit<Page>;
+            // the descriptors and the alignment of the first page cost at most one more page
+            let num_pages = (end - start - PAGE_SIZE) / (PAGE_SIZE + core::mem::size_of::<Page>());
+            let ptr = start as *mut MaybeUninit<Page>;
             let descriptors: &'static mut [MaybeUninit<Page>] =
                 core::slice::from_raw_parts_mut(ptr, num_pages);
             for uninit in descriptors.iter_mut() {
-                uninit.write(Page { flags: Empty });
+                uninit.write(Page {
+                    flags: Empty,
+                    refs: 0,
+                });
             }
             let offset: isize = num_pages as isize * core::mem::size_of::<Page>() as isize;
             Pmem {
                 descriptors: core::mem::transmute::<_, &'static mut [Page]>(descriptors),
-                alloc_start: HEAP_START
+                alloc_start: start
                     + offset as usize
-                    + (-(HEAP_START as isize + offset)).rem_euclid(PAGE_SIZE as isize) as usize,
+                    + (-(start as isize + offset)).rem_euclid(PAGE_SIZE as isize) as usize,
                 _traits: PhantomData,
             }
         }
     }
+    /// End of the memory the pages are handed out from.
+    pub fn end(&self) -> usize {
+        self.alloc_start + self.descriptors.len() * PAGE_SIZE
+    }
+    /// Takes the pages overlapping `start..end` out of circulation for good, as one allocation
+    /// that is never freed.
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        let start = max(start, self.alloc_start);
+        let end = core::cmp::min(end, self.end());
+        if start >= end {
+            return;
+        }
+        let first = (start - self.alloc_start) / PAGE_SIZE;
+        let last = (end - 1 - self.alloc_start) / PAGE_SIZE;
+        for p in &mut self.descriptors[first..=last] {
+            assert!(p.flags == Empty, "reserving allocated memory");
+            p.flags = Taken;
+            p.refs = 1;
+        }
+        self.descriptors[last].flags = Last;
+    }
     pub fn descriptors(&self) -> &[Page] {
         self.descriptors
     }
@@ -102,7 +131,7 @@ impl Pmem {
         let mut physical = core::ptr::null_mut();
         for (i, p) in self.descriptors[..num_pages - pages].iter_mut().enumerate() {
             match p {
-                Page { flags: Empty } => {
+                Page { flags: Empty, .. } => {
                     found += 1;
                 }
                 _ => {
@@ -117,8 +146,13 @@ impl Pmem {
                 break;
             }
         }
-        for p in &mut self.descriptors[begin..][..found - 1] {
-            p.flags = Taken;
+        if begin != usize::MAX {
+            for p in &mut self.descriptors[begin..][..found - 1] {
+                p.flags = Taken;
+            }
+            for p in &mut self.descriptors[begin..][..found] {
+                p.refs = 1;
+            }
         }
         IPage(begin, physical)
     }
@@ -130,6 +164,7 @@ impl Pmem {
                 break;
             }
             p.flags = Empty;
+            p.refs = 0;
             index += 1;
         }
         assert!(
@@ -137,15 +172,40 @@ impl Pmem {
             "potential double-free detected"
         );
         self.descriptors[index].flags = Empty;
+        self.descriptors[index].refs = 0;
     }
     pub unsafe fn dealloc_phys(&mut self, phys: *mut u8) {
         assert_eq!(phys.align_offset(PAGE_SIZE), 0);
-        assert!((phys as usize) < HEAP_START + HEAP_SIZE);
+        assert!((phys as usize) < self.end());
         assert!((phys as usize) >= self.alloc_start);
         let index = (phys as usize - self.alloc_start) / PAGE_SIZE;
         let ip = IPage(index, phys);
         self.dealloc(ip)
     }
+    fn descriptor(&mut self, phys: *const u8) -> &mut Page {
+        assert_eq!(phys.align_offset(PAGE_SIZE), 0);
+        assert!((phys as usize) >= self.alloc_start);
+        let index = (phys as usize - self.alloc_start) / PAGE_SIZE;
+        &mut self.descriptors[index]
+    }
+    pub fn ref_count(&mut self, phys: *const u8) -> u16 {
+        self.descriptor(phys).refs
+    }
+    /// Adds a mapping to a single page allocation.
+    pub fn share(&mut self, phys: *const u8) {
+        let page = self.descriptor(phys);
+        assert!(page.flags == Last && page.refs > 0, "sharing a free page");
+        page.refs += 1;
+    }
+    /// Drops a mapping of a single page allocation, the page is freed with its last mapping.
+    pub unsafe fn release(&mut self, phys: *mut u8) {
+        let page = self.descriptor(phys);
+        assert!(page.refs > 0, "potential double-free detected");
+        page.refs -= 1;
+        if page.refs == 0 {
+            self.dealloc_phys(phys);
+        }
+    }
 }
 
 impl Display for Pmem {
@@ -204,6 +264,15 @@ pub struct Table {
 #[derive(Copy, Clone)]
 pub struct Entry(u64);
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StoreFault {
+    // the page is writable now, the store can be retried
+    Resolved,
+    NotCow,
+    // no memory for the copy
+    OutOfMemory,
+}
+
 #[allow(unused)]
 pub mod entry_bits {
     type Flag = u64;
@@ -217,6 +286,8 @@ pub mod entry_bits {
     pub const GLOBAL: Flag = 32;
     pub const ACCESS: Flag = 64;
     pub const DIRTY: Flag = 128;
+    // reserved for software (RSW)
+    pub const COW: Flag = 256;
 
     pub const READ_WRITE: Flag = READ | WRITE;
     pub const READ_EXECUTE: Flag = READ | EXECUTE;
@@ -224,6 +295,8 @@ pub mod entry_bits {
 }
 
 impl Table {
+    /// Maps `vaddr` to `paddr` with a page of `level`, returns false if memory for a table on
+    /// the way ran out. The tables allocated until then stay in place, unmap frees them.
     pub fn map(
         root: &mut Table,
         pmem: &mut Pmem,
@@ -231,7 +304,7 @@ impl Table {
         paddr: usize,
         bits: u64,
         level: usize,
-    ) {
+    ) -> bool {
         assert_eq!((vaddr as *const u8).align_offset(PAGE_SIZE), 0);
         assert_eq!((paddr as *const u8).align_offset(PAGE_SIZE), 0);
         assert_ne!(bits & entry_bits::RWE, 0);
@@ -243,9 +316,17 @@ impl Table {
         let mut current = root;
         for i in (level + 1..=2).rev() {
             let v = &mut current.entries[vpn[i]];
+            assert_eq!(
+                v.get_entry() & entry_bits::GLOBAL,
+                0,
+                "mapping into the kernel's tables"
+            );
+            assert!(!v.is_leaf(), "address already mapped by a larger page");
             if !v.is_valid() {
                 let page = pmem.zalloc(1);
-                assert!(page.available(), "out of memory");
+                if !page.available() {
+                    return false;
+                }
                 v.set_entry(page.leak() as u64 >> 2 | entry_bits::VALID);
             }
             let next = v.get_phys() as *mut Table;
@@ -253,19 +334,158 @@ impl Table {
         }
         let entry = (paddr >> 2) as u64 | bits | entry_bits::VALID;
         current.entries[vpn[level]].set_entry(entry);
+        true
     }
+    /// Releases every mapping and table below `self`, except the kernel's global ones.
     pub fn unmap(&mut self, pmem: &mut Pmem) {
         for entry in &mut self.entries {
+            if entry.get_entry() & entry_bits::GLOBAL != 0 {
+                continue;
+            }
             if entry.is_valid() && !entry.is_leaf() {
                 let next = entry.get_phys() as *mut Table;
-                unsafe { (*next).unmap(pmem) };
-            }
-            if entry.is_valid() {
                 unsafe {
-                    pmem.dealloc_phys(entry.get_phys() as *mut u8);
+                    (*next).unmap(pmem);
+                    pmem.dealloc_phys(next as *mut u8);
+                }
+            } else if entry.is_valid() {
+                unsafe {
+                    pmem.release(entry.get_phys() as *mut u8);
+                }
+            }
+        }
+    }
+    /// Maps the kernel into the empty root table `self`. Each of the kernel's level 1 tables is
+    /// copied, with its entries marked global, so a process can still map the 2 MiB regions the
+    /// kernel leaves free while unmap and duplicate recognize the kernel's entries. Returns
+    /// false if memory ran out, unmap frees the tables copied until then.
+    pub fn share_kernel(&mut self, kernel: &Table, pmem: &mut Pmem) -> bool {
+        for (entry, shared) in self.entries.iter_mut().zip(kernel.entries.iter()) {
+            if !shared.is_valid() {
+                continue;
+            }
+            assert!(!shared.is_leaf(), "kernel mapped with gigapages");
+            let page = pmem.zalloc(1);
+            if !page.available() {
+                return false;
+            }
+            let table = unsafe { &mut *(page.leak() as *mut Table) };
+            let level1 = unsafe { &*(shared.get_phys() as *const Table) };
+            for (dst, src) in table.entries.iter_mut().zip(level1.entries.iter()) {
+                if src.is_valid() {
+                    dst.set_entry(src.get_entry() | entry_bits::GLOBAL);
                 }
             }
+            entry.set_entry((table as *mut Table as u64) >> 2 | entry_bits::VALID);
+        }
+        true
+    }
+    /// Whether anything is mapped in the 2 MiB regions overlapping `start..end`.
+    pub fn maps_region(&self, start: usize, end: usize) -> bool {
+        let mut region = start & !(MEGAPAGE_SIZE - 1);
+        while region < end {
+            let entry = &self.entries[region >> 30 & 0x1ff];
+            if !entry.is_valid() {
+                // skip the rest of the gigabyte
+                region = (region | (1 << 30) - 1) + 1;
+                continue;
+            }
+            if entry.is_leaf() {
+                return true;
+            }
+            let level1 = unsafe { &*(entry.get_phys() as *const Table) };
+            if level1.entries[region >> 21 & 0x1ff].is_valid() {
+                return true;
+            }
+            region += MEGAPAGE_SIZE;
         }
+        false
+    }
+    /// Calls `f` with the address and entry of every user page mapped by `self`, in address
+    /// order.
+    pub fn user_pages(&self, f: &mut dyn FnMut(usize, u64)) {
+        self.user_pages_level(f, 2, 0)
+    }
+    fn user_pages_level(&self, f: &mut dyn FnMut(usize, u64), level: usize, base: usize) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !entry.is_valid() || entry.get_entry() & entry_bits::GLOBAL != 0 {
+                continue;
+            }
+            let vaddr = base | i << (12 + 9 * level);
+            if !entry.is_leaf() {
+                let next = unsafe { &*(entry.get_phys() as *const Table) };
+                next.user_pages_level(f, level - 1, vaddr);
+            } else if entry.get_entry() & entry_bits::USER != 0 {
+                f(vaddr, entry.get_entry());
+            }
+        }
+    }
+    /// Shares every user mapping of `self` with `dst`. Writable pages become read-only copy on
+    /// write pages in both tables, so the caller has to flush this table's TLB entries.
+    /// Returns false if memory for `dst`'s tables ran out, mappings made so far stay in `dst`.
+    pub fn duplicate(&mut self, dst: &mut Table, pmem: &mut Pmem) -> bool {
+        self.duplicate_level(dst, pmem, 2, 0)
+    }
+    fn duplicate_level(
+        &mut self,
+        dst: &mut Table,
+        pmem: &mut Pmem,
+        level: usize,
+        base: usize,
+    ) -> bool {
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            // dst has the kernel mapped already
+            if !entry.is_valid() || entry.get_entry() & entry_bits::GLOBAL != 0 {
+                continue;
+            }
+            let vaddr = base | i << (12 + 9 * level);
+            if !entry.is_leaf() {
+                let next = unsafe { &mut *(entry.get_phys() as *mut Table) };
+                if !next.duplicate_level(dst, pmem, level - 1, vaddr) {
+                    return false;
+                }
+                continue;
+            }
+            if entry.get_entry() & entry_bits::USER == 0 {
+                continue;
+            }
+            assert_eq!(level, 0, "user mappings larger than a page");
+            if entry.get_entry() & entry_bits::WRITE != 0 {
+                entry.set_entry(entry.get_entry() & !entry_bits::WRITE | entry_bits::COW);
+            }
+            let phys = entry.get_phys() as usize;
+            if !Table::map(dst, pmem, vaddr, phys, entry.get_entry() & 0x3ff, 0) {
+                return false;
+            }
+            pmem.share(phys as *const u8);
+        }
+        true
+    }
+    /// Resolves a store fault on a copy on write page. The caller has to flush the TLB entry for
+    /// `vaddr` once it is resolved.
+    pub fn copy_on_write(root: &mut Table, pmem: &mut Pmem, vaddr: usize) -> StoreFault {
+        let entry = match Table::lookup(root, vaddr) {
+            Some(entry) if entry.get_entry() & entry_bits::COW != 0 => entry,
+            _ => return StoreFault::NotCow,
+        };
+        let flags = entry.get_entry() & 0x3ff & !entry_bits::COW | entry_bits::WRITE;
+        let phys = entry.get_phys() as *mut u8;
+        if pmem.ref_count(phys) == 1 {
+            // every other sharer already took its own copy
+            entry.set_entry(entry.get_entry() & !0x3ff | flags);
+            return StoreFault::Resolved;
+        }
+        let page = pmem.alloc(1);
+        if !page.available() {
+            return StoreFault::OutOfMemory;
+        }
+        let page = page.leak();
+        unsafe {
+            page.copy_from_nonoverlapping(phys, PAGE_SIZE);
+            pmem.release(phys);
+        }
+        entry.set_entry((page as u64) >> 2 | flags);
+        StoreFault::Resolved
     }
     pub fn virt_to_phys(root: &Table, vaddr: *const u8) -> Option<usize> {
         let vaddr = vaddr as usize;
@@ -293,6 +513,27 @@ impl Table {
         }
         None
     }
+    pub fn lookup(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+        let vpn = [
+            vaddr >> 12 & 0x1ff,
+            vaddr >> 21 & 0x1ff,
+            vaddr >> 30 & 0x1ff,
+        ];
+        let mut current = root;
+        for i in (0..=2).rev() {
+            let entry = &mut current.entries[vpn[i]];
+            if !entry.is_valid() {
+                break;
+            }
+            if entry.is_leaf() {
+                return Some(entry);
+            }
+            assert!(i > 0, "more than three levels found");
+            let next = entry.get_phys() as *mut Table;
+            current = unsafe { &mut *next };
+        }
+        None
+    }
 }
 
 impl Entry {
@@ -318,7 +559,25 @@ pub fn id_map_range(root: &mut Table, alloc: &mut Pmem, start: usize, end: usize
     let pages = (end - addr).div_ceil(PAGE_SIZE);
     let pages = max(1, pages);
     for _ in 0..pages {
-        Table::map(root, alloc, addr, addr, bits, 0);
+        if !Table::map(root, alloc, addr, addr, bits, 0) {
+            panic!("out of memory");
+        }
         addr += 1 << 12;
     }
 }
+
+/// Like id_map_range, but maps the 2 MiB aligned parts of the range with megapages.
+pub fn id_map_range_large(root: &mut Table, alloc: &mut Pmem, start: usize, end: usize, bits: u64) {
+    let mut addr = start & !(PAGE_SIZE - 1);
+    while addr < end {
+        let level = if addr % MEGAPAGE_SIZE == 0 && end - addr >= MEGAPAGE_SIZE {
+            1
+        } else {
+            0
+        };
+        if !Table::map(root, alloc, addr, addr, bits, level) {
+            panic!("out of memory");
+        }
+        addr += if level == 1 { MEGAPAGE_SIZE } else { PAGE_SIZE };
+    }
+}
@@ -1,11 +1,115 @@
+extern crate alloc;
 use crate::cpu::TrapFrame;
-use crate::page::IPage;
-use crate::{cpu, get_mm, page, Pmem, Table, PAGE_SIZE};
+use crate::elf::{Elf, ElfError, Segment};
+use crate::page::{entry_bits, IPage, StoreFault};
+use crate::vfs::{self, File, FsError, Inode};
+use crate::{cpu, entropy, get_mm, kmem, page, timer, Pmem, Table, PAGE_SIZE};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 use core::ops::DerefMut;
 
 const STACK_PAGES: usize = 2;
-const START_ADDR: usize = 0x2000_0000;
-const STACK_ADDR: usize = 0xf_0000_0000;
+pub const STACK_ADDR: usize = 0xf_0000_0000;
+pub const STACK_END: usize = STACK_ADDR + STACK_PAGES * PAGE_SIZE;
+pub const INIT_PID: u16 = 1;
+/// Size of the file descriptor table.
+pub const MAX_FILES: usize = 16;
+
+// auxiliary vector entries of the initial stack
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+extern "C" {
+    static INIT_ELF_START: usize;
+    static INIT_ELF_END: usize;
+}
+
+/// The built-in init program linked into the kernel image.
+pub fn init_image() -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts(INIT_ELF_START as *const u8, INIT_ELF_END - INIT_ELF_START)
+    }
+}
+
+const NO_FILE: Option<Arc<File>> = None;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExecError {
+    Elf(ElfError),
+    File(FsError),
+    NotExecutable,
+    OutOfMemory,
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for ExecError {
+    fn from(e: ElfError) -> Self {
+        ExecError::Elf(e)
+    }
+}
+
+impl From<FsError> for ExecError {
+    fn from(e: FsError) -> Self {
+        ExecError::File(e)
+    }
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ExecError::Elf(e) => e.fmt(f),
+            ExecError::File(e) => e.fmt(f),
+            ExecError::NotExecutable => write!(f, "not a regular file"),
+            ExecError::OutOfMemory => write!(f, "not enough memory for the new image"),
+            ExecError::ArgumentsTooLong => write!(f, "arguments do not fit on the stack"),
+        }
+    }
+}
+
+/// An executable read from a file into physical pages, images may well be larger than the
+/// kernel heap.
+pub struct Image {
+    pages: IPage,
+    len: usize,
+}
+
+impl Image {
+    pub fn load(inode: &dyn Inode) -> Result<Self, ExecError> {
+        let stat = inode.stat()?;
+        if stat.mode & vfs::S_IFMT != vfs::S_IFREG {
+            return Err(ExecError::NotExecutable);
+        }
+        if !stat.allows(vfs::S_IXUSR) {
+            return Err(FsError::Denied.into());
+        }
+        let len = stat.size as usize;
+        // at least one page, an empty allocation would not be one
+        let pages = get_mm().alloc(core::cmp::max(len.div_ceil(PAGE_SIZE), 1));
+        if !pages.available() {
+            return Err(ExecError::OutOfMemory);
+        }
+        let mut image = Image { pages, len: 0 };
+        let buf =
+            unsafe { core::slice::from_raw_parts_mut(image.pages.physical() as *mut u8, len) };
+        while image.len < len {
+            match inode.read(image.len as u64, &mut buf[image.len..])? {
+                0 => break,
+                read => image.len += read,
+            }
+        }
+        Ok(image)
+    }
+    pub fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.pages.physical(), self.len) }
+    }
+}
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum ProcessState {
@@ -15,64 +119,234 @@ pub enum ProcessState {
     Dead,
 }
 
+impl Display for ProcessState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            ProcessState::Running => "R (running)",
+            ProcessState::Sleeping => "S (sleeping)",
+            ProcessState::Waiting => "S (waiting)",
+            ProcessState::Dead => "X (dead)",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
 pub struct Process {
     frame: TrapFrame,
-    stack: IPage,
     pc: usize,
     pid: u16,
+    parent: u16,
     root: *mut Table,
     state: ProcessState,
     sleep_until: usize,
+    channel: usize,
+    nice: i8,
+    // bit n set: may run on hart n
+    affinity: usize,
+    // by descriptor, shared with the children forked after they were opened
+    files: [Option<Arc<File>>; MAX_FILES],
+    // canonical absolute path
+    cwd: String,
 }
 
 impl Process {
-    pub fn new(func: fn()) -> Self {
-        static mut NEXT_PID: u16 = 0;
-        let mut pm = get_mm();
-        let pm = pm.deref_mut();
-        let mut res = Self {
+    fn empty(pc: usize, pid: u16) -> Option<Self> {
+        let root = new_table(get_mm().deref_mut())?;
+        Some(Self {
             frame: cpu::TrapFrame::zero(),
-            stack: pm.alloc(STACK_PAGES),
-            pc: START_ADDR | (func as usize & 0xfff),
-            pid: unsafe { NEXT_PID },
-            root: pm.zalloc(1).leak() as *mut Table,
+            pc,
+            pid,
+            parent: INIT_PID,
+            root,
             state: ProcessState::Running,
             sleep_until: 0,
+            channel: 0,
+            nice: 0,
+            affinity: (1 << cpu::MAX_HARTS) - 1,
+            files: [NO_FILE; MAX_FILES],
+            cwd: String::from("/"),
+        })
+    }
+    pub fn from_elf(image: &[u8], pid: u16) -> Result<Self, ElfError> {
+        let elf = parse_image(image)?;
+        let mut res = Self::empty(elf.entry(), pid).ok_or(ElfError::OutOfMemory)?;
+        res.frame.regs[2] = STACK_END; // set sp
+
+        // on error the partially built address space is released by Drop, outside of the borrow
+        let loaded = {
+            let mut pm = get_mm();
+            let pm = pm.deref_mut();
+            let table = unsafe { &mut *res.root };
+            elf.load(table, pm).and_then(|_| map_stack(table, pm))
+        };
+        loaded.map(|_| res)
+    }
+    /// Creates the child `pid` sharing this process' pages copy on write, with a copy of its
+    /// registers, resuming at `pc`. The child sees 0 as the return value in a0. None if memory
+    /// ran out.
+    pub fn fork(&mut self, pc: usize, pid: u16) -> Option<Self> {
+        let mut child = Self::empty(pc, pid)?;
+        child.parent = self.pid;
+        child.nice = self.nice;
+        child.affinity = self.affinity;
+        child.files = self.files.clone();
+        child.cwd = self.cwd.clone();
+        child.frame = self.frame;
+        child.frame.regs[10] = 0;
+        let copied = {
+            let mut pm = get_mm();
+            let pm = pm.deref_mut();
+            let table = unsafe { &mut *self.root };
+            table.duplicate(child.get_table(), pm)
+        };
+        // writable pages of the parent just became read-only
+        cpu::satp_fence_asid(self.pid as usize);
+        if copied {
+            Some(child)
+        } else {
+            None
+        }
+    }
+    /// Replaces the address space with `image`, started with `argv` and `envp` on a System V
+    /// initial stack. On error the old image is left untouched.
+    pub fn exec(
+        &mut self,
+        image: &[u8],
+        argv: &[Vec<u8>],
+        envp: &[Vec<u8>],
+    ) -> Result<(), ExecError> {
+        let elf = parse_image(image)?;
+        let root = new_table(get_mm().deref_mut()).ok_or(ExecError::OutOfMemory)?;
+        let table = unsafe { &mut *root };
+        let loaded = {
+            let mut pm = get_mm();
+            let pm = pm.deref_mut();
+            elf.load(table, pm).and_then(|_| map_stack(table, pm))
+        };
+        let sp = match loaded {
+            Ok(()) => build_stack(table, &elf, argv, envp),
+            Err(e) => Err(e.into()),
         };
-        unsafe { NEXT_PID += 1 };
-        res.frame.regs[2] = STACK_ADDR + PAGE_SIZE * STACK_PAGES; // set sp
-        let table = unsafe { &mut *res.root };
-        let stack_top = res.stack.physical() as usize;
-
-        for i in 0..STACK_PAGES {
-            Table::map(
-                table,
-                pm,
-                STACK_ADDR + i * PAGE_SIZE,
-                stack_top + i * PAGE_SIZE,
-                page::entry_bits::READ_WRITE | page::entry_bits::USER,
-                0,
-            );
-        }
-        let func = (func as usize) & !0xfff;
-        Table::map(
-            table,
-            pm,
-            START_ADDR,
-            func,
-            page::entry_bits::USER | page::entry_bits::READ_EXECUTE,
-            0,
-        );
-        Table::map(
-            table,
-            pm,
-            START_ADDR + 0x1000,
-            func + 0x1000,
-            page::entry_bits::USER | page::entry_bits::READ_EXECUTE,
-            0,
-        );
-
-        res
+        let sp = match sp {
+            Ok(sp) => sp,
+            Err(e) => {
+                free_table(root);
+                return Err(e);
+            }
+        };
+
+        // swapping the contents keeps the root's address, and with it satp, unchanged. The old
+        // tables are only freed once the TLB no longer refers to them.
+        unsafe { core::ptr::swap(self.root, root) };
+        cpu::satp_fence_asid(self.pid as usize);
+        free_table(root);
+        self.frame.regs = [0; 32];
+        self.frame.fregs = [0; 32];
+        self.frame.regs[2] = sp;
+        self.pc = elf.entry();
+        Ok(())
+    }
+    /// Handles a store page fault at `vaddr` by copying the page if it is copy on write.
+    pub fn store_fault(&mut self, vaddr: usize) -> StoreFault {
+        let page = vaddr & !(PAGE_SIZE - 1);
+        let resolved = {
+            let mut pm = get_mm();
+            Table::copy_on_write(self.get_table(), pm.deref_mut(), page)
+        };
+        if resolved == StoreFault::Resolved {
+            cpu::satp_fence(page, self.pid as usize);
+        }
+        resolved
+    }
+    /// Copies `data` to the user address `vaddr`, copying copy on write pages first.
+    /// Returns false if part of the range isn't mapped writable for the user.
+    pub fn copy_out(&mut self, vaddr: usize, data: &[u8]) -> bool {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = match vaddr.checked_add(done) {
+                Some(addr) => addr,
+                None => return false,
+            };
+            let cow = match Table::lookup(self.get_table(), addr & !(PAGE_SIZE - 1)) {
+                Some(entry) => entry.get_entry() & entry_bits::COW != 0,
+                None => return false,
+            };
+            if cow && self.store_fault(addr) != StoreFault::Resolved {
+                return false;
+            }
+            let len = core::cmp::min(data.len() - done, PAGE_SIZE - addr % PAGE_SIZE);
+            match user_page(self.get_table(), addr, entry_bits::WRITE) {
+                Some(phys) => unsafe {
+                    phys.copy_from_nonoverlapping(data[done..].as_ptr(), len);
+                },
+                None => return false,
+            }
+            done += len;
+        }
+        true
+    }
+    /// Fills `buf` from the user address `vaddr`, returns false if part of the range isn't
+    /// mapped readable for the user.
+    pub fn copy_in(&mut self, vaddr: usize, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = match vaddr.checked_add(done) {
+                Some(addr) => addr,
+                None => return false,
+            };
+            let len = core::cmp::min(buf.len() - done, PAGE_SIZE - addr % PAGE_SIZE);
+            match user_page(self.get_table(), addr, entry_bits::READ) {
+                Some(phys) => unsafe {
+                    buf[done..].as_mut_ptr().copy_from_nonoverlapping(phys, len);
+                },
+                None => return false,
+            }
+            done += len;
+        }
+        true
+    }
+    /// Reads a NUL terminated string of at most `max` bytes from the user address `vaddr`.
+    pub fn read_str(&mut self, vaddr: usize, max: usize) -> Option<Vec<u8>> {
+        let mut res = Vec::new();
+        loop {
+            let mut c = [0];
+            if res.len() >= max || !self.copy_in(vaddr.checked_add(res.len())?, &mut c) {
+                return None;
+            }
+            if c[0] == 0 {
+                return Some(res);
+            }
+            res.push(c[0]);
+        }
+    }
+    /// Puts the process to sleep until mtime reaches `until`.
+    pub fn sleep(&mut self, until: usize) {
+        self.state = ProcessState::Sleeping;
+        self.sleep_until = until;
+    }
+    /// Makes a sleeping process runnable again once its deadline passed.
+    pub fn wake_at(&mut self, now: usize) {
+        if self.state == ProcessState::Sleeping && self.sleep_until <= now {
+            self.state = ProcessState::Running;
+        }
+    }
+    /// Puts the process to sleep until `channel` is woken.
+    pub fn block(&mut self, channel: usize) {
+        self.state = ProcessState::Waiting;
+        self.channel = channel;
+    }
+    /// Makes the process runnable if it is blocked on `channel`, returns whether it was.
+    pub fn wake(&mut self, channel: usize) -> bool {
+        if self.state == ProcessState::Waiting && self.channel == channel {
+            self.state = ProcessState::Running;
+            self.channel = 0;
+            return true;
+        }
+        false
+    }
+    /// Channel other processes wake when one of this process' children exits.
+    pub fn child_channel(&self) -> usize {
+        self as *const Process as usize
     }
     pub fn get_frame(&mut self) -> &mut TrapFrame {
         &mut self.frame
@@ -80,6 +354,9 @@ impl Process {
     pub fn get_pc(&self) -> usize {
         self.pc
     }
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
     pub fn get_table(&mut self) -> &mut Table {
         unsafe { &mut *self.root }
     }
@@ -89,17 +366,209 @@ impl Process {
     pub fn get_pid(&self) -> u16 {
         self.pid
     }
+    pub fn get_parent(&self) -> u16 {
+        self.parent
+    }
+    pub fn set_parent(&mut self, parent: u16) {
+        self.parent = parent;
+    }
     pub fn get_sleep_until(&self) -> usize {
         self.sleep_until
     }
+    pub fn get_nice(&self) -> i8 {
+        self.nice
+    }
+    pub fn set_nice(&mut self, nice: i8) {
+        self.nice = nice;
+    }
+    pub fn set_affinity(&mut self, mask: usize) {
+        self.affinity = mask;
+    }
+    pub fn runs_on(&self, hart: usize) -> bool {
+        self.affinity & 1 << hart != 0
+    }
+    /// Puts `file` in the lowest free slot of the file table, returns its descriptor.
+    pub fn install_file(&mut self, file: File) -> Option<usize> {
+        let fd = self.files.iter().position(Option::is_none)?;
+        self.files[fd] = Some(Arc::new(file));
+        Some(fd)
+    }
+    pub fn get_file(&self, fd: usize) -> Option<Arc<File>> {
+        self.files.get(fd)?.clone()
+    }
+    /// Releases descriptor `fd`, returns false if it wasn't open.
+    pub fn close_file(&mut self, fd: usize) -> bool {
+        self.files.get_mut(fd).and_then(Option::take).is_some()
+    }
+    pub fn get_affinity(&self) -> usize {
+        self.affinity
+    }
+    /// Number of open file descriptors.
+    pub fn open_files(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+    /// The user mappings as runs of pages with the same permissions: start, end and the
+    /// READ, WRITE and EXECUTE bits. Copy on write pages count as writable.
+    pub fn regions(&mut self) -> Vec<(usize, usize, u64)> {
+        let mut regions: Vec<(usize, usize, u64)> = Vec::new();
+        self.get_table().user_pages(&mut |vaddr, entry| {
+            let mut bits = entry & entry_bits::RWE;
+            if entry & entry_bits::COW != 0 {
+                bits |= entry_bits::WRITE;
+            }
+            match regions.last_mut() {
+                Some((_, end, last)) if *end == vaddr && *last == bits => *end += PAGE_SIZE,
+                _ => regions.push((vaddr, vaddr + PAGE_SIZE, bits)),
+            }
+        });
+        regions
+    }
+    pub fn get_cwd(&self) -> &str {
+        &self.cwd
+    }
+    pub fn set_cwd(&mut self, cwd: String) {
+        self.cwd = cwd;
+    }
+}
+
+fn parse_image(image: &[u8]) -> Result<Elf<'_>, ElfError> {
+    let elf = Elf::parse(image)?;
+    // the kernel is mapped into every address space
+    if elf.segments().filter(Segment::is_load).any(|s| {
+        s.vaddr < STACK_END && s.end() > STACK_ADDR
+            || kmem::kernel_table().maps_region(s.vaddr, s.end())
+    }) {
+        return Err(ElfError::ReservedAddress);
+    }
+    Ok(elf)
+}
+
+/// Translates `vaddr` if it is mapped for the user with all of `bits`.
+fn user_page(root: &mut Table, vaddr: usize, bits: u64) -> Option<*mut u8> {
+    let entry = Table::lookup(root, vaddr & !(PAGE_SIZE - 1))?.get_entry();
+    if entry & (bits | entry_bits::USER) != bits | entry_bits::USER {
+        return None;
+    }
+    Table::virt_to_phys(root, vaddr as *const u8).map(|phys| phys as *mut u8)
+}
+
+/// Lays out argc, argv, envp and the auxiliary vector below the strings they point to at the
+/// top of the stack, and returns the 16 byte aligned stack pointer pointing at argc.
+fn build_stack(
+    table: &mut Table,
+    elf: &Elf<'_>,
+    argv: &[Vec<u8>],
+    envp: &[Vec<u8>],
+) -> Result<usize, ExecError> {
+    // 16 bytes for AT_RANDOM
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>() + 16;
+    let mut auxv = Vec::with_capacity(8);
+    if let Some(phdr) = elf.phdr_address() {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, elf.phent_size()));
+    auxv.push((AT_PHNUM, elf.segments().count()));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, elf.entry()));
+    auxv.push((AT_RANDOM, STACK_END - 16));
+    auxv.push((AT_NULL, 0));
+    let pointers = (1 + argv.len() + 1 + envp.len() + 1 + 2 * auxv.len()) * 8;
+    let sp = match STACK_END.checked_sub(strings + pointers) {
+        Some(sp) if sp & !0xf >= STACK_ADDR => sp & !0xf,
+        _ => return Err(ExecError::ArgumentsTooLong),
+    };
+
+    let mut stack = Vec::with_capacity(STACK_END - sp);
+    stack.resize(STACK_END - sp, 0);
+    let mut words = Vec::with_capacity(pointers / 8);
+    let mut string_addr = sp + pointers;
+    let mut push_strings = |words: &mut Vec<usize>, strings: &[Vec<u8>]| {
+        for s in strings {
+            let offset = string_addr - sp;
+            stack[offset..offset + s.len()].copy_from_slice(s);
+            words.push(string_addr);
+            string_addr += s.len() + 1;
+        }
+        words.push(0);
+    };
+    words.push(argv.len());
+    push_strings(&mut words, argv);
+    push_strings(&mut words, envp);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    for (i, word) in words.iter().enumerate() {
+        stack[i * 8..][..8].copy_from_slice(&word.to_le_bytes());
+    }
+    // not cryptographically random, but unique enough for stack protector canaries, and
+    // random where the virtio-rng pools reach
+    let mut seed = timer::mtime() | 1;
+    for chunk in stack[STACK_END - 16 - sp..].chunks_mut(8) {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        chunk.copy_from_slice(&seed.to_le_bytes());
+    }
+    entropy::mix_into(&mut stack[STACK_END - 16 - sp..]);
+
+    let mut done = 0;
+    while done < stack.len() {
+        let addr = sp + done;
+        let len = core::cmp::min(stack.len() - done, PAGE_SIZE - addr % PAGE_SIZE);
+        let phys = user_page(table, addr, entry_bits::WRITE).expect("stack not mapped");
+        unsafe { phys.copy_from_nonoverlapping(stack[done..].as_ptr(), len) };
+        done += len;
+    }
+    Ok(sp)
+}
+
+/// Allocates the root table of a new address space, with the kernel mapped in. None if memory
+/// ran out.
+fn new_table(pm: &mut Pmem) -> Option<*mut Table> {
+    let root = pm.zalloc(1);
+    if !root.available() {
+        return None;
+    }
+    let root = root.leak() as *mut Table;
+    unsafe {
+        if (*root).share_kernel(kmem::kernel_table(), pm) {
+            return Some(root);
+        }
+        (*root).unmap(pm);
+        pm.dealloc_phys(root as *mut u8);
+    }
+    None
+}
+
+fn free_table(root: *mut Table) {
+    let mut pm = get_mm();
+    let pm = pm.deref_mut();
+    unsafe {
+        (*root).unmap(pm);
+        pm.dealloc_phys(root as *mut u8);
+    }
+}
+
+fn map_stack(table: &mut Table, pm: &mut Pmem) -> Result<(), ElfError> {
+    for i in 0..STACK_PAGES {
+        let page = pm.zalloc(1);
+        if !page.available() {
+            return Err(ElfError::OutOfMemory);
+        }
+        let page = page.leak();
+        let vaddr = STACK_ADDR + i * PAGE_SIZE;
+        let bits = page::entry_bits::READ_WRITE | page::entry_bits::USER;
+        if !Table::map(table, pm, vaddr, page as usize, bits, 0) {
+            unsafe { pm.dealloc_phys(page) };
+            return Err(ElfError::OutOfMemory);
+        }
+    }
+    Ok(())
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        let mut pm = get_mm();
-        let pm = pm.deref_mut();
-        let table = unsafe { &mut *self.root };
-        table.unmap(pm);
-        unsafe { pm.dealloc_phys(self.root as *mut u8) };
+        free_table(self.root);
     }
 }
@@ -0,0 +1,248 @@
+extern crate alloc;
+use crate::kmem::GA;
+use crate::page::entry_bits;
+use crate::process::{Process, MAX_FILES, STACK_ADDR, STACK_END};
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use crate::{bcache, get_mm, sched, trap, PAGE_SIZE};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::fmt::Write;
+
+/// Files showing what the kernel knows about its memory, interrupts and processes. Their
+/// contents are made up anew on every read.
+pub struct Procfs;
+
+#[derive(Copy, Clone)]
+enum Entry {
+    Root,
+    Meminfo,
+    Buddyinfo,
+    Interrupts,
+    Bcache,
+    // the reading process' directory
+    SelfLink,
+    Process(u16),
+    Status(u16),
+    Maps(u16),
+}
+
+const ROOT_FILES: [(&str, Entry); 5] = [
+    ("meminfo", Entry::Meminfo),
+    ("buddyinfo", Entry::Buddyinfo),
+    ("interrupts", Entry::Interrupts),
+    ("bcache", Entry::Bcache),
+    ("self", Entry::SelfLink),
+];
+
+/// The files in the directory of process `pid`.
+fn process_files(pid: u16) -> [(&'static str, Entry); 2] {
+    [("status", Entry::Status(pid)), ("maps", Entry::Maps(pid))]
+}
+
+impl Entry {
+    fn ino(self) -> u64 {
+        match self {
+            Entry::Root => 1,
+            Entry::Meminfo => 2,
+            Entry::Buddyinfo => 3,
+            Entry::Interrupts => 4,
+            Entry::Bcache => 5,
+            Entry::SelfLink => 6,
+            // every process gets a block of four from 8 on
+            Entry::Process(pid) => (pid as u64 + 2) << 2,
+            Entry::Status(pid) => (pid as u64 + 2) << 2 | 1,
+            Entry::Maps(pid) => (pid as u64 + 2) << 2 | 2,
+        }
+    }
+    fn mode(self) -> u32 {
+        match self {
+            Entry::Root | Entry::Process(_) => S_IFDIR | 0o555,
+            Entry::SelfLink => S_IFLNK | 0o777,
+            _ => S_IFREG | 0o444,
+        }
+    }
+    /// Formats the contents of the file into `out`.
+    fn show(self, out: &mut Window<'_>) -> Result<(), FsError> {
+        // the error of a full window only cuts the text short
+        let _ = match self {
+            Entry::Meminfo => write!(out, "{}", *get_mm()),
+            Entry::Buddyinfo => writeln!(out, "{}", GA),
+            Entry::Interrupts => write!(out, "{}", trap::Interrupts),
+            Entry::Bcache => write!(out, "{}", bcache::stats()),
+            Entry::Status(pid) => sched::find(pid, |p| status(p, out)).ok_or(FsError::NotFound)?,
+            Entry::Maps(pid) => sched::find(pid, |p| maps(p, out)).ok_or(FsError::NotFound)?,
+            Entry::SelfLink => return Err(FsError::Invalid),
+            Entry::Root | Entry::Process(_) => return Err(FsError::IsDir),
+        };
+        Ok(())
+    }
+}
+
+fn status(p: &mut Process, out: &mut Window<'_>) -> core::fmt::Result {
+    let pages: usize = p
+        .regions()
+        .iter()
+        .map(|(start, end, _)| (end - start) / PAGE_SIZE)
+        .sum();
+    writeln!(out, "Pid:\t{}", p.get_pid())?;
+    writeln!(out, "PPid:\t{}", p.get_parent())?;
+    writeln!(out, "State:\t{}", p.get_state())?;
+    writeln!(out, "Nice:\t{}", p.get_nice())?;
+    writeln!(out, "Cpus_allowed:\t{:x}", p.get_affinity())?;
+    writeln!(out, "VmSize:\t{} kB", pages * PAGE_SIZE / 1024)?;
+    writeln!(out, "FDSize:\t{}", MAX_FILES)?;
+    writeln!(out, "Files:\t{}", p.open_files())?;
+    writeln!(out, "Cwd:\t{}", p.get_cwd())
+}
+
+/// A line per run of pages with the same permissions, in the format of Linux without the
+/// offset, device and inode columns.
+fn maps(p: &mut Process, out: &mut Window<'_>) -> core::fmt::Result {
+    for (start, end, bits) in p.regions() {
+        let flag = |bit, c| if bits & bit != 0 { c } else { '-' };
+        write!(
+            out,
+            "{:08x}-{:08x} {}{}{}p",
+            start,
+            end,
+            flag(entry_bits::READ, 'r'),
+            flag(entry_bits::WRITE, 'w'),
+            flag(entry_bits::EXECUTE, 'x')
+        )?;
+        if start >= STACK_ADDR && end <= STACK_END {
+            write!(out, " [stack]")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Keeps the part of the formatted text from byte `skip` on that fits into `buf`, formatting
+/// is stopped with an error once it is full. Nothing is allocated, the allocators' own Display
+/// implementations hold their locks while writing.
+struct Window<'a> {
+    skip: u64,
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let skipped = core::cmp::min(self.skip, s.len() as u64) as usize;
+        self.skip -= skipped as u64;
+        let s = &s.as_bytes()[skipped..];
+        let len = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s[..len]);
+        self.len += len;
+        if self.len == self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+/// The PID a directory name stands for, written the way the kernel writes numbers.
+fn parse_pid(name: &str) -> Option<u16> {
+    if !name.bytes().all(|b| b.is_ascii_digit()) || name.len() > 1 && name.starts_with('0') {
+        return None;
+    }
+    name.parse().ok()
+}
+
+struct Node {
+    entry: Entry,
+}
+
+impl Node {
+    fn node(entry: Entry) -> Arc<dyn Inode> {
+        Arc::new(Node { entry })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let size = match self.entry {
+            Entry::SelfLink => self.readlink()?.len() as u64,
+            _ => 0,
+        };
+        Ok(Stat {
+            ino: self.entry.ino(),
+            mode: self.entry.mode(),
+            nlink: 1,
+            size,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut window = Window {
+            skip: offset,
+            buf,
+            len: 0,
+        };
+        self.entry.show(&mut window)?;
+        Ok(window.len)
+    }
+    fn write(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match self.entry {
+            Entry::Root => {
+                if let Some(&(_, entry)) = ROOT_FILES.iter().find(|(n, _)| *n == name) {
+                    return Ok(Node::node(entry));
+                }
+                let pid = parse_pid(name).ok_or(FsError::NotFound)?;
+                sched::find(pid, |_| ()).ok_or(FsError::NotFound)?;
+                Ok(Node::node(Entry::Process(pid)))
+            }
+            Entry::Process(pid) => match process_files(pid).iter().find(|(n, _)| *n == name) {
+                Some(&(_, entry)) => Ok(Node::node(entry)),
+                None => Err(FsError::NotFound),
+            },
+            _ => Err(FsError::NotDir),
+        }
+    }
+    fn create(&self, _name: &str, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let entry = match self.entry {
+            Entry::Root => match ROOT_FILES.get(index) {
+                Some(&(name, entry)) => Some((String::from(name), entry)),
+                // processes come and go between calls, one may be skipped or listed twice
+                None => sched::pids()
+                    .get(index - ROOT_FILES.len())
+                    .map(|&pid| (pid.to_string(), Entry::Process(pid))),
+            },
+            Entry::Process(pid) => process_files(pid)
+                .get(index)
+                .map(|&(name, entry)| (String::from(name), entry)),
+            _ => return Err(FsError::NotDir),
+        };
+        Ok(entry.map(|(name, entry)| DirEntry {
+            ino: entry.ino(),
+            mode: entry.mode() & S_IFMT,
+            name,
+        }))
+    }
+    fn readlink(&self) -> Result<String, FsError> {
+        match self.entry {
+            Entry::SelfLink => match sched::current() {
+                Some(p) => Ok(p.get_pid().to_string()),
+                None => Err(FsError::NotFound),
+            },
+            _ => Err(FsError::Invalid),
+        }
+    }
+}
+
+impl Filesystem for Procfs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Node::node(Entry::Root)
+    }
+}
@@ -0,0 +1,107 @@
+use core::arch::asm;
+use core::fmt::Write;
+
+// extension IDs, passed in a7
+pub const EXT_BASE: usize = 0x10;
+pub const EXT_TIME: usize = 0x5449_4d45;
+pub const EXT_IPI: usize = 0x73_5049;
+pub const EXT_HSM: usize = 0x48_534d;
+pub const EXT_SRST: usize = 0x5352_5354;
+// legacy extensions, they take no function ID and return their result in a0 alone
+pub const EXT_CONSOLE_PUTCHAR: usize = 0x1;
+pub const EXT_CONSOLE_GETCHAR: usize = 0x2;
+
+// base extension function IDs, passed in a6
+pub const BASE_GET_SPEC_VERSION: usize = 0;
+pub const BASE_GET_IMPL_ID: usize = 1;
+pub const BASE_GET_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_GET_MVENDORID: usize = 4;
+pub const BASE_GET_MARCHID: usize = 5;
+pub const BASE_GET_MIMPID: usize = 6;
+
+pub const HSM_HART_START: usize = 0;
+pub const SRST_SYSTEM_RESET: usize = 0;
+
+// system reset types and reasons
+pub const RESET_SHUTDOWN: usize = 0;
+pub const RESET_COLD_REBOOT: usize = 1;
+pub const RESET_WARM_REBOOT: usize = 2;
+pub const RESET_REASON_NONE: usize = 0;
+pub const RESET_REASON_FAILURE: usize = 1;
+
+pub const SUCCESS: isize = 0;
+pub const ERR_FAILED: isize = -1;
+pub const ERR_NOT_SUPPORTED: isize = -2;
+pub const ERR_INVALID_PARAM: isize = -3;
+pub const ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// Result of an SBI call, an error code and a value.
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+fn call(ext: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") ext,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// Implemented SBI specification version, the major number in bits 24..31.
+pub fn spec_version() -> (usize, usize) {
+    let version = call(EXT_BASE, BASE_GET_SPEC_VERSION, 0, 0, 0).value;
+    (version >> 24 & 0x7f, version & 0xff_ffff)
+}
+
+pub fn probe_extension(ext: usize) -> bool {
+    call(EXT_BASE, BASE_PROBE_EXTENSION, ext, 0, 0).value != 0
+}
+
+/// Programs the next timer interrupt of the calling hart at `deadline`, in mtime ticks. Also
+/// clears a pending one.
+pub fn set_timer(deadline: u64) {
+    call(EXT_TIME, 0, deadline as usize, 0, 0);
+}
+
+/// Raises a supervisor software interrupt on the harts in `mask`, bit n stands for hart
+/// `base + n`.
+pub fn send_ipi(mask: usize, base: usize) -> isize {
+    call(EXT_IPI, 0, mask, base, 0).error
+}
+
+/// Starts the stopped hart `hart` in supervisor mode at `start`, with its id in a0, `opaque` in
+/// a1 and translation off.
+pub fn hart_start(hart: usize, start: usize, opaque: usize) -> isize {
+    call(EXT_HSM, HSM_HART_START, hart, start, opaque).error
+}
+
+/// Shuts down or reboots the machine, only returns if that failed.
+pub fn system_reset(kind: usize, reason: usize) -> isize {
+    call(EXT_SRST, SRST_SYSTEM_RESET, kind, reason, 0).error
+}
+
+pub fn console_putchar(c: u8) {
+    call(EXT_CONSOLE_PUTCHAR, 0, c as usize, 0, 0);
+}
+
+/// Writes through the firmware's console, without taking the UART lock.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.bytes() {
+            console_putchar(c);
+        }
+        Ok(())
+    }
+}
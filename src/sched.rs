@@ -1,72 +1,561 @@
-use crate::process::Process;
+use crate::process::{init_image, Image, Process, INIT_PID};
 extern crate alloc;
-use crate::cpu::TrapFrame;
-use crate::process::ProcessState::Running;
-use crate::{cpu, Table};
+use crate::cpu::{TrapFrame, MAX_HARTS};
+use crate::lock::{SpinOnce, Spinlock, TicketLock, TicketLockGuard};
+use crate::process::ProcessState::{Running, Sleeping};
+use crate::{cpu, kmem, switch_to_user, timer, vfs, Table};
+use alloc::boxed::Box;
 use alloc::collections::VecDeque;
-use core::arch::asm;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
 
-static mut SCHED: Option<Scheduler> = None;
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+/// Run queues, level 0 has the highest priority.
+const LEVELS: usize = 5;
+/// Number of scheduling decisions a runnable process waits before it moves up one level.
+const AGING_ROUNDS: u32 = 8;
+
+static RUN_QUEUES: SpinOnce<[TicketLock<RunQueue>; MAX_HARTS]> = SpinOnce::new();
+static ZOMBIES: Spinlock<Vec<Zombie>> = Spinlock::new(Vec::new());
+static mut IDLE_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+
+extern "C" {
+    fn idle_loop();
+}
 
 pub fn init() {
-    unsafe { SCHED = Some(Scheduler::init()) }
+    let queues = RUN_QUEUES.call_once(Default::default);
+    // the built-in init is run unless the root filesystem has one, Image::load refuses a file
+    // that isn't regular and executable
+    let loaded = vfs::lookup("/", "/init").ok();
+    let loaded = loaded.and_then(|inode| Image::load(&*inode).ok());
+    let image = loaded.as_ref().map_or(init_image(), Image::data);
+    match Process::from_elf(image, INIT_PID) {
+        Ok(init) => queues[0].lock().enqueue(Box::new(init)),
+        Err(e) => panic!("could not load init: {}", e),
+    }
+}
+
+/// Locks the run queue of `hart`. Never hold two run queues at once, harts steal from each other.
+fn run_queue(hart: usize) -> TicketLockGuard<'static, RunQueue> {
+    RUN_QUEUES.get().expect("scheduler not initialized")[hart].lock()
+}
+
+/// Enters the scheduler on `hart` for the first time, after the kernel has been initialized.
+pub fn start(hart: usize) -> ! {
+    cpu::kernel_lock();
+    run_queue(hart).online = true;
+    let (frame, epc, satp) = schedule();
+    arm_timer(hart);
+    unsafe { switch_to_user(frame as usize, epc, satp) }
 }
 
+/// Picks the next process to run, or the hart's idle context if none is runnable. A hart that
+/// has nothing to run first tries to take a process from the busiest other hart.
 pub fn schedule() -> (*mut TrapFrame, usize, usize) {
-    let scheduler = unsafe { SCHED.as_mut().unwrap() };
-    scheduler.procs.rotate_left(1);
-    let mut mepc = 0;
-    let mut satp = 0;
-    let mut pid = 0;
-    let mut frame = None;
-    match scheduler.procs.front_mut() {
-        Some(p) if p.get_state() == Running => {
-            pid = p.get_pid();
-            satp = (p.get_table() as *const Table) as usize;
-            mepc = p.get_pc();
-            frame = Some(p.get_frame());
-        }
-        _ => {}
-    }
-    println!("Scheduling {}\n{:?}", pid, (0, mepc, satp));
-    if let Some(frame) = frame {
-        if satp != 0 {
-            (
-                frame as *mut TrapFrame,
-                mepc,
-                cpu::build_satp(cpu::SatpMode::Sv39, pid, satp),
-            )
+    let hart = cpu::hartid();
+    let mut rq = run_queue(hart);
+    let now = timer::mtime() as usize;
+    for p in rq.processes_mut() {
+        p.wake_at(now);
+    }
+    if let Some(task) = rq.current.take() {
+        if task.process.runs_on(hart) {
+            rq.enqueue(task.process);
         } else {
-            (frame as *mut TrapFrame, mepc, 0)
+            // its affinity changed while it was running
+            drop(rq);
+            place(task.process, hart);
+            rq = run_queue(hart);
         }
+    }
+    rq.current = rq.pick();
+    if rq.current.is_none() {
+        drop(rq);
+        let stolen = steal(hart);
+        rq = run_queue(hart);
+        rq.current = stolen.map(Task::new);
+    }
+    let task = match rq.current {
+        Some(ref mut task) => task,
+        None => {
+            // satp 0 makes switch_to_user resume the idle loop in supervisor mode on the
+            // kernel's table
+            let frame = unsafe { &mut IDLE_FRAME[hart] };
+            frame.hartid = hart;
+            return (frame as *mut TrapFrame, idle_loop as usize, 0);
+        }
+    };
+    let p = &mut task.process;
+    let pid = p.get_pid();
+    let satp = (p.get_table() as *const Table) as usize;
+    let epc = p.get_pc();
+    let frame = p.get_frame();
+    // the trap vector finds the hart id in the frame
+    frame.hartid = hart;
+    println!(
+        "Scheduling {} on hart {}\n{:?}",
+        pid,
+        hart,
+        (task.level, epc, satp)
+    );
+    if satp != 0 {
+        (
+            frame as *mut TrapFrame,
+            epc,
+            cpu::build_satp(cpu::SatpMode::Sv39, pid, satp),
+        )
     } else {
-        (core::ptr::null_mut(), 0, 0)
+        (frame as *mut TrapFrame, epc, 0)
+    }
+}
+
+/// Queues `process` on `hart` if its affinity allows it, otherwise on the first online hart it
+/// may run on. An idle target hart gets a software interrupt.
+fn place(process: Box<Process>, hart: usize) {
+    let target = if process.runs_on(hart) {
+        hart
+    } else {
+        (0..MAX_HARTS)
+            .find(|&h| process.runs_on(h) && run_queue(h).online)
+            .unwrap_or(hart)
+    };
+    let mut rq = run_queue(target);
+    if target != hart {
+        rq.migrations += 1;
+    }
+    rq.enqueue(process);
+    if target != cpu::hartid() && rq.is_idle() {
+        timer::send_ipi(target);
+    }
+}
+
+/// Takes a runnable process that may run on `hart` from the hart with the most of them waiting.
+fn steal(hart: usize) -> Option<Box<Process>> {
+    let (victim, _) = (0..MAX_HARTS)
+        .filter(|&h| h != hart)
+        .map(|h| (h, run_queue(h).runnable(hart)))
+        .filter(|&(_, waiting)| waiting > 0)
+        .max_by_key(|&(_, waiting)| waiting)?;
+    let process = run_queue(victim).take_runnable(hart)?;
+    let mut rq = run_queue(hart);
+    rq.migrations += 1;
+    println!(
+        "hart {} took process {} from hart {}, {} migrations",
+        hart,
+        process.get_pid(),
+        victim,
+        rq.migrations
+    );
+    Some(process)
+}
+
+/// Programs the next timer interrupt of `hart` at the end of the current process' time slice,
+/// or earlier if a process sleeping on this hart has to be woken before that. Idle harts keep
+/// getting interrupted every quantum, which is when they look for work to steal.
+pub fn arm_timer(hart: usize) {
+    let mut rq = run_queue(hart);
+    let slice = match rq.current {
+        Some(ref task) => time_slice(task.level),
+        None => timer::quantum(),
+    };
+    let quantum_end = timer::mtime() + slice;
+    let deadline = rq
+        .processes_mut()
+        .filter(|p| p.get_state() == Sleeping)
+        .map(|p| p.get_sleep_until() as u64)
+        .fold(quantum_end, core::cmp::min);
+    timer::set_deadline(deadline);
+}
+
+/// The process currently running on this hart, None while it idles.
+pub fn current() -> Option<&'static mut Process> {
+    let mut rq = run_queue(cpu::hartid());
+    // the box keeps the process in place and only this hart touches it while it is current
+    rq.current
+        .as_mut()
+        .map(|t| unsafe { &mut *(t.process.as_mut() as *mut Process) })
+}
+
+/// The PIDs of the processes that have not exited, in ascending order.
+pub fn pids() -> Vec<u16> {
+    let mut pids = Vec::new();
+    for hart in 0..MAX_HARTS {
+        pids.extend(run_queue(hart).processes_mut().map(|p| p.get_pid()));
+    }
+    pids.sort_unstable();
+    pids
+}
+
+/// Picks the PID of a new process: the next one after the last handed out that neither a
+/// process nor a zombie has. PIDs are the ASIDs of the address spaces as well, so 0 is left to
+/// the kernel. None if all of them are taken.
+fn alloc_pid() -> Option<u16> {
+    static NEXT_PID: AtomicU16 = AtomicU16::new(INIT_PID + 1);
+    let live = pids();
+    let zombies = ZOMBIES.lock();
+    for _ in 0..u16::MAX {
+        // system calls run under the kernel lock, nobody else forks meanwhile
+        let pid = NEXT_PID.load(Ordering::Relaxed);
+        NEXT_PID.store(pid.checked_add(1).unwrap_or(1), Ordering::Relaxed);
+        if live.binary_search(&pid).is_err() && !zombies.iter().any(|z| z.pid == pid) {
+            return Some(pid);
+        }
+    }
+    None
+}
+
+/// Runs `f` on process `pid`, on whichever hart it is.
+pub fn find<R>(pid: u16, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    for hart in 0..MAX_HARTS {
+        let mut rq = run_queue(hart);
+        let found = rq.processes_mut().find(|p| p.get_pid() == pid);
+        if let Some(p) = found {
+            return Some(f(p));
+        }
     }
+    None
 }
 
-struct Scheduler {
-    procs: VecDeque<Process>,
+/// Forks the current process, the child is queued and resumes at `pc`. Returns the child's PID,
+/// None if there is no PID or not enough memory for it.
+pub fn fork(pc: usize) -> Option<u16> {
+    let pid = alloc_pid()?;
+    let child = current()?.fork(pc, pid)?;
+    place(Box::new(child), cpu::hartid());
+    Some(pid)
 }
 
-impl Scheduler {
-    pub fn init() -> Scheduler {
-        let mut res = Self {
-            procs: VecDeque::with_capacity(15),
-        };
-        res.procs.push_back(Process::new(init_process));
-        res
+/// Gives up the hart, the current process resumes at `pc` once it is running again.
+pub fn yield_current(pc: usize) -> ! {
+    if let Some(current) = current() {
+        current.set_pc(pc);
     }
+    let (frame, epc, satp) = schedule();
+    arm_timer(cpu::hartid());
+    unsafe { switch_to_user(frame as usize, epc, satp) }
 }
 
-fn init_process() {
-    let mut i: usize = 0;
-    loop {
-        i += 1;
-        if i > 70_000_000 {
-            unsafe {
-                asm!("li a0, 0", "ecall", out("a0") _);
+/// Puts the current process to sleep until mtime reaches `until`, it then resumes at `pc`.
+pub fn sleep(until: u64, pc: usize) -> ! {
+    if let Some(current) = current() {
+        current.sleep(until as usize);
+    }
+    timer::fire_before(until);
+    yield_current(pc)
+}
+
+/// Blocks the current process until `channel` is woken, it then resumes at `pc`.
+pub fn block_on(channel: usize, pc: usize) -> ! {
+    if let Some(current) = current() {
+        current.block(channel);
+    }
+    yield_current(pc)
+}
+
+/// Wakes the processes blocked on `channel`, idle harts they are queued on get a software
+/// interrupt.
+pub fn wake(channel: usize) {
+    let me = cpu::hartid();
+    for hart in 0..MAX_HARTS {
+        let mut rq = run_queue(hart);
+        let mut woken = false;
+        for p in rq.processes_mut() {
+            woken |= p.wake(channel);
+        }
+        if woken && hart != me && rq.is_idle() {
+            timer::send_ipi(hart);
+        }
+    }
+}
+
+/// Changes the nice value of `pid`, 0 refers to the current process.
+pub fn set_nice(pid: u16, nice: i8) -> bool {
+    let nice = nice.clamp(NICE_MIN, NICE_MAX);
+    let pid = match (pid, current()) {
+        (0, Some(current)) => current.get_pid(),
+        (0, None) => return false,
+        (pid, _) => pid,
+    };
+    for hart in 0..MAX_HARTS {
+        let mut rq = run_queue(hart);
+        if let Some(ref mut task) = rq.current {
+            if task.process.get_pid() == pid {
+                task.process.set_nice(nice);
+                return true;
+            }
+        }
+        if let Some(mut process) = rq.remove(pid) {
+            process.set_nice(nice);
+            rq.enqueue(process);
+            return true;
+        }
+    }
+    false
+}
+
+/// Nice value of `pid`, 0 refers to the current process.
+pub fn get_nice(pid: u16) -> Option<i8> {
+    if pid == 0 {
+        current().map(|p| p.get_nice())
+    } else {
+        find(pid, |p| p.get_nice())
+    }
+}
+
+/// Restricts `pid` to the harts in `mask`, 0 refers to the current process. The mask has to
+/// contain an online hart. A queued process moves right away, a running one when it is
+/// preempted next.
+pub fn set_affinity(pid: u16, mask: usize) -> bool {
+    let pid = match (pid, current()) {
+        (0, Some(current)) => current.get_pid(),
+        (0, None) => return false,
+        (pid, _) => pid,
+    };
+    if !(0..MAX_HARTS).any(|h| mask & 1 << h != 0 && run_queue(h).online) {
+        return false;
+    }
+    for hart in 0..MAX_HARTS {
+        let mut rq = run_queue(hart);
+        if let Some(ref mut task) = rq.current {
+            if task.process.get_pid() == pid {
+                task.process.set_affinity(mask);
+                return true;
+            }
+        }
+        if let Some(mut process) = rq.remove(pid) {
+            process.set_affinity(mask);
+            drop(rq);
+            place(process, hart);
+            return true;
+        }
+    }
+    false
+}
+
+/// Terminates the current process. Its address space is freed right away, the exit status is
+/// kept in a zombie record until the parent reaps it.
+pub fn exit(status: i32) -> ! {
+    let process = {
+        let mut rq = run_queue(cpu::hartid());
+        let process = &rq
+            .current
+            .as_ref()
+            .expect("exit without a running process")
+            .process;
+        let pid = process.get_pid();
+        let parent = process.get_parent();
+        assert_ne!(pid, INIT_PID, "init exited with status {}", status);
+        // recorded before the process leaves its run queue, so reap always sees the child
+        ZOMBIES.lock().push(Zombie {
+            pid,
+            parent,
+            status,
+        });
+        rq.current.take().unwrap().process
+    };
+    let pid = process.get_pid();
+
+    let mut orphans = false;
+    for z in ZOMBIES.lock().iter_mut().filter(|z| z.parent == pid) {
+        z.parent = INIT_PID;
+        orphans = true;
+    }
+    for hart in 0..MAX_HARTS {
+        let mut rq = run_queue(hart);
+        for p in rq.processes_mut().filter(|p| p.get_parent() == pid) {
+            p.set_parent(INIT_PID);
+        }
+    }
+    let parent = find(process.get_parent(), |p| p.child_channel());
+    let init = if orphans {
+        find(INIT_PID, |p| p.child_channel())
+    } else {
+        None
+    };
+    for channel in parent.into_iter().chain(init) {
+        wake(channel);
+    }
+
+    // freeing the address space takes the page allocator lock, so not under a run queue's. The
+    // hart still runs on the process' table, whose kernel mappings go with it.
+    cpu::satp_write(kmem::kernel_satp());
+    drop(process);
+    cpu::satp_fence_asid(pid as usize);
+    let (frame, epc, satp) = schedule();
+    unsafe { switch_to_user(frame as usize, epc, satp) }
+}
+
+pub enum Reap {
+    Exited(u16),
+    // report failed, the child is left to be reaped
+    Unreported,
+    Pending,
+    NoChildren,
+}
+
+/// Reaps an exited child of the current process, any child if `pid` is None. The exit status
+/// is passed to `report` first, the child stays a zombie if that fails, like on a bad address
+/// to store the status at.
+pub fn reap(pid: Option<u16>, report: impl FnOnce(i32) -> bool) -> Reap {
+    let me = match current() {
+        Some(p) => p.get_pid(),
+        None => return Reap::NoChildren,
+    };
+    let matches =
+        |child: u16, parent: u16| parent == me && child != me && pid.map_or(true, |p| p == child);
+    // live children first, an exiting child is a zombie before it leaves its run queue
+    let running = (0..MAX_HARTS).any(|hart| {
+        let mut rq = run_queue(hart);
+        let found = rq
+            .processes_mut()
+            .any(|p| matches(p.get_pid(), p.get_parent()));
+        found
+    });
+    let found = ZOMBIES
+        .lock()
+        .iter()
+        .find(|z| matches(z.pid, z.parent))
+        .map(|z| (z.pid, z.status));
+    if let Some((child, status)) = found {
+        // not under the lock, copying the status out may allocate. Nobody else reaps the child
+        // meanwhile, system calls run under the kernel lock.
+        if !report(status) {
+            return Reap::Unreported;
+        }
+        let mut zombies = ZOMBIES.lock();
+        if let Some(i) = zombies.iter().position(|z| z.pid == child) {
+            zombies.swap_remove(i);
+        }
+        return Reap::Exited(child);
+    }
+    if running {
+        Reap::Pending
+    } else {
+        Reap::NoChildren
+    }
+}
+
+struct Zombie {
+    pid: u16,
+    parent: u16,
+    status: i32,
+}
+
+fn base_level(nice: i8) -> usize {
+    (nice - NICE_MIN) as usize * LEVELS / (NICE_MAX - NICE_MIN + 1) as usize
+}
+
+/// Higher priority levels get longer time slices, nice 0 gets the default quantum.
+fn time_slice(level: usize) -> u64 {
+    timer::quantum() * (LEVELS - level) as u64 / 3
+}
+
+// boxed, so trap frames keep their address while queued
+struct Task {
+    process: Box<Process>,
+    level: usize,
+    // scheduling decisions passed over while runnable
+    age: u32,
+}
+
+impl Task {
+    fn new(process: Box<Process>) -> Self {
+        let level = base_level(process.get_nice());
+        Task {
+            process,
+            level,
+            age: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RunQueue {
+    current: Option<Task>,
+    queues: [VecDeque<Task>; LEVELS],
+    online: bool,
+    // processes that moved to this hart from another one
+    migrations: usize,
+}
+
+// processes only move between harts under the run queue locks
+unsafe impl Send for RunQueue {}
+
+impl RunQueue {
+    fn processes_mut(&mut self) -> impl Iterator<Item = &mut Process> {
+        self.current
+            .iter_mut()
+            .chain(self.queues.iter_mut().flatten())
+            .map(|t| t.process.as_mut())
+    }
+    fn is_idle(&self) -> bool {
+        self.online && self.current.is_none()
+    }
+    fn enqueue(&mut self, process: Box<Process>) {
+        let task = Task::new(process);
+        self.queues[task.level].push_back(task);
+    }
+    /// Takes the queued process `pid` out of the run queue.
+    fn remove(&mut self, pid: u16) -> Option<Box<Process>> {
+        for queue in self.queues.iter_mut() {
+            if let Some(i) = queue.iter().position(|t| t.process.get_pid() == pid) {
+                return queue.remove(i).map(|t| t.process);
+            }
+        }
+        None
+    }
+    /// Number of queued runnable processes that may run on `hart`.
+    fn runnable(&self, hart: usize) -> usize {
+        self.queues
+            .iter()
+            .flatten()
+            .filter(|t| t.process.get_state() == Running && t.process.runs_on(hart))
+            .count()
+    }
+    /// Takes the highest priority runnable process that may run on `hart`.
+    fn take_runnable(&mut self, hart: usize) -> Option<Box<Process>> {
+        for queue in self.queues.iter_mut() {
+            let found = queue
+                .iter()
+                .position(|t| t.process.get_state() == Running && t.process.runs_on(hart));
+            if let Some(i) = found {
+                return queue.remove(i).map(|t| t.process);
+            }
+        }
+        None
+    }
+    /// Takes the first runnable process of the highest priority level, then ages the runnable
+    /// processes left behind.
+    fn pick(&mut self) -> Option<Task> {
+        let mut picked = None;
+        for queue in self.queues.iter_mut() {
+            if let Some(i) = queue.iter().position(|t| t.process.get_state() == Running) {
+                picked = queue.remove(i);
+                break;
+            }
+        }
+        for level in 1..LEVELS {
+            let (higher, lower) = self.queues.split_at_mut(level);
+            let queue = &mut lower[0];
+            let mut i = 0;
+            while i < queue.len() {
+                let task = &mut queue[i];
+                if task.process.get_state() == Running {
+                    task.age += 1;
+                }
+                if task.age >= AGING_ROUNDS {
+                    let mut task = queue.remove(i).unwrap();
+                    task.age = 0;
+                    task.level = level - 1;
+                    higher[level - 1].push_back(task);
+                    continue;
+                }
+                i += 1;
             }
-            i = 0;
         }
+        // a process that gets to run starts over at its own level
+        picked.map(|task| Task::new(task.process))
     }
 }
@@ -1,15 +1,375 @@
+extern crate alloc;
 use crate::cpu::TrapFrame;
+use crate::process::{ExecError, Image, Process};
+use crate::sched::Reap;
+use crate::vfs::FsError;
+use crate::{bcache, block, sched, timer, vfs, PAGE_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
 
-pub fn do_syscall(mepc: usize, frame: &mut TrapFrame) -> usize {
+pub const SYS_EXIT: usize = 0;
+pub const SYS_FORK: usize = 1;
+pub const SYS_WAIT: usize = 2;
+pub const SYS_WAITPID: usize = 3;
+pub const SYS_EXECVE: usize = 4;
+pub const SYS_SLEEP: usize = 5;
+pub const SYS_SETPRIORITY: usize = 6;
+pub const SYS_GETPRIORITY: usize = 7;
+pub const SYS_SCHED_SETAFFINITY: usize = 8;
+pub const SYS_SYNC: usize = 9;
+pub const SYS_OPEN: usize = 10;
+pub const SYS_CLOSE: usize = 11;
+pub const SYS_READ: usize = 12;
+pub const SYS_WRITE: usize = 13;
+pub const SYS_LSEEK: usize = 14;
+pub const SYS_FSTAT: usize = 15;
+pub const SYS_GETDENTS: usize = 16;
+pub const SYS_MKDIR: usize = 17;
+pub const SYS_UNLINK: usize = 18;
+pub const SYS_CHDIR: usize = 19;
+pub const SYS_SYMLINK: usize = 20;
+pub const SYS_READLINK: usize = 21;
+pub const SYS_RENAME: usize = 22;
+pub const SYS_LINK: usize = 23;
+pub const SYS_FTRUNCATE: usize = 24;
+
+/// waitpid option: return 0 instead of blocking while the children are still running
+pub const WNOHANG: usize = 1;
+
+const MAX_PATH: usize = 256;
+const MAX_ARGS: usize = 256;
+
+pub fn do_syscall(epc: usize, hart: usize, frame: &mut TrapFrame) -> usize {
     let syscall_num = frame.regs[10];
     match syscall_num {
-        0 => {
-            println!("exit system call");
-            mepc + 4
+        SYS_EXIT => {
+            sched::exit(frame.regs[11] as i32);
+        }
+        SYS_FORK => {
+            frame.regs[10] = match sched::fork(epc + 4) {
+                Some(pid) => pid as usize,
+                None => usize::MAX,
+            };
+            epc + 4
+        }
+        SYS_WAIT | SYS_WAITPID => {
+            let (pid, status, options) = if syscall_num == SYS_WAIT {
+                (usize::MAX, frame.regs[11], 0)
+            } else {
+                (frame.regs[11], frame.regs[12], frame.regs[13])
+            };
+            // a negative pid waits for any child
+            let filter = if (pid as isize) < 0 {
+                None
+            } else {
+                match u16::try_from(pid) {
+                    Ok(pid) => Some(pid),
+                    Err(_) => {
+                        frame.regs[10] = usize::MAX;
+                        return epc + 4;
+                    }
+                }
+            };
+            let report = |code: i32| {
+                // encoded like the wait status of other unices, WEXITSTATUS is bits 8..16
+                let wstatus = (code & 0xff) << 8;
+                status == 0
+                    || sched::current()
+                        .map_or(false, |p| p.copy_out(status, &wstatus.to_le_bytes()))
+            };
+            frame.regs[10] = match sched::reap(filter, report) {
+                Reap::Exited(child) => child as usize,
+                Reap::Unreported => usize::MAX,
+                Reap::Pending if options & WNOHANG != 0 => 0,
+                Reap::Pending => {
+                    // the wait is restarted once a child exits
+                    let channel = sched::current().unwrap().child_channel();
+                    sched::block_on(channel, epc);
+                }
+                Reap::NoChildren => usize::MAX,
+            };
+            epc + 4
+        }
+        SYS_EXECVE => {
+            let current = sched::current().unwrap();
+            let (path, argv, envp) = (frame.regs[11], frame.regs[12], frame.regs[13]);
+            match bcache::restartable(|| execve(current, path, argv, envp)) {
+                // the trap frame has been reset for the new image
+                Ok(()) => current.get_pc(),
+                Err(FsError::WouldBlock(channel)) => sched::block_on(channel, epc),
+                Err(_) => {
+                    frame.regs[10] = usize::MAX;
+                    epc + 4
+                }
+            }
+        }
+        SYS_SLEEP => {
+            // a1 holds the duration in nanoseconds
+            let until = timer::mtime().saturating_add(timer::ns_to_ticks(frame.regs[11] as u64));
+            frame.regs[10] = 0;
+            sched::sleep(until, epc + 4);
+        }
+        SYS_SETPRIORITY => {
+            // a1 holds the pid, 0 for the caller, a2 the nice value
+            let nice =
+                (frame.regs[12] as isize).clamp(sched::NICE_MIN as isize, sched::NICE_MAX as isize);
+            let done =
+                u16::try_from(frame.regs[11]).is_ok_and(|pid| sched::set_nice(pid, nice as i8));
+            frame.regs[10] = if done { 0 } else { usize::MAX };
+            epc + 4
+        }
+        SYS_GETPRIORITY => {
+            // like the Linux system call, the result is 20 - nice so that it is never negative
+            let nice = u16::try_from(frame.regs[11]).ok().and_then(sched::get_nice);
+            frame.regs[10] = match nice {
+                Some(nice) => (20 - nice as isize) as usize,
+                None => usize::MAX,
+            };
+            epc + 4
+        }
+        SYS_SCHED_SETAFFINITY => {
+            // a1 holds the pid, 0 for the caller, a2 the mask of allowed harts
+            let done = u16::try_from(frame.regs[11])
+                .is_ok_and(|pid| sched::set_affinity(pid, frame.regs[12]));
+            if !done {
+                frame.regs[10] = usize::MAX;
+                return epc + 4;
+            }
+            frame.regs[10] = 0;
+            // leave a hart the caller may no longer run on right away
+            if !sched::current().map_or(true, |p| p.runs_on(hart)) {
+                sched::yield_current(epc + 4);
+            }
+            epc + 4
+        }
+        SYS_SYNC => {
+            // restarted until no write-back is in flight, the disks are flushed at the end
+            if let Some(request) = bcache::sync() {
+                block::block_on(request, epc);
+            }
+            frame.regs[10] = 0;
+            epc + 4
+        }
+        SYS_OPEN..=SYS_FTRUNCATE => {
+            let process = sched::current().unwrap();
+            let (a1, a2, a3) = (frame.regs[11], frame.regs[12], frame.regs[13]);
+            frame.regs[10] =
+                match bcache::restartable(|| file_syscall(process, syscall_num, a1, a2, a3)) {
+                    Ok(value) => value,
+                    // restarted once there is something to read, the locals of the call are
+                    // gone, nothing on the kernel stack is dropped once the process is
+                    // switched out
+                    Err(FsError::WouldBlock(channel)) => sched::block_on(channel, epc),
+                    Err(_) => usize::MAX,
+                };
+            epc + 4
         }
         _ => {
             println!("unknown system call");
-            mepc + 4
+            epc + 4
+        }
+    }
+}
+
+/// Reads a NULL terminated array of user string pointers, as passed to execve.
+fn read_str_array(process: &mut Process, vaddr: usize) -> Option<Vec<Vec<u8>>> {
+    let mut res = Vec::new();
+    if vaddr == 0 {
+        return Some(res);
+    }
+    loop {
+        let mut ptr = [0; 8];
+        let addr = vaddr.checked_add(res.len() * 8)?;
+        if res.len() >= MAX_ARGS || !process.copy_in(addr, &mut ptr) {
+            return None;
+        }
+        match usize::from_le_bytes(ptr) {
+            0 => return Some(res),
+            ptr => res.push(process.read_str(ptr, PAGE_SIZE)?),
+        }
+    }
+}
+
+/// A path passed by the process, FsError::Invalid if it can't be read.
+fn read_path(process: &mut Process, vaddr: usize) -> Result<String, FsError> {
+    let path = process.read_str(vaddr, MAX_PATH).ok_or(FsError::Invalid)?;
+    String::from_utf8(path).map_err(|_| FsError::Invalid)
+}
+
+/// Copies `buf` to `vaddr` in the process, FsError::Invalid if it isn't writable there.
+fn copy_out(process: &mut Process, vaddr: usize, buf: &[u8]) -> Result<(), FsError> {
+    if process.copy_out(vaddr, buf) {
+        Ok(())
+    } else {
+        Err(FsError::Invalid)
+    }
+}
+
+/// Replaces the image of the process. The errors other than FsError::WouldBlock, for the image
+/// still being read, are reported here.
+fn execve(process: &mut Process, path: usize, argv: usize, envp: usize) -> Result<(), FsError> {
+    let path = read_path(process, path)?;
+    let loaded = vfs::lookup(process.get_cwd(), &path).map_err(Into::into);
+    let image = match loaded.and_then(|inode| Image::load(&*inode)) {
+        Ok(image) => image,
+        Err(ExecError::File(e @ FsError::WouldBlock(_))) => return Err(e),
+        Err(e) => {
+            println!("exec {}: {}", path, e);
+            return Err(FsError::Invalid);
+        }
+    };
+    let argv = read_str_array(process, argv).ok_or(FsError::Invalid)?;
+    let envp = read_str_array(process, envp).ok_or(FsError::Invalid)?;
+    match process.exec(image.data(), &argv, &envp) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            println!("exec {}: {}", path, e);
+            Err(FsError::Invalid)
+        }
+    }
+}
+
+/// The system calls on files and paths, the arguments are a1 to a3. Errors are all reported as
+/// -1, but for FsError::WouldBlock on which the call blocks to be restarted.
+fn file_syscall(
+    process: &mut Process,
+    num: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+) -> Result<usize, FsError> {
+    match num {
+        SYS_OPEN => {
+            // a1 holds the path, a2 the open flags, a3 the mode of a created file
+            let path = read_path(process, a1)?;
+            let file = vfs::open(process.get_cwd(), &path, a2, a3 as u32)?;
+            process.install_file(file).ok_or(FsError::Invalid)
+        }
+        SYS_CLOSE if process.close_file(a1) => Ok(0),
+        SYS_CLOSE => Err(FsError::BadAccess),
+        SYS_READ => {
+            // a1 holds the descriptor, a2 the buffer, a3 its length, the same for write
+            let file = process.get_file(a1).ok_or(FsError::BadAccess)?;
+            let mut chunk = Vec::new();
+            chunk.resize(core::cmp::min(a3, PAGE_SIZE), 0);
+            let mut done = 0;
+            while done < a3 {
+                let len = core::cmp::min(a3 - done, chunk.len());
+                let read = match file.read(&mut chunk[..len]) {
+                    Ok(read) => read,
+                    Err(_) if done > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                let vaddr = a2.checked_add(done).ok_or(FsError::Invalid)?;
+                copy_out(process, vaddr, &chunk[..read])?;
+                done += read;
+                if read < len {
+                    break;
+                }
+            }
+            Ok(done)
+        }
+        SYS_WRITE => {
+            let file = process.get_file(a1).ok_or(FsError::BadAccess)?;
+            let mut chunk = Vec::new();
+            chunk.resize(core::cmp::min(a3, PAGE_SIZE), 0);
+            let mut done = 0;
+            while done < a3 {
+                let len = core::cmp::min(a3 - done, chunk.len());
+                let vaddr = a2.checked_add(done).ok_or(FsError::Invalid)?;
+                if !process.copy_in(vaddr, &mut chunk[..len]) {
+                    return Err(FsError::Invalid);
+                }
+                let written = match file.write(&chunk[..len]) {
+                    Ok(written) => written,
+                    Err(_) if done > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                done += written;
+                if written < len {
+                    break;
+                }
+            }
+            Ok(done)
+        }
+        SYS_LSEEK => {
+            // a1 holds the descriptor, a2 the offset, a3 whence
+            let file = process.get_file(a1).ok_or(FsError::BadAccess)?;
+            Ok(file.seek(a2 as i64, a3)? as usize)
+        }
+        SYS_FSTAT => {
+            // a1 holds the descriptor, a2 where the vfs::Stat goes
+            let stat = process.get_file(a1).ok_or(FsError::BadAccess)?.stat()?;
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    &stat as *const vfs::Stat as *const u8,
+                    core::mem::size_of::<vfs::Stat>(),
+                )
+            };
+            copy_out(process, a2, bytes)?;
+            Ok(0)
+        }
+        SYS_GETDENTS => {
+            // a1 holds the descriptor of a directory, a2 the buffer, a3 its length
+            let file = process.get_file(a1).ok_or(FsError::BadAccess)?;
+            let mut buf = Vec::new();
+            buf.resize(core::cmp::min(a3, PAGE_SIZE), 0);
+            let used = file.getdents(&mut buf)?;
+            copy_out(process, a2, &buf[..used])?;
+            Ok(used)
+        }
+        SYS_MKDIR => {
+            // a1 holds the path, a2 the mode
+            let path = read_path(process, a1)?;
+            vfs::mkdir(process.get_cwd(), &path, a2 as u32)?;
+            Ok(0)
+        }
+        SYS_UNLINK => {
+            let path = read_path(process, a1)?;
+            vfs::unlink(process.get_cwd(), &path)?;
+            Ok(0)
+        }
+        SYS_CHDIR => {
+            let path = read_path(process, a1)?;
+            let path = vfs::resolve_dir(process.get_cwd(), &path)?;
+            process.set_cwd(path);
+            Ok(0)
+        }
+        SYS_SYMLINK => {
+            // a1 holds the target, a2 the path of the link
+            let target = read_path(process, a1)?;
+            let path = read_path(process, a2)?;
+            vfs::symlink(process.get_cwd(), &target, &path)?;
+            Ok(0)
+        }
+        SYS_READLINK => {
+            // a1 holds the path, a2 the buffer, a3 its length, the target is not terminated
+            let path = read_path(process, a1)?;
+            let target = vfs::readlink(process.get_cwd(), &path)?;
+            let len = core::cmp::min(target.len(), a3);
+            copy_out(process, a2, &target.as_bytes()[..len])?;
+            Ok(len)
+        }
+        SYS_RENAME => {
+            // a1 holds the old path, a2 the new one
+            let from = read_path(process, a1)?;
+            let to = read_path(process, a2)?;
+            vfs::rename(process.get_cwd(), &from, &to)?;
+            Ok(0)
+        }
+        SYS_LINK => {
+            // a1 holds the path of the existing file, a2 the path of the new link
+            let existing = read_path(process, a1)?;
+            let path = read_path(process, a2)?;
+            vfs::link(process.get_cwd(), &existing, &path)?;
+            Ok(0)
+        }
+        SYS_FTRUNCATE => {
+            // a1 holds the descriptor, a2 the new length
+            let file = process.get_file(a1).ok_or(FsError::BadAccess)?;
+            file.truncate(a2 as u64)?;
+            Ok(0)
         }
+        _ => Err(FsError::Invalid),
     }
 }
@@ -0,0 +1,55 @@
+use crate::cpu::{self, MAX_HARTS};
+use crate::{fdt, sbi};
+use core::arch::asm;
+
+/// Time slice of a process in nanoseconds.
+pub const QUANTUM_NS: u64 = 1_000_000_000;
+
+// deadline each hart last handed to the firmware, the CLINT can't be read from supervisor mode
+static mut DEADLINE: [u64; MAX_HARTS] = [u64::MAX; MAX_HARTS];
+
+/// Reads mtime through the time CSR.
+pub fn mtime() -> u64 {
+    unsafe {
+        let rval;
+        asm!("rdtime {}", out(reg) rval);
+        rval
+    }
+}
+
+/// Programs the next timer interrupt of this hart.
+pub fn set_deadline(deadline: u64) {
+    unsafe { DEADLINE[cpu::hartid()] = deadline };
+    sbi::set_timer(deadline);
+}
+
+/// Moves the next timer interrupt of this hart forward to `deadline` if it is earlier.
+pub fn fire_before(deadline: u64) {
+    if deadline < unsafe { DEADLINE[cpu::hartid()] } {
+        set_deadline(deadline);
+    }
+}
+
+/// Raises a supervisor software interrupt on `hart`.
+pub fn send_ipi(hart: usize) {
+    sbi::send_ipi(1 << hart, 0);
+}
+
+/// Acknowledges a software interrupt of this hart.
+pub fn clear_ipi() {
+    cpu::sip_clear(1 << 1);
+}
+
+/// mtime ticks per second, the timebase-frequency of the device tree.
+pub fn frequency() -> u64 {
+    fdt::machine().timebase
+}
+
+/// Time slice of a process in mtime ticks.
+pub fn quantum() -> u64 {
+    ns_to_ticks(QUANTUM_NS)
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * frequency() as u128 / 1_000_000_000) as u64
+}
@@ -0,0 +1,489 @@
+extern crate alloc;
+use crate::lock::Spinlock;
+use crate::page::IPage;
+use crate::vfs::{DirEntry, Filesystem, FsError, Inode, Stat, NAME_MAX, S_IFDIR, S_IFLNK, S_IFMT};
+use crate::{get_mm, PAGE_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const ROOT_INO: u64 = 1;
+/// Most bytes of kernel heap the inodes, names and page lists of a tmpfs may take, roughly
+/// counted. Their data may use far more memory, which comes from the pages.
+const META_LIMIT: usize = 64 << 10;
+/// What an inode or a name takes besides the name itself, with its share of the map it is in.
+const ENTRY_COST: usize = 128;
+
+/// A filesystem in memory: the data of its files is kept in pages of physical memory, the
+/// rest on the kernel heap. It starts out empty and is gone once the kernel stops.
+pub struct Tmpfs(Arc<Volume>);
+
+struct Volume {
+    // most pages the file data may take
+    limit: usize,
+    // operations like rename change several inodes together
+    state: Spinlock<State>,
+}
+
+struct State {
+    inodes: BTreeMap<u64, Data>,
+    next_ino: u64,
+    // pages taken by file data
+    pages: usize,
+    // bytes of heap charged against META_LIMIT
+    meta: usize,
+}
+
+/// A page of file data.
+struct Page(IPage);
+
+// only reached through the volume's lock
+unsafe impl Send for Page {}
+
+struct Data {
+    mode: u32,
+    nlink: u32,
+    content: Content,
+}
+
+enum Content {
+    // a page for every PAGE_SIZE bytes, None for a hole
+    File {
+        pages: Vec<Option<Page>>,
+        size: u64,
+    },
+    Dir {
+        parent: u64,
+        entries: BTreeMap<String, u64>,
+    },
+}
+
+impl Tmpfs {
+    /// An empty filesystem whose files may take up to `limit` bytes of physical memory.
+    pub fn new(limit: usize) -> Self {
+        let root = Data {
+            mode: S_IFDIR | 0o777,
+            nlink: 2,
+            content: Content::Dir {
+                parent: ROOT_INO,
+                entries: BTreeMap::new(),
+            },
+        };
+        let mut inodes = BTreeMap::new();
+        inodes.insert(ROOT_INO, root);
+        Tmpfs(Arc::new(Volume {
+            limit: limit / PAGE_SIZE,
+            state: Spinlock::new(State {
+                inodes,
+                next_ino: ROOT_INO + 1,
+                pages: 0,
+                meta: 0,
+            }),
+        }))
+    }
+}
+
+impl Filesystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.0.clone(),
+            ino: ROOT_INO,
+        })
+    }
+}
+
+impl Data {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// What the entry `name` is charged.
+fn name_cost(name: &str) -> usize {
+    ENTRY_COST + name.len()
+}
+
+/// What the list of a file's pages is charged for `slots` of them.
+fn slots_cost(slots: usize) -> usize {
+    slots * core::mem::size_of::<Option<Page>>()
+}
+
+impl State {
+    /// Charges `bytes` of metadata, fails with NoSpace once it would exceed the limit.
+    fn charge(&mut self, bytes: usize) -> Result<(), FsError> {
+        if self.meta + bytes > META_LIMIT {
+            return Err(FsError::NoSpace);
+        }
+        self.meta += bytes;
+        Ok(())
+    }
+    fn get(&self, ino: u64) -> Result<&Data, FsError> {
+        // the inode of an open file that rename replaced is gone
+        self.inodes.get(&ino).ok_or(FsError::NotFound)
+    }
+    fn get_mut(&mut self, ino: u64) -> Result<&mut Data, FsError> {
+        self.inodes.get_mut(&ino).ok_or(FsError::NotFound)
+    }
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<String, u64>, FsError> {
+        match &self.get(ino)?.content {
+            Content::Dir { entries, .. } => Ok(entries),
+            Content::File { .. } => Err(FsError::NotDir),
+        }
+    }
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, FsError> {
+        match &mut self.get_mut(ino)?.content {
+            Content::Dir { entries, .. } => Ok(entries),
+            Content::File { .. } => Err(FsError::NotDir),
+        }
+    }
+    /// Takes a link of `ino` away, it is freed with the last one. A directory has only one,
+    /// besides its own ".", and takes one of its parent's.
+    fn unlink(&mut self, ino: u64) -> Result<(), FsError> {
+        let data = self.get_mut(ino)?;
+        let parent = match data.content {
+            Content::Dir { parent, .. } => {
+                data.nlink = 0;
+                Some(parent)
+            }
+            Content::File { .. } => {
+                data.nlink -= 1;
+                None
+            }
+        };
+        if data.nlink == 0 {
+            self.meta -= ENTRY_COST;
+            if let Some(Data {
+                content: Content::File { pages, .. },
+                ..
+            }) = self.inodes.remove(&ino)
+            {
+                self.pages -= pages.iter().flatten().count();
+                self.meta -= slots_cost(pages.len());
+            }
+        }
+        if let Some(parent) = parent {
+            self.get_mut(parent)?.nlink -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// An inode of a mounted tmpfs, its data stays with the volume.
+struct Node {
+    volume: Arc<Volume>,
+    ino: u64,
+}
+
+impl Node {
+    fn node(&self, ino: u64) -> Arc<dyn Inode> {
+        Arc::new(Node {
+            volume: self.volume.clone(),
+            ino,
+        })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let state = self.volume.state.lock();
+        let data = state.get(self.ino)?;
+        let size = match &data.content {
+            Content::File { size, .. } => *size,
+            Content::Dir { .. } => 0,
+        };
+        Ok(Stat {
+            ino: self.ino,
+            mode: data.mode,
+            nlink: data.nlink,
+            size,
+        })
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let state = self.volume.state.lock();
+        let (pages, size) = match &state.get(self.ino)?.content {
+            Content::File { pages, size } => (pages, *size),
+            Content::Dir { .. } => return Err(FsError::IsDir),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let position = offset as usize + done;
+            let start = position % PAGE_SIZE;
+            let chunk = core::cmp::min(len - done, PAGE_SIZE - start);
+            let buf = &mut buf[done..done + chunk];
+            match pages.get(position / PAGE_SIZE) {
+                Some(Some(page)) => {
+                    let data =
+                        unsafe { core::slice::from_raw_parts(page.0.physical().add(start), chunk) };
+                    buf.copy_from_slice(data);
+                }
+                _ => buf.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.volume.state.lock();
+        let State {
+            inodes,
+            pages,
+            meta,
+            ..
+        } = &mut *state;
+        let data = inodes.get_mut(&self.ino).ok_or(FsError::NotFound)?;
+        let (file, size) = match &mut data.content {
+            Content::File { pages, size } => (pages, size),
+            Content::Dir { .. } => return Err(FsError::IsDir),
+        };
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::TooBig)?;
+        if end > isize::MAX as u64 {
+            return Err(FsError::TooBig);
+        }
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let position = offset as usize + done;
+            let (index, start) = (position / PAGE_SIZE, position % PAGE_SIZE);
+            let chunk = core::cmp::min(buf.len() - done, PAGE_SIZE - start);
+            if file.len() <= index {
+                let grown = slots_cost(index + 1 - file.len());
+                if *meta + grown > META_LIMIT {
+                    result = Err(FsError::NoSpace);
+                    break;
+                }
+                *meta += grown;
+                file.reserve_exact(index + 1 - file.len());
+                file.resize_with(index + 1, || None);
+            }
+            if file[index].is_none() {
+                if *pages >= self.volume.limit {
+                    result = Err(FsError::NoSpace);
+                    break;
+                }
+                let page = get_mm().zalloc(1);
+                if !page.available() {
+                    result = Err(FsError::NoSpace);
+                    break;
+                }
+                *pages += 1;
+                file[index] = Some(Page(page));
+            }
+            if let Some(page) = &file[index] {
+                unsafe {
+                    let dst = (page.0.physical() as *mut u8).add(start);
+                    dst.copy_from_nonoverlapping(buf[done..].as_ptr(), chunk);
+                }
+            }
+            done += chunk;
+        }
+        // what was written before running out of space is kept
+        if done > 0 {
+            *size = core::cmp::max(*size, offset + done as u64);
+        }
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let state = self.volume.state.lock();
+        match state.entries(self.ino)?.get(name) {
+            Some(&ino) => Ok(self.node(ino)),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn create(&self, name: &str, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.volume.state.lock();
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        if state.entries(self.ino)?.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+        // the inode and its name
+        state.charge(ENTRY_COST + name_cost(name))?;
+        let ino = state.next_ino;
+        state.next_ino += 1;
+        let data = if mode & S_IFMT == S_IFDIR {
+            // "." and ".." count as links of the directory and of its parent
+            state.get_mut(self.ino)?.nlink += 1;
+            Data {
+                mode,
+                nlink: 2,
+                content: Content::Dir {
+                    parent: self.ino,
+                    entries: BTreeMap::new(),
+                },
+            }
+        } else {
+            Data {
+                mode,
+                nlink: 1,
+                content: Content::File {
+                    pages: Vec::new(),
+                    size: 0,
+                },
+            }
+        };
+        state.inodes.insert(ino, data);
+        state.entries_mut(self.ino)?.insert(String::from(name), ino);
+        Ok(self.node(ino))
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut state = self.volume.state.lock();
+        let ino = *state
+            .entries(self.ino)?
+            .get(name)
+            .ok_or(FsError::NotFound)?;
+        if state
+            .entries(ino)
+            .map_or(false, |entries| !entries.is_empty())
+        {
+            return Err(FsError::NotEmpty);
+        }
+        state.entries_mut(self.ino)?.remove(name);
+        state.meta -= name_cost(name);
+        state.unlink(ino)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let state = self.volume.state.lock();
+        let (parent, entries) = match &state.get(self.ino)?.content {
+            Content::Dir { parent, entries } => (*parent, entries),
+            Content::File { .. } => return Err(FsError::NotDir),
+        };
+        // "." and ".." are not kept as entries
+        let (ino, name) = match index {
+            0 => (self.ino, "."),
+            1 => (parent, ".."),
+            _ => match entries.iter().nth(index - 2) {
+                Some((name, &ino)) => (ino, name.as_str()),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(DirEntry {
+            ino,
+            mode: state.get(ino)?.mode & S_IFMT,
+            name: String::from(name),
+        }))
+    }
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        // the target is the contents of the link
+        let link = self.create(name, S_IFLNK | 0o777)?;
+        link.write(0, target.as_bytes())?;
+        Ok(link)
+    }
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut state = self.volume.state.lock();
+        let State {
+            inodes,
+            pages,
+            meta,
+            ..
+        } = &mut *state;
+        let data = inodes.get_mut(&self.ino).ok_or(FsError::NotFound)?;
+        let (file, current) = match &mut data.content {
+            Content::File { pages, size } => (pages, size),
+            Content::Dir { .. } => return Err(FsError::IsDir),
+        };
+        if size > isize::MAX as u64 {
+            return Err(FsError::TooBig);
+        }
+        let kept = (size as usize).div_ceil(PAGE_SIZE);
+        if file.len() > kept {
+            *pages -= file[kept..].iter().flatten().count();
+            *meta -= slots_cost(file.len() - kept);
+            file.truncate(kept);
+            file.shrink_to_fit();
+        }
+        // the rest of the last page must read as zeros if the file grows again
+        let start = size as usize % PAGE_SIZE;
+        if let Some(Some(page)) = file.get(size as usize / PAGE_SIZE) {
+            unsafe {
+                let tail = (page.0.physical() as *mut u8).add(start);
+                tail.write_bytes(0, PAGE_SIZE - start);
+            }
+        }
+        *current = size;
+        Ok(())
+    }
+    fn link(&self, name: &str, ino: u64) -> Result<(), FsError> {
+        let mut state = self.volume.state.lock();
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        if state.entries(self.ino)?.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+        if state.get(ino)?.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        state.charge(name_cost(name))?;
+        state.get_mut(ino)?.nlink += 1;
+        state.entries_mut(self.ino)?.insert(String::from(name), ino);
+        Ok(())
+    }
+    fn rename(&self, name: &str, dir: u64, new_name: &str) -> Result<(), FsError> {
+        let mut state = self.volume.state.lock();
+        if new_name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        let ino = *state
+            .entries(self.ino)?
+            .get(name)
+            .ok_or(FsError::NotFound)?;
+        let moved_dir = state.get(ino)?.is_dir();
+        let replaced = state.entries(dir)?.get(new_name).copied();
+        if replaced == Some(ino) {
+            return Ok(());
+        }
+        if moved_dir {
+            // a directory can't go into itself, nor anywhere below
+            let mut above = dir;
+            loop {
+                if above == ino {
+                    return Err(FsError::Invalid);
+                }
+                if above == ROOT_INO {
+                    break;
+                }
+                above = match state.get(above)?.content {
+                    Content::Dir { parent, .. } => parent,
+                    Content::File { .. } => return Err(FsError::Corrupt),
+                };
+            }
+        }
+        if let Some(replaced) = replaced {
+            match (moved_dir, state.entries(replaced).ok()) {
+                (true, Some(entries)) if !entries.is_empty() => return Err(FsError::NotEmpty),
+                (true, None) => return Err(FsError::NotDir),
+                (false, Some(_)) => return Err(FsError::IsDir),
+                _ => {}
+            }
+            state.unlink(replaced)?;
+        } else {
+            state.charge(name_cost(new_name))?;
+        }
+        // a replaced entry keeps its name
+        state.entries_mut(self.ino)?.remove(name);
+        state.meta -= name_cost(name);
+        state.entries_mut(dir)?.insert(String::from(new_name), ino);
+        if moved_dir {
+            if let Content::Dir { parent, .. } = &mut state.get_mut(ino)?.content {
+                *parent = dir;
+            }
+            state.get_mut(self.ino)?.nlink -= 1;
+            state.get_mut(dir)?.nlink += 1;
+        }
+        Ok(())
+    }
+}
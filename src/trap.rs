@@ -1,10 +1,60 @@
-use crate::cpu::TrapFrame;
-use crate::sched::schedule;
+use crate::cpu::{TrapFrame, MAX_HARTS};
+use crate::page::StoreFault;
+use crate::sched;
 use crate::syscall::do_syscall;
-use crate::{switch_to_user, uart};
+use crate::{bcache, fdt, switch_to_user, timer, uart, virtio};
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// PLIC interrupt sources, enable_interrupt takes the first 32.
+const IRQS: usize = 32;
+
+// interrupts taken by each hart since boot
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_TAKEN: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_TAKEN_ANYWHERE: [AtomicUsize; MAX_HARTS] = [NONE_TAKEN; MAX_HARTS];
+static SOFTWARE: [AtomicUsize; MAX_HARTS] = [NONE_TAKEN; MAX_HARTS];
+static TIMER: [AtomicUsize; MAX_HARTS] = [NONE_TAKEN; MAX_HARTS];
+static EXTERNAL: [[AtomicUsize; MAX_HARTS]; IRQS] = [NONE_TAKEN_ANYWHERE; IRQS];
+
+/// The interrupt counters, a line per source with a column per hart like Linux'
+/// /proc/interrupts.
+pub struct Interrupts;
+
+impl Display for Interrupts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let machine = fdt::machine();
+        let harts = || (0..MAX_HARTS).filter(|&h| machine.harts & 1 << h != 0);
+        let row = |f: &mut Formatter<'_>, name: &dyn Display, counts: &[AtomicUsize], source| {
+            write!(f, "{:>4}:", name)?;
+            for hart in harts() {
+                write!(f, " {:>10}", counts[hart].load(Ordering::Relaxed))?;
+            }
+            writeln!(f, "  {}", source)
+        };
+        write!(f, "     ")?;
+        for hart in harts() {
+            write!(f, " {:>9}{}", "CPU", hart)?;
+        }
+        writeln!(f)?;
+        let uart_irq = machine.uart.map_or(0, |uart| uart.irq);
+        for (irq, counts) in EXTERNAL.iter().enumerate() {
+            let source = match virtio::irq_driver(irq as u32) {
+                Some(driver) => driver,
+                None if irq as u32 == uart_irq => "uart",
+                None if counts.iter().all(|c| c.load(Ordering::Relaxed) == 0) => continue,
+                None => "unknown",
+            };
+            row(f, &irq, counts, source)?;
+        }
+        row(f, &"IPI", &SOFTWARE, "software interrupts")?;
+        row(f, &"LOC", &TIMER, "timer interrupts")
+    }
+}
 
 #[no_mangle]
-extern "C" fn m_trap(
+extern "C" fn s_trap(
     mut epc: usize,
     tval: usize,
     cause: usize,
@@ -16,23 +66,40 @@ extern "C" fn m_trap(
     let cause = cause & 0xfff;
     if is_async {
         match cause {
-            3 => {
-                println!("Machine software interrupt CPU#{}", hart);
+            1 => {
+                SOFTWARE[hart].fetch_add(1, Ordering::Relaxed);
+                println!("Supervisor software interrupt CPU#{}", hart);
+                timer::clear_ipi();
+                // sent to idle harts when a process became runnable
+                if sched::current().is_none() {
+                    sched::yield_current(epc);
+                }
             }
-            7 => unsafe {
+            5 => unsafe {
+                TIMER[hart].fetch_add(1, Ordering::Relaxed);
                 println!("Timer interrupt...");
-                let (frame, mepc, satp) = schedule();
-                let timecmp = 0x02004000 as *mut u64;
-                let time = 0x0200bff8 as *const u64;
-                timecmp.write_volatile(time.read_volatile() + 10_000_000);
-                switch_to_user(frame as usize, mepc, satp);
+                if let Some(current) = sched::current() {
+                    current.set_pc(epc);
+                }
+                bcache::periodic();
+                let (frame, pc, satp) = sched::schedule();
+                sched::arm_timer(hart);
+                switch_to_user(frame as usize, pc, satp);
             },
-            11 => {
+            9 => {
                 if let Some(interrupt) = plic::claim() {
+                    if let Some(counts) = EXTERNAL.get(interrupt.get() as usize) {
+                        counts[hart].fetch_add(1, Ordering::Relaxed);
+                    }
+                    let uart_irq = fdt::machine().uart.map_or(0, |uart| uart.irq);
                     match interrupt.get() {
-                        10 => {
+                        id if id == uart_irq => {
                             // UART
-                            if let Some(c) = uart::get_uart().get() {
+                            // the guard must not outlive the statement, print! locks the UART
+                            let c = uart::get_uart().get();
+                            if let Some(c) = c {
+                                // terminals send CR for the enter key
+                                uart::receive(if c == 13 { b'\n' } else { c });
                                 match c {
                                     8 => {
                                         print!("{} {}", 8_u8 as char, 8_u8 as char);
@@ -41,8 +108,10 @@ extern "C" fn m_trap(
                                         println!();
                                     }
                                     0x1b => {
-                                        if let Some(91) = uart::get_uart().get() {
-                                            if let Some(b) = uart::get_uart().get() {
+                                        let bracket = uart::get_uart().get();
+                                        if let Some(91) = bracket {
+                                            let b = uart::get_uart().get();
+                                            if let Some(b) = b {
                                                 match b as char {
                                                     'A' => {
                                                         println!("That's the up arrow!");
@@ -69,12 +138,18 @@ extern "C" fn m_trap(
                                 }
                             }
                         }
-                        _ => {
-                            println!("NON-UART async interrupt");
+                        id => {
+                            if !virtio::interrupt(id) {
+                                println!("NON-UART async interrupt");
+                            }
                         }
                     }
                     plic::complete(interrupt.get());
                 }
+                // the interrupt may have made a process runnable
+                if sched::current().is_none() {
+                    sched::yield_current(epc);
+                }
             }
             _ => {
                 panic!("Unhandled async trap CPU#{} -> {}", hart, cause);
@@ -90,14 +165,7 @@ extern "C" fn m_trap(
             }
             8 => {
                 println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
-                epc = do_syscall(epc, frame);
-            }
-            9 => {
-                println!("E-call from Supervisor mode! CPU#{} -> 0x{:08x}", hart, epc);
-                epc = do_syscall(epc, frame);
-            }
-            11 => {
-                panic!("E-call from Machine mode! CPU#{} -> 0x{:08x}", hart, epc);
+                epc = do_syscall(epc, hart, frame);
             }
             12 => {
                 panic!(
@@ -112,10 +180,23 @@ extern "C" fn m_trap(
                 );
             }
             15 => {
-                panic!(
-                    "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
+                // retry the store once the copy on write page has been copied
+                match sched::current().map(|p| p.store_fault(tval)) {
+                    Some(StoreFault::Resolved) => {}
+                    Some(StoreFault::OutOfMemory) => {
+                        println!(
+                            "Out of memory copying on write CPU#{} -> 0x{:08x}: 0x{:08x}",
+                            hart, epc, tval
+                        );
+                        sched::exit(-1);
+                    }
+                    _ => {
+                        panic!(
+                            "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
+                            hart, epc, tval
+                        );
+                    }
+                }
             }
             _ => {
                 panic!("unhandled sync trap CPU#{} -> {}", hart, cause);
@@ -126,23 +207,35 @@ extern "C" fn m_trap(
 }
 
 pub mod plic {
+    use core::sync::atomic::{AtomicUsize, Ordering};
 
-    const PLIC_BASE: usize = 0xc000000;
-    const ENABLE_0_31: usize = 0x2000;
-    const HART_0_M_THRESH: usize = 0x200000;
-    const PLIC_CLAIM: usize = 0x200004;
-    pub fn get_addresses() -> &'static [usize] {
-        &[
-            PLIC_BASE + ENABLE_0_31,
-            PLIC_BASE + HART_0_M_THRESH,
-            PLIC_BASE + PLIC_CLAIM,
-            PLIC_BASE,
+    // the registers of context 1, hart 0 in supervisor mode
+    static PLIC_BASE: AtomicUsize = AtomicUsize::new(0);
+    const ENABLE_0_31: usize = 0x2080;
+    const HART_0_S_THRESH: usize = 0x201000;
+    const PLIC_CLAIM: usize = 0x201004;
+
+    /// Sets the base address, taken from the device tree.
+    pub fn init(base: usize) {
+        PLIC_BASE.store(base, Ordering::Relaxed);
+    }
+    fn base() -> usize {
+        let base = PLIC_BASE.load(Ordering::Relaxed);
+        assert_ne!(base, 0, "plic not initialized");
+        base
+    }
+    pub fn get_addresses() -> [usize; 4] {
+        [
+            base() + ENABLE_0_31,
+            base() + HART_0_S_THRESH,
+            base() + PLIC_CLAIM,
+            base(),
         ]
     }
     pub fn enable_interrupt(id: usize) {
         assert!(id <= 31);
 
-        let ptr = (PLIC_BASE + ENABLE_0_31) as *mut u32;
+        let ptr = (base() + ENABLE_0_31) as *mut u32;
         unsafe {
             ptr.write_volatile(ptr.read_volatile() | (1_u32 << id));
         }
@@ -150,7 +243,7 @@ pub mod plic {
     pub fn set_priority(id: usize, prio: u8) {
         assert!(id <= 31);
         assert!(prio <= 7);
-        let ptr = PLIC_BASE as *mut u32;
+        let ptr = base() as *mut u32;
         unsafe {
             ptr.add(id).write_volatile(prio as u32);
         }
@@ -158,14 +251,14 @@ pub mod plic {
     pub fn set_threshold(tsh: u8) {
         let tsh = tsh & 7;
         unsafe {
-            ((PLIC_BASE + HART_0_M_THRESH) as *mut u32).write_volatile(tsh as u32);
+            ((base() + HART_0_S_THRESH) as *mut u32).write_volatile(tsh as u32);
         }
     }
     pub fn claim() -> Option<core::num::NonZeroU32> {
-        let claimed = unsafe { ((PLIC_BASE + PLIC_CLAIM) as *mut u32).read_volatile() };
+        let claimed = unsafe { ((base() + PLIC_CLAIM) as *mut u32).read_volatile() };
         core::num::NonZeroU32::new(claimed)
     }
     pub fn complete(id: u32) {
-        unsafe { ((PLIC_BASE + PLIC_CLAIM) as *mut u32).write_volatile(id) }
+        unsafe { ((base() + PLIC_CLAIM) as *mut u32).write_volatile(id) }
     }
 }
@@ -1,49 +1,163 @@
+use crate::devfs::CharDevice;
+use crate::lock::{SpinOnce, Spinlock, SpinlockGuard};
+use crate::sched;
+use crate::vfs::FsError;
 use core::fmt::Write;
 use core::marker::PhantomData;
 
-static mut UART: Option<Uart<0x10000000, Init>> = None;
+static UART: SpinOnce<Spinlock<Uart<Init>>> = SpinOnce::new();
 
-pub fn initialize() {
-    unsafe { assert!(UART.is_none()) }
-    let uart = unsafe { Uart::<0x10000000, Uninit>::new().init() };
-    unsafe { UART = Some(uart) };
+/// Bytes typed ahead of a read of the console.
+const INPUT_SIZE: usize = 256;
+/// Bytes print! formats before it locks the UART to send them.
+const PRINT_SIZE: usize = 128;
+
+static INPUT: Spinlock<Input> = Spinlock::new(Input {
+    bytes: [0; INPUT_SIZE],
+    start: 0,
+    len: 0,
+});
+
+// a ring of the bytes received but not read yet
+struct Input {
+    bytes: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+/// The UART as /dev/console and /dev/ttyS0. Reads block until something was typed, a newline
+/// is written as CR LF.
+pub struct Console;
+
+pub static CONSOLE: Console = Console;
+
+impl CharDevice for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut input = INPUT.lock();
+        if input.len == 0 && !buf.is_empty() {
+            return Err(FsError::WouldBlock(input_channel()));
+        }
+        let len = core::cmp::min(buf.len(), input.len);
+        for b in buf[..len].iter_mut() {
+            *b = input.bytes[input.start];
+            input.start = (input.start + 1) % INPUT_SIZE;
+        }
+        input.len -= len;
+        Ok(len)
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        let uart = get_uart();
+        for &c in buf {
+            if c == b'\n' {
+                uart.put(b'\r');
+            }
+            uart.put(c);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Queues a byte received by the UART for readers of the console and wakes them. When nobody
+/// reads, the oldest bytes are dropped.
+pub fn receive(c: u8) {
+    {
+        let mut input = INPUT.lock();
+        if input.len == INPUT_SIZE {
+            input.start = (input.start + 1) % INPUT_SIZE;
+            input.len -= 1;
+        }
+        let end = (input.start + input.len) % INPUT_SIZE;
+        input.bytes[end] = c;
+        input.len += 1;
+    }
+    sched::wake(input_channel());
+}
+
+/// Readers of the console block on this channel.
+fn input_channel() -> usize {
+    &INPUT as *const Spinlock<Input> as usize
+}
+
+/// Sets up the 16550 at `base`, where the device tree put it.
+pub fn initialize(base: usize) {
+    assert!(UART.get().is_none());
+    UART.call_once(|| Spinlock::new(unsafe { Uart::new(base).init() }));
 }
 
-pub fn get_uart() -> &'static Uart<0x10000000, Init> {
-    unsafe { UART.as_ref().unwrap() }
+/// Locks the UART, keep the guard only as long as needed since print! takes it as well.
+pub fn get_uart() -> SpinlockGuard<'static, Uart<Init>> {
+    UART.get().expect("uart not initialized").lock()
+}
+
+// formatted text on its way to the UART, which is only locked to send it
+struct Pending {
+    bytes: [u8; PRINT_SIZE],
+    len: usize,
+}
+
+impl Pending {
+    fn send(&mut self) {
+        let uart = get_uart();
+        for &c in &self.bytes[..self.len] {
+            uart.put(c);
+        }
+        self.len = 0;
+    }
+}
+
+impl Write for Pending {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &c in s.as_bytes() {
+            if self.len == PRINT_SIZE {
+                self.send();
+            }
+            self.bytes[self.len] = c;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Backs print!. The arguments are formatted on the stack with the UART unlocked, so their
+/// Display implementations may print as well. Text longer than PRINT_SIZE is sent in pieces,
+/// the output of other harts may come between them.
+pub fn print(args: core::fmt::Arguments<'_>) {
+    let mut pending = Pending {
+        bytes: [0; PRINT_SIZE],
+        len: 0,
+    };
+    let _ = pending.write_fmt(args);
+    pending.send();
 }
 
 pub struct Uninit {}
 pub struct Init {}
 
 #[non_exhaustive]
-pub struct Uart<const B: usize, S = Uninit>(PhantomData<S>, PhantomData<*mut ()>);
+pub struct Uart<S = Uninit>(usize, PhantomData<S>, PhantomData<*mut ()>);
 
-impl<const B: usize> Uart<B, Uninit> {
-    pub fn new() -> Uart<B, Uninit> {
-        Uart(PhantomData::default(), PhantomData::default())
+// the registers may be driven from any hart, as long as the UART lock serializes them
+unsafe impl<S> Send for Uart<S> {}
+
+impl Uart<Uninit> {
+    pub fn new(base: usize) -> Uart<Uninit> {
+        Uart(base, PhantomData::default(), PhantomData::default())
     }
-    pub unsafe fn init(self) -> Uart<B, Init> {
-        uart_init(B);
-        Uart(PhantomData::default(), PhantomData::default())
+    pub unsafe fn init(self) -> Uart<Init> {
+        uart_init(self.0);
+        Uart(self.0, PhantomData::default(), PhantomData::default())
     }
 }
-impl<const B: usize> Uart<B, Init> {
+impl Uart<Init> {
     pub fn get(&self) -> Option<u8> {
-        uart_get(B)
+        uart_get(self.0)
     }
     pub fn put(&self, c: u8) {
-        uart_put(B, c)
-    }
-}
-
-impl<const B: usize> Default for Uart<B, Uninit> {
-    fn default() -> Self {
-        Self::new()
+        uart_put(self.0, c)
     }
 }
 
-impl<const B: usize> Write for &Uart<B, Init> {
+impl Write for Uart<Init> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for c in s.bytes() {
             self.put(c)
@@ -71,7 +185,8 @@ fn uart_init(base_addr: usize) {
     }
 }
 
-fn uart_get(base_addr: usize) -> Option<u8> {
+// also used by the firmware, which has to get by without the UART lock
+pub fn uart_get(base_addr: usize) -> Option<u8> {
     let ptr = base_addr as *mut u8;
     unsafe {
         if ptr.add(5).read_volatile() & 1 == 0 {
@@ -82,7 +197,7 @@ fn uart_get(base_addr: usize) -> Option<u8> {
     }
 }
 
-fn uart_put(base_addr: usize, c: u8) {
+pub fn uart_put(base_addr: usize, c: u8) {
     let ptr = base_addr as *mut u8;
     unsafe {
         ptr.add(0).write_volatile(c);
@@ -0,0 +1,612 @@
+//! Paths are resolved by walking them from the root of the filesystem mounted closest to them,
+//! looking every component up in its directory. There is no cache of directory entries: each
+//! lookup goes to the filesystem driver again, whose reads the block cache serves at best.
+
+extern crate alloc;
+use crate::block::BlockError;
+use crate::lock::Spinlock;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+// file types, in the upper bits of the mode like on Linux
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFBLK: u32 = 0o060000;
+
+// permission bits of the owner, the only ones checked as processes have no user ids
+pub const S_IRUSR: u32 = 0o400;
+pub const S_IWUSR: u32 = 0o200;
+pub const S_IXUSR: u32 = 0o100;
+
+/// Longest name of a directory entry.
+pub const NAME_MAX: usize = 255;
+/// Longest target of a symbolic link.
+pub const PATH_MAX: usize = 4096;
+/// Most symbolic links followed while resolving one path, more are taken for a loop.
+const MAX_SYMLINKS: usize = 8;
+
+// open flags, with the values Linux uses
+pub const O_ACCMODE: usize = 3;
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_APPEND: usize = 0o2000;
+pub const O_DIRECTORY: usize = 0o200000;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+static MOUNTS: Spinlock<Vec<Mount>> = Spinlock::new(Vec::new());
+/// The inodes with open files. Filesystems free an inode along with its last link, which
+/// therefore stays while the inode is open.
+static OPEN: Spinlock<Vec<OpenInode>> = Spinlock::new(Vec::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FsError {
+    NotFound,
+    NotDir,
+    IsDir,
+    Exists,
+    ReadOnly,
+    NameTooLong,
+    Busy,
+    BadAccess,
+    Invalid,
+    NotEmpty,
+    NoSpace,
+    TooBig,
+    Corrupt,
+    Loop,
+    Denied,
+    Unsupported,
+    CrossDevice,
+    /// Nothing to read yet or a block still being read from the disk, sched::wake is called
+    /// on the channel once there is. The call is to be made again then.
+    WouldBlock(usize),
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::WouldBlock(channel) => FsError::WouldBlock(channel),
+            e => FsError::Io(e),
+        }
+    }
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotDir => "not a directory",
+            FsError::IsDir => "is a directory",
+            FsError::Exists => "file exists",
+            FsError::ReadOnly => "read-only filesystem",
+            FsError::NameTooLong => "file name too long",
+            FsError::Busy => "device or resource busy",
+            FsError::BadAccess => "file not open for this access",
+            FsError::Invalid => "invalid argument",
+            FsError::NotEmpty => "directory not empty",
+            FsError::NoSpace => "no space left on the filesystem",
+            FsError::TooBig => "file too large",
+            FsError::Corrupt => "filesystem structure is invalid",
+            FsError::Loop => "too many levels of symbolic links",
+            FsError::Denied => "permission denied",
+            FsError::Unsupported => "operation not supported by the filesystem",
+            FsError::CrossDevice => "not on the same filesystem",
+            FsError::WouldBlock(_) => "operation would block",
+            FsError::Io(e) => return write!(f, "I/O error: {}", e),
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// What stat returns, laid out for user space as is.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Stat {
+    pub ino: u64,
+    /// File type and permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+    /// Whether the permission bits include all of `bits`.
+    pub fn allows(&self, bits: u32) -> bool {
+        self.mode & bits == bits
+    }
+}
+
+pub struct DirEntry {
+    pub ino: u64,
+    /// File type bits of the mode.
+    pub mode: u32,
+    pub name: String,
+}
+
+/// A file or directory of a mounted filesystem. Inodes are shared between open files and
+/// lookups, filesystems keep their mutable state behind their own locks.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Result<Stat, FsError>;
+    /// Reads from byte `offset` on, returns the number of bytes read, 0 at the end of the file.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+    /// Writes at byte `offset`, growing the file if needed, returns the number of bytes
+    /// written.
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError>;
+    /// The entry `name` of this directory. Mount points are not crossed here, the path
+    /// resolver does that.
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+    /// Adds the entry `name` to this directory, a file or directory depending on the type
+    /// bits of `mode`.
+    fn create(&self, name: &str, mode: u32) -> Result<Arc<dyn Inode>, FsError>;
+    /// Removes the entry `name` from this directory.
+    fn unlink(&self, name: &str) -> Result<(), FsError>;
+    /// Entry number `index` of this directory, None past the last one.
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError>;
+    /// The target of this symbolic link. By default it is the contents of the file.
+    fn readlink(&self) -> Result<String, FsError> {
+        let stat = self.stat()?;
+        if !stat.is_symlink() {
+            return Err(FsError::Invalid);
+        }
+        if stat.size as usize > PATH_MAX {
+            return Err(FsError::Corrupt);
+        }
+        let mut target = Vec::new();
+        target.resize(stat.size as usize, 0);
+        let read = self.read(0, &mut target)?;
+        target.truncate(read);
+        String::from_utf8(target).map_err(|_| FsError::Corrupt)
+    }
+    /// Adds the symbolic link `name` to `target` to this directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::Unsupported)
+    }
+    /// Cuts this file to `size` bytes, or extends it with zeros.
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+    /// Adds the entry `name` to this directory for inode number `ino` of the same filesystem,
+    /// which is not a directory.
+    fn link(&self, _name: &str, _ino: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+    /// Moves the entry `name` of this directory to the directory with inode number `dir` of
+    /// the same filesystem as `new_name`, replacing what is there.
+    fn rename(&self, _name: &str, _dir: u64, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+pub trait Filesystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    // components of the mount point, empty for the root
+    path: Vec<String>,
+    fs: Arc<dyn Filesystem>,
+}
+
+impl Mount {
+    fn covers(&self, path: &[String]) -> bool {
+        self.path.len() <= path.len() && self.path.iter().zip(path).all(|(a, b)| a == b)
+    }
+}
+
+struct OpenInode {
+    // the filesystem's address
+    fs: usize,
+    ino: u64,
+    files: usize,
+}
+
+/// An open file, shared by the descriptors dup'ed or inherited from it.
+pub struct File {
+    inode: Arc<dyn Inode>,
+    flags: usize,
+    // byte offset, or index of the next entry of a directory
+    offset: Spinlock<u64>,
+    // the entry in OPEN
+    fs: usize,
+    ino: u64,
+}
+
+impl File {
+    fn new(inode: Arc<dyn Inode>, flags: usize, fs: usize, ino: u64) -> Self {
+        let mut open = OPEN.lock();
+        match open.iter_mut().find(|o| o.fs == fs && o.ino == ino) {
+            Some(o) => o.files += 1,
+            None => open.push(OpenInode { fs, ino, files: 1 }),
+        }
+        File {
+            inode,
+            flags,
+            offset: Spinlock::new(0),
+            fs,
+            ino,
+        }
+    }
+    fn readable(&self) -> bool {
+        matches!(self.flags & O_ACCMODE, O_RDONLY | O_RDWR)
+    }
+    fn writable(&self) -> bool {
+        matches!(self.flags & O_ACCMODE, O_WRONLY | O_RDWR)
+    }
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.readable() {
+            return Err(FsError::BadAccess);
+        }
+        let mut offset = self.offset.lock();
+        let read = self.inode.read(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.writable() {
+            return Err(FsError::BadAccess);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags & O_APPEND != 0 {
+            *offset = self.inode.stat()?.size;
+        }
+        let written = self.inode.write(*offset, buf)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+    /// Moves the offset like lseek, returns the new one.
+    pub fn seek(&self, offset: i64, whence: usize) -> Result<u64, FsError> {
+        let mut current = self.offset.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *current,
+            SEEK_END => self.inode.stat()?.size,
+            _ => return Err(FsError::Invalid),
+        };
+        let target = (base as i64).checked_add(offset).ok_or(FsError::Invalid)?;
+        if target < 0 {
+            return Err(FsError::Invalid);
+        }
+        *current = target as u64;
+        Ok(*current)
+    }
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        self.inode.stat()
+    }
+    pub fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if !self.writable() {
+            return Err(FsError::BadAccess);
+        }
+        self.inode.truncate(size)
+    }
+    /// Fills `buf` with Linux dirent64 records from the offset on, returns the number of bytes
+    /// used, 0 at the end of the directory.
+    pub fn getdents(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let mut used = 0;
+        while let Some(entry) = self.inode.readdir(*offset as usize)? {
+            // inode, offset of the next entry, record length and type, then the name
+            let len = (19 + entry.name.len() + 1 + 7) & !7;
+            if used + len > buf.len() {
+                if used == 0 {
+                    return Err(FsError::Invalid);
+                }
+                break;
+            }
+            let record = &mut buf[used..used + len];
+            record.fill(0);
+            record[..8].copy_from_slice(&entry.ino.to_le_bytes());
+            record[8..16].copy_from_slice(&(*offset + 1).to_le_bytes());
+            record[16..18].copy_from_slice(&(len as u16).to_le_bytes());
+            record[18] = (entry.mode >> 12) as u8;
+            record[19..19 + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            used += len;
+            *offset += 1;
+        }
+        Ok(used)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let mut open = OPEN.lock();
+        if let Some(i) = open
+            .iter()
+            .position(|o| o.fs == self.fs && o.ino == self.ino)
+        {
+            open[i].files -= 1;
+            if open[i].files == 0 {
+                open.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Splits `path` into its components, a relative one is appended to the working directory
+/// `cwd`. `.` and empty components are dropped, `..` is left to walk as what it leads to
+/// depends on the symbolic links before it.
+fn components(cwd: &str, path: &str) -> Result<Vec<String>, FsError> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut res = Vec::new();
+    for name in base.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            _ if name.len() > NAME_MAX => return Err(FsError::NameTooLong),
+            _ => res.push(String::from(name)),
+        }
+    }
+    Ok(res)
+}
+
+/// The absolute path made of `path`'s components.
+fn join(path: &[String]) -> String {
+    let mut res = String::new();
+    for name in path {
+        res.push('/');
+        res.push_str(name);
+    }
+    if res.is_empty() {
+        res.push('/');
+    }
+    res
+}
+
+/// Looks `path` up from the root of the filesystem mounted closest to it, returns the inode
+/// and the path it was found at, without `..` and only ending in a symbolic link if `follow`
+/// isn't set. Symbolic links on the way are followed, and the one `path` ends in if `follow`
+/// is set: the target takes the place of the components up to the link and the walk starts
+/// over. So does `..`, which takes away the directory it was found in along with itself. That
+/// directory is reached through no symbolic link anymore, and `..` of a mount's root leads
+/// back into the filesystem it is mounted on.
+fn walk(path: Vec<String>, follow: bool) -> Result<(Arc<dyn Inode>, Vec<String>), FsError> {
+    let mut path = path;
+    let mut links = 0;
+    'walk: loop {
+        let (depth, fs) = mount_of(&path)?;
+        let mut inode = fs.root();
+        for i in depth..path.len() {
+            if !inode.stat()?.allows(S_IXUSR) {
+                return Err(FsError::Denied);
+            }
+            if path[i] == ".." {
+                // the root is its own parent
+                path.drain(i.saturating_sub(1)..=i);
+                continue 'walk;
+            }
+            let next = inode.lookup(&path[i])?;
+            if (follow || i + 1 < path.len()) && next.stat()?.is_symlink() {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(FsError::Loop);
+                }
+                let mut target = next.readlink()?;
+                if target.is_empty() {
+                    return Err(FsError::NotFound);
+                }
+                for name in &path[i + 1..] {
+                    target.push('/');
+                    target.push_str(name);
+                }
+                path = components(&join(&path[..i]), &target)?;
+                continue 'walk;
+            }
+            inode = next;
+        }
+        return Ok((inode, path));
+    }
+}
+
+/// The directory `path` is in, with its own path, and the last component. The directory is
+/// about to change, so it must be writable.
+fn parent(cwd: &str, path: &str) -> Result<(Arc<dyn Inode>, Vec<String>, String), FsError> {
+    let mut path = components(cwd, path)?;
+    // the root has no parent to change
+    let name = path.pop().ok_or(FsError::Busy)?;
+    // nor has what `..` leads to a name in this directory
+    if name == ".." {
+        return Err(FsError::Busy);
+    }
+    let (dir, path) = walk(path, true)?;
+    if !dir.stat()?.allows(S_IWUSR | S_IXUSR) {
+        return Err(FsError::Denied);
+    }
+    Ok((dir, path, name))
+}
+
+/// The filesystem mounted closest to `path`, with the length of its mount point.
+fn mount_of(path: &[String]) -> Result<(usize, Arc<dyn Filesystem>), FsError> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|m| m.covers(path))
+        .max_by_key(|m| m.path.len())
+        .ok_or(FsError::NotFound)?;
+    Ok((mount.path.len(), mount.fs.clone()))
+}
+
+/// Tells the filesystem mounted closest to `path` apart from the others.
+fn fs_id(path: &[String]) -> Result<usize, FsError> {
+    let (_, fs) = mount_of(path)?;
+    Ok(Arc::as_ptr(&fs) as *const u8 as usize)
+}
+
+/// Whether the paths `a` and `b` lead into the same filesystem.
+fn same_mount(a: &[String], b: &[String]) -> Result<bool, FsError> {
+    let (a_depth, _) = mount_of(a)?;
+    let (b_depth, _) = mount_of(b)?;
+    Ok(a[..a_depth] == b[..b_depth])
+}
+
+fn is_mount_point(path: &[String]) -> bool {
+    MOUNTS.lock().iter().any(|m| m.path == path)
+}
+
+fn is_open(fs: usize, ino: u64) -> bool {
+    OPEN.lock().iter().any(|o| o.fs == fs && o.ino == ino)
+}
+
+/// The absolute path of the directory `path` with the symbolic links followed, as kept for
+/// the working directory.
+pub fn resolve_dir(cwd: &str, path: &str) -> Result<String, FsError> {
+    let (inode, path) = walk(components(cwd, path)?, true)?;
+    let stat = inode.stat()?;
+    if !stat.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    if !stat.allows(S_IXUSR) {
+        return Err(FsError::Denied);
+    }
+    Ok(join(&path))
+}
+
+/// Mounts `fs` on the directory `path`, the first filesystem is mounted on "/".
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<(), FsError> {
+    let mut path = components("/", path)?;
+    let has_root = MOUNTS.lock().iter().any(|m| m.path.is_empty());
+    if has_root {
+        let (dir, resolved) = walk(path, true)?;
+        if !dir.stat()?.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        path = resolved;
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(FsError::Busy);
+    }
+    println!("vfs: {} mounted on {}", fs.name(), join(&path));
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// The inode at `path`, relative paths start at `cwd`.
+pub fn lookup(cwd: &str, path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    walk(components(cwd, path)?, true).map(|(inode, _)| inode)
+}
+
+/// Opens `path` with the open flags `flags`, a file created by O_CREAT gets the permission
+/// bits of `mode`. The owner's bits of an existing file must allow the access.
+pub fn open(cwd: &str, path: &str, flags: usize, mode: u32) -> Result<File, FsError> {
+    if flags & O_ACCMODE == O_ACCMODE {
+        return Err(FsError::Invalid);
+    }
+    let (inode, path, created) = match walk(components(cwd, path)?, true) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(FsError::Exists),
+        Ok((inode, path)) => (inode, path, false),
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+            let (dir, dir_path, name) = parent(cwd, path)?;
+            (dir.create(&name, S_IFREG | mode & 0o7777)?, dir_path, true)
+        }
+        Err(e) => return Err(e),
+    };
+    let stat = inode.stat()?;
+    if stat.is_dir() && flags & O_ACCMODE != O_RDONLY {
+        return Err(FsError::IsDir);
+    }
+    if !stat.is_dir() && flags & O_DIRECTORY != 0 {
+        return Err(FsError::NotDir);
+    }
+    let file = File::new(inode, flags, fs_id(&path)?, stat.ino);
+    // whoever creates a file may use it, whatever its mode
+    let denied =
+        file.readable() && !stat.allows(S_IRUSR) || file.writable() && !stat.allows(S_IWUSR);
+    if denied && !created {
+        return Err(FsError::Denied);
+    }
+    Ok(file)
+}
+
+pub fn mkdir(cwd: &str, path: &str, mode: u32) -> Result<(), FsError> {
+    let (dir, _, name) = parent(cwd, path)?;
+    dir.create(&name, S_IFDIR | mode & 0o7777).map(|_| ())
+}
+
+/// Removes `path`, a symbolic link itself rather than its target.
+pub fn unlink(cwd: &str, path: &str) -> Result<(), FsError> {
+    let (dir, mut dir_path, name) = parent(cwd, path)?;
+    dir_path.push(name);
+    if is_mount_point(&dir_path) {
+        return Err(FsError::Busy);
+    }
+    let name = &dir_path[dir_path.len() - 1];
+    // the last link of an open inode stays
+    let stat = dir.lookup(name)?.stat()?;
+    if (stat.is_dir() || stat.nlink <= 1) && is_open(fs_id(&dir_path)?, stat.ino) {
+        return Err(FsError::Busy);
+    }
+    dir.unlink(name)
+}
+
+/// Creates the symbolic link `path` to `target`, which is stored as given.
+pub fn symlink(cwd: &str, target: &str, path: &str) -> Result<(), FsError> {
+    if target.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    if target.len() > PATH_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    let (dir, _, name) = parent(cwd, path)?;
+    dir.symlink(&name, target).map(|_| ())
+}
+
+/// Moves `from` to `to` within a filesystem, replacing what `to` names.
+pub fn rename(cwd: &str, from: &str, to: &str) -> Result<(), FsError> {
+    let (from_dir, mut from_path, from_name) = parent(cwd, from)?;
+    let (to_dir, mut to_path, to_name) = parent(cwd, to)?;
+    if !same_mount(&from_path, &to_path)? {
+        return Err(FsError::CrossDevice);
+    }
+    from_path.push(from_name);
+    to_path.push(to_name);
+    if is_mount_point(&from_path) || is_mount_point(&to_path) {
+        return Err(FsError::Busy);
+    }
+    let dir = to_dir.stat()?.ino;
+    from_dir.rename(
+        &from_path[from_path.len() - 1],
+        dir,
+        &to_path[to_path.len() - 1],
+    )
+}
+
+/// Adds `path` as another name of the file `existing`, a hard link. A symbolic link is linked
+/// itself rather than its target.
+pub fn link(cwd: &str, existing: &str, path: &str) -> Result<(), FsError> {
+    let (inode, existing_path) = walk(components(cwd, existing)?, false)?;
+    let (dir, dir_path, name) = parent(cwd, path)?;
+    if !same_mount(&existing_path, &dir_path)? {
+        return Err(FsError::CrossDevice);
+    }
+    let stat = inode.stat()?;
+    if stat.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    dir.link(&name, stat.ino)
+}
+
+/// The target of the symbolic link `path`.
+pub fn readlink(cwd: &str, path: &str) -> Result<String, FsError> {
+    let (inode, _) = walk(components(cwd, path)?, false)?;
+    inode.readlink()
+}
@@ -0,0 +1,508 @@
+use crate::lock::Spinlock;
+use crate::page::{Pmem, PAGE_SIZE};
+use crate::{cpu, fdt, get_mm, trap};
+use core::fmt::{Display, Formatter};
+use core::mem::size_of;
+use core::ptr::addr_of_mut;
+
+// virtio-mmio registers, offsets from the base of a slot
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // legacy only
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // legacy only
+const QUEUE_PFN: usize = 0x040; // legacy only
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+// device status bits
+const ACKNOWLEDGE: u32 = 1;
+const DRIVER: u32 = 2;
+const DRIVER_OK: u32 = 4;
+const FEATURES_OK: u32 = 8;
+const FAILED: u32 = 128;
+
+/// The device conforms to virtio 1.0 or later, a modern driver must accept it.
+const F_VERSION_1: u64 = 1 << 32;
+
+pub const ID_NET: u32 = 1;
+pub const ID_BLOCK: u32 = 2;
+pub const ID_CONSOLE: u32 = 3;
+pub const ID_ENTROPY: u32 = 4;
+pub const ID_BALLOON: u32 = 5;
+pub const ID_SCSI: u32 = 8;
+pub const ID_GPU: u32 = 16;
+pub const ID_INPUT: u32 = 18;
+
+/// Interrupt status bit, the device used buffers of a queue.
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Descriptors of each virtqueue, QEMU offers up to 1024 but a page and a half is plenty.
+pub const QUEUE_SIZE: usize = 128;
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+const MAX_DRIVERS: usize = 8;
+
+static DRIVERS: Spinlock<[Option<&'static dyn Driver>; MAX_DRIVERS]> =
+    Spinlock::new([None; MAX_DRIVERS]);
+static BINDINGS: Spinlock<[Option<Binding>; fdt::MAX_VIRTIO]> =
+    Spinlock::new([None; fdt::MAX_VIRTIO]);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VirtioError {
+    BadMagic,
+    BadVersion,
+    NoDevice,
+    FeaturesRejected,
+    QueueUnavailable,
+    QueueTooSmall,
+    OutOfMemory,
+}
+
+impl Display for VirtioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            VirtioError::BadMagic => "not a virtio-mmio device",
+            VirtioError::BadVersion => "unsupported virtio-mmio version",
+            VirtioError::NoDevice => "no device in this slot",
+            VirtioError::FeaturesRejected => "device rejected the negotiated features",
+            VirtioError::QueueUnavailable => "queue is missing or already in use",
+            VirtioError::QueueTooSmall => "queue has fewer descriptors than needed",
+            VirtioError::OutOfMemory => "out of memory while allocating a queue",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+pub fn device_name(id: u32) -> &'static str {
+    match id {
+        ID_NET => "network card",
+        ID_BLOCK => "block device",
+        ID_CONSOLE => "console",
+        ID_ENTROPY => "entropy source",
+        ID_BALLOON => "memory balloon",
+        ID_SCSI => "SCSI host",
+        ID_GPU => "GPU",
+        ID_INPUT => "input device",
+        _ => "unknown device",
+    }
+}
+
+/// Registers of one virtio-mmio slot. Only a handle, whoever drives the device keeps its own
+/// copy and the interrupt dispatch keeps another.
+#[derive(Copy, Clone)]
+pub struct Mmio {
+    base: usize,
+    version: u32,
+}
+
+impl Mmio {
+    /// The device in the slot at `base`, which must be identity mapped.
+    pub fn probe(base: usize) -> Result<Mmio, VirtioError> {
+        let mmio = Mmio { base, version: 0 };
+        if mmio.read(MAGIC_VALUE) != MAGIC {
+            return Err(VirtioError::BadMagic);
+        }
+        let version = mmio.read(VERSION);
+        if version != 1 && version != 2 {
+            return Err(VirtioError::BadVersion);
+        }
+        if mmio.read(DEVICE_ID) == 0 {
+            return Err(VirtioError::NoDevice);
+        }
+        Ok(Mmio { base, version })
+    }
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ((self.base + register) as *const u32).read_volatile() }
+    }
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ((self.base + register) as *mut u32).write_volatile(value) }
+    }
+    pub fn base(&self) -> usize {
+        self.base
+    }
+    /// Whether the device only speaks the legacy (pre 1.0) interface.
+    pub fn legacy(&self) -> bool {
+        self.version == 1
+    }
+    pub fn device_id(&self) -> u32 {
+        self.read(DEVICE_ID)
+    }
+    pub fn vendor_id(&self) -> u32 {
+        self.read(VENDOR_ID)
+    }
+    pub fn status(&self) -> u32 {
+        self.read(STATUS)
+    }
+    fn set_status(&self, bits: u32) {
+        self.write(STATUS, self.status() | bits);
+    }
+    /// Resets the device and tells it a driver was found, the first steps of the handshake.
+    pub fn reset(&self) {
+        self.write(STATUS, 0);
+        self.set_status(ACKNOWLEDGE);
+        self.set_status(DRIVER);
+    }
+    /// Accepts the features in `supported` the device offers, returns them. Devices with the
+    /// modern interface have to confirm them before the queues may be set up.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let mut offered = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        offered |= (self.read(DEVICE_FEATURES) as u64) << 32;
+        let mut features = offered & supported;
+        if !self.legacy() {
+            if offered & F_VERSION_1 == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+            features |= F_VERSION_1;
+        }
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+        if self.legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.set_status(FEATURES_OK);
+            if self.status() & FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+    /// Allocates queue `index` and hands it to the device.
+    pub fn setup_queue(&self, index: u32) -> Result<Virtqueue, VirtioError> {
+        self.write(QUEUE_SEL, index);
+        let in_use = if self.legacy() {
+            self.read(QUEUE_PFN) != 0
+        } else {
+            self.read(QUEUE_READY) != 0
+        };
+        let max = self.read(QUEUE_NUM_MAX) as usize;
+        if in_use || max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        if max < QUEUE_SIZE {
+            return Err(VirtioError::QueueTooSmall);
+        }
+        let queue = Virtqueue::new(index, &mut get_mm())?;
+        self.write(QUEUE_NUM, QUEUE_SIZE as u32);
+        let rings = queue.rings as usize;
+        if self.legacy() {
+            // the device finds the rings from the page number, the used ring on the next
+            // QueueAlign boundary after the available ring
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (rings / PAGE_SIZE) as u32);
+        } else {
+            let desc = rings;
+            let driver = unsafe { addr_of_mut!((*queue.rings).avail) as usize };
+            let device = unsafe { addr_of_mut!((*queue.rings).used) as usize };
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, driver as u32);
+            self.write(QUEUE_DRIVER_HIGH, (driver >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, device as u32);
+            self.write(QUEUE_DEVICE_HIGH, (device >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+        Ok(queue)
+    }
+    /// Ends the handshake, the device may use its queues from now on.
+    pub fn driver_ok(&self) {
+        self.set_status(DRIVER_OK);
+    }
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.set_status(FAILED);
+    }
+    /// Tells the device there are new buffers in queue `index`.
+    pub fn notify(&self, index: u32) {
+        cpu::io_fence();
+        self.write(QUEUE_NOTIFY, index);
+    }
+    /// Reads and acknowledges the pending interrupts, a mask of the INTERRUPT_ bits.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+    /// A 32 bit field of the device specific configuration space.
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+    /// A 64 bit field of the configuration space, read again if it changed between the halves.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.read(CONFIG_GENERATION);
+            let low = self.config_u32(offset) as u64;
+            let high = self.config_u32(offset + 4) as u64;
+            // legacy devices have no generation counter, the register reads 0
+            if self.legacy() || generation == self.read(CONFIG_GENERATION) {
+                return high << 32 | low;
+            }
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+struct Available {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    event: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct Used {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElement; QUEUE_SIZE],
+    event: u16,
+}
+
+// the split virtqueue in the layout the legacy interface expects, the used ring on the page
+// after the descriptors and the available ring
+#[repr(C)]
+struct Rings {
+    desc: [Descriptor; QUEUE_SIZE],
+    avail: Available,
+    _padding: [u8; PAGE_SIZE - size_of::<[Descriptor; QUEUE_SIZE]>() - size_of::<Available>()],
+    used: Used,
+}
+
+/// A buffer handed to the device, `writable` ones are filled in by it.
+#[derive(Copy, Clone)]
+pub struct Buffer {
+    pub addr: usize,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// A split virtqueue. Free descriptors are chained through their next fields, starting at
+/// `free_head`.
+pub struct Virtqueue {
+    index: u32,
+    rings: *mut Rings,
+    free_head: u16,
+    num_free: usize,
+    // the used ring entries up to here have been popped
+    last_used: u16,
+}
+
+// the rings are only touched by whoever owns the queue, and the device
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    fn new(index: u32, pm: &mut Pmem) -> Result<Virtqueue, VirtioError> {
+        let pages = size_of::<Rings>().div_ceil(PAGE_SIZE);
+        let memory = pm.zalloc(pages);
+        if !memory.available() {
+            return Err(VirtioError::OutOfMemory);
+        }
+        // devices live as long as the kernel, so do their queues
+        let rings = memory.leak() as *mut Rings;
+        let desc = unsafe { &mut (*rings).desc };
+        for (i, d) in desc.iter_mut().enumerate() {
+            d.next = (i + 1) as u16;
+        }
+        Ok(Virtqueue {
+            index,
+            rings,
+            free_head: 0,
+            num_free: QUEUE_SIZE,
+            last_used: 0,
+        })
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    /// Chains `buffers` and makes them available to the device, returns the head descriptor
+    /// that identifies them in the used ring. The device only learns about them once notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free {
+            return None;
+        }
+        let rings = unsafe { &mut *self.rings };
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            // free descriptors already point at the next free one, the chain links itself
+            let desc = &mut rings.desc[self.free_head as usize];
+            desc.addr = buffer.addr as u64;
+            desc.len = buffer.len;
+            desc.flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+        }
+        self.num_free -= buffers.len();
+        unsafe {
+            let idx = addr_of_mut!(rings.avail.idx).read_volatile();
+            rings.avail.ring[idx as usize % QUEUE_SIZE] = head;
+            // the device must see the ring entry before the index that publishes it
+            cpu::io_fence();
+            addr_of_mut!(rings.avail.idx).write_volatile(idx.wrapping_add(1));
+        }
+        Some(head)
+    }
+    /// Takes the next chain the device is done with, its head and the number of bytes the
+    /// device wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let rings = unsafe { &mut *self.rings };
+        cpu::io_fence();
+        let idx = unsafe { addr_of_mut!(rings.used.idx).read_volatile() };
+        if idx == self.last_used {
+            return None;
+        }
+        let element = unsafe {
+            addr_of_mut!(rings.used.ring[self.last_used as usize % QUEUE_SIZE]).read_volatile()
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        let head = element.id as u16;
+        let mut tail = head;
+        self.num_free += 1;
+        while rings.desc[tail as usize].flags & DESC_F_NEXT != 0 {
+            tail = rings.desc[tail as usize].next;
+            self.num_free += 1;
+        }
+        rings.desc[tail as usize].next = self.free_head;
+        self.free_head = head;
+        Some((head, element.len))
+    }
+}
+
+/// A driver for one type of virtio device. Registered drivers are bound by probe to every
+/// device with their device ID.
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+    fn device_id(&self) -> u32;
+    /// Takes over the device in `slot`, which has been reset. Negotiating features, setting up
+    /// the queues and setting DRIVER_OK are up to the driver.
+    fn attach(&self, slot: usize, device: Mmio) -> Result<(), VirtioError>;
+    /// Called with the acknowledged interrupt status when the device in `slot` interrupts.
+    fn interrupt(&self, slot: usize, status: u32);
+}
+
+#[derive(Copy, Clone)]
+struct Binding {
+    irq: u32,
+    device: Mmio,
+    driver: &'static dyn Driver,
+}
+
+pub fn register(driver: &'static dyn Driver) {
+    let mut drivers = DRIVERS.lock();
+    let free = drivers
+        .iter_mut()
+        .find(|d| d.is_none())
+        .expect("too many virtio drivers");
+    *free = Some(driver);
+}
+
+/// Looks at every virtio-mmio slot of the device tree and binds the registered drivers to the
+/// devices they drive. Needs the page allocator, and the slots identity mapped.
+pub fn probe() {
+    let drivers = *DRIVERS.lock();
+    for (slot, transport) in fdt::machine().virtio().iter().enumerate() {
+        let device = match Mmio::probe(transport.reg.start) {
+            Ok(device) => device,
+            Err(VirtioError::NoDevice) => continue,
+            Err(e) => {
+                println!("virtio 0x{:x}: {}", transport.reg.start, e);
+                continue;
+            }
+        };
+        let id = device.device_id();
+        println!(
+            "virtio 0x{:x}: {} (id {}, vendor 0x{:x}{})",
+            device.base(),
+            device_name(id),
+            id,
+            device.vendor_id(),
+            if device.legacy() { ", legacy" } else { "" }
+        );
+        let driver = match drivers.iter().flatten().find(|d| d.device_id() == id) {
+            Some(&driver) => driver,
+            None => continue,
+        };
+        device.reset();
+        if let Err(e) = driver.attach(slot, device) {
+            device.fail();
+            println!("virtio 0x{:x}: {}: {}", device.base(), driver.name(), e);
+            continue;
+        }
+        BINDINGS.lock()[slot] = Some(Binding {
+            irq: transport.irq,
+            device,
+            driver,
+        });
+        trap::plic::enable_interrupt(transport.irq as usize);
+        trap::plic::set_priority(transport.irq as usize, 1);
+    }
+}
+
+/// Hands the PLIC interrupt `irq` to the driver of the device raising it, false if no bound
+/// device uses it.
+pub fn interrupt(irq: u32) -> bool {
+    // the driver may take locks of its own, not with the bindings held
+    let bindings = *BINDINGS.lock();
+    let mut handled = false;
+    for (slot, binding) in bindings.iter().enumerate() {
+        if let Some(binding) = binding.as_ref().filter(|b| b.irq == irq) {
+            let status = binding.device.ack_interrupt();
+            binding.driver.interrupt(slot, status);
+            handled = true;
+        }
+    }
+    handled
+}
+
+/// The name of the driver of the device using the PLIC interrupt `irq`, if one is bound.
+pub fn irq_driver(irq: u32) -> Option<&'static str> {
+    let bindings = *BINDINGS.lock();
+    let binding = bindings.iter().flatten().find(|b| b.irq == irq)?;
+    Some(binding.driver.name())
+}